@@ -0,0 +1,490 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use bot::{Bot, BotEvent, WorldInfo};
+use clap::Parser;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use shared::{
+    ActionFlags, ChunkPos, ChunkStreamMessage, ChunkView, ServerChatMessage,
+    ServerControlStreamMessage,
+};
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// Connects many bots to a running server and has them play at once, then reports how many got
+/// in, how long the server took to answer and what it complained about. Aimed at a dedicated
+/// server, which counts every bot against its player cap even when they run on the same host,
+/// so the cap has to allow for them, e.g. `server --max-players 100`.
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "Load generator driving many simulated players")]
+struct Args {
+    /// Address of the game server
+    #[arg(long, default_value = "127.0.0.1:5250")]
+    server: SocketAddr,
+
+    /// Number of bots
+    #[arg(short, long, default_value_t = 20)]
+    bots: usize,
+
+    /// Milliseconds between starting one bot and the next
+    #[arg(long, default_value_t = 50)]
+    ramp: u64,
+
+    /// Seconds each bot plays once it is in the world
+    #[arg(short, long, default_value_t = 30)]
+    duration: u64,
+
+    /// Movement inputs per second for each bot, 0 to send none
+    #[arg(long, default_value_t = 30.0)]
+    input_rate: f64,
+
+    /// Chat messages per second for each bot, 0 to send none
+    #[arg(long, default_value_t = 0.2)]
+    chat_rate: f64,
+
+    /// View changes per second for each bot, each requesting the chunks it moves onto
+    #[arg(long, default_value_t = 0.5)]
+    view_rate: f64,
+
+    /// Chunk view radius, capped at what the server allows
+    #[arg(long, default_value_t = 2)]
+    view_radius: usize,
+
+    /// Account names are this followed by the bot's number, unique to the run by default
+    #[arg(long)]
+    prefix: Option<String>,
+
+    /// Where the server's certificate is pinned, a fresh file in the temp directory by default
+    #[arg(long)]
+    known_servers: Option<PathBuf>,
+
+    /// Different seeds give the bots different movement and walks through the world
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    if let Err(e) = rustls::crypto::aws_lc_rs::default_provider().install_default() {
+        return Err(anyhow!("Error installing default crypto provider: {:?}", e));
+    }
+
+    let args = Args::parse();
+    let run = format!("load{}", std::process::id());
+    let prefix = args.prefix.clone().unwrap_or_else(|| format!("{run}_"));
+    let temp_known_servers = args.known_servers.is_none();
+    let known_servers = args
+        .known_servers
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("{run}-known_servers")));
+
+    // Pins the certificate once up front, rather than every bot racing to write the file
+    Bot::connect(args.server, &known_servers)
+        .await
+        .map_err(|e| anyhow!("Could not reach {}: {e}", args.server))?
+        .disconnect();
+
+    println!(
+        "Starting {} bots against {} for {} s each",
+        args.bots, args.server, args.duration
+    );
+    let started = Instant::now();
+    let args = Arc::new(args);
+    let known_servers = Arc::new(known_servers);
+    let mut bots = Vec::with_capacity(args.bots);
+    for index in 0..args.bots {
+        let username = format!("{prefix}{index}");
+        let rng = StdRng::seed_from_u64(args.seed.wrapping_add(index as u64));
+        bots.push(tokio::spawn(run_bot(
+            username,
+            args.clone(),
+            known_servers.clone(),
+            rng,
+        )));
+        tokio::time::sleep(Duration::from_millis(args.ramp)).await;
+    }
+
+    let mut stats = Stats::default();
+    for bot in bots {
+        match bot.await {
+            Ok(bot_stats) => stats.merge(bot_stats),
+            Err(e) => stats.count_failure(format!("bot task: {e}")),
+        }
+    }
+    if temp_known_servers {
+        let _ = std::fs::remove_file(known_servers.as_path());
+    }
+
+    println!("Finished in {:.1} s\n", started.elapsed().as_secs_f64());
+    print!("{}", Report::new(&stats, args.bots));
+    Ok(())
+}
+
+/// What one or all bots saw.
+#[derive(Debug, Default)]
+struct Stats {
+    joined: usize,
+    /// Errors that kept a bot out of the world or cut it off, by message
+    failures: BTreeMap<String, usize>,
+    /// Errors, denials and disconnects the server sent, by message
+    server_errors: BTreeMap<String, usize>,
+    latencies: BTreeMap<&'static str, Vec<Duration>>,
+    counts: BTreeMap<&'static str, usize>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.joined += other.joined;
+        for (failure, count) in other.failures {
+            *self.failures.entry(failure).or_default() += count;
+        }
+        for (error, count) in other.server_errors {
+            *self.server_errors.entry(error).or_default() += count;
+        }
+        for (name, samples) in other.latencies {
+            self.latencies.entry(name).or_default().extend(samples);
+        }
+        for (name, count) in other.counts {
+            *self.counts.entry(name).or_default() += count;
+        }
+    }
+
+    fn count_failure(&mut self, failure: String) {
+        *self.failures.entry(failure).or_default() += 1;
+    }
+
+    fn count_server_error(&mut self, error: String) {
+        *self.server_errors.entry(error).or_default() += 1;
+    }
+
+    fn count(&mut self, name: &'static str) {
+        *self.counts.entry(name).or_default() += 1;
+    }
+
+    fn record(&mut self, name: &'static str, latency: Duration) {
+        self.latencies.entry(name).or_default().push(latency);
+    }
+
+    /// Times one step of getting into the world, noting where it failed.
+    async fn time<T>(
+        &mut self,
+        name: &'static str,
+        step: impl Future<Output = anyhow::Result<T>>,
+    ) -> Option<T> {
+        let start = Instant::now();
+        match step.await {
+            Ok(value) => {
+                self.record(name, start.elapsed());
+                Some(value)
+            }
+            Err(e) => {
+                self.count_failure(format!("{name}: {e}"));
+                None
+            }
+        }
+    }
+}
+
+async fn run_bot(
+    username: String,
+    args: Arc<Args>,
+    known_servers: Arc<PathBuf>,
+    rng: StdRng,
+) -> Stats {
+    let mut stats = Stats::default();
+    let Some(mut bot) = stats
+        .time("connect", Bot::connect(args.server, &known_servers))
+        .await
+    else {
+        return stats;
+    };
+    let joined = async {
+        stats
+            .time("login", bot.create_account(&username, &username))
+            .await?;
+        stats
+            .time("character", bot.create_character(&username))
+            .await?;
+        stats.time("join", bot.join_world()).await
+    }
+    .await;
+    let Some(world) = joined else {
+        bot.disconnect();
+        return stats;
+    };
+    stats.joined += 1;
+
+    let mut player = Player::new(&username, world, &args, rng);
+    if let Err(e) = player.play(&mut bot, &args, &mut stats).await {
+        stats.count_failure(format!("playing: {e}"));
+    }
+    if let Some(rtt) = bot.clock().rtt() {
+        stats.record("ping rtt", rtt);
+    }
+    bot.disconnect();
+    stats
+}
+
+/// A bot's state while it is in the world.
+struct Player {
+    username: String,
+    rng: StdRng,
+    movement: [f32; 2],
+    view: ChunkView,
+    loaded: HashSet<ChunkPos>,
+    /// When the current view was requested and which of its chunks have not arrived yet
+    pending_chunks: Option<(Instant, HashSet<ChunkPos>)>,
+    /// Inputs not yet acknowledged, oldest first
+    inputs: VecDeque<(u32, Instant)>,
+    /// Chat messages not yet echoed back, by text
+    chats: HashMap<String, Instant>,
+    next_chat: usize,
+}
+
+impl Player {
+    fn new(username: &str, world: WorldInfo, args: &Args, rng: StdRng) -> Self {
+        Self {
+            username: username.to_string(),
+            rng,
+            movement: [0.0, 0.0],
+            view: ChunkView::new(
+                ChunkPos::new(0, 0),
+                args.view_radius.min(world.max_view_radius),
+            ),
+            loaded: HashSet::new(),
+            pending_chunks: None,
+            inputs: VecDeque::new(),
+            chats: HashMap::new(),
+            next_chat: 0,
+        }
+    }
+
+    async fn play(&mut self, bot: &mut Bot, args: &Args, stats: &mut Stats) -> anyhow::Result<()> {
+        let deadline = tokio::time::sleep(Duration::from_secs(args.duration));
+        tokio::pin!(deadline);
+        let mut input_interval = interval(args.input_rate);
+        let mut chat_interval = interval(args.chat_rate);
+        let mut view_interval = interval(args.view_rate);
+        self.request_view(bot, stats).await?;
+
+        loop {
+            tokio::select! {
+                _ = &mut deadline => return Ok(()),
+                _ = tick(&mut input_interval) => self.send_input(bot, stats).await?,
+                _ = tick(&mut chat_interval) => self.say(bot, stats).await?,
+                _ = tick(&mut view_interval) => {
+                    let (x, y) = [(-1, 0), (1, 0), (0, -1), (0, 1)][self.rng.random_range(0..4)];
+                    let center = self.view.center;
+                    self.view.center = ChunkPos::new(center.x + x, center.y + y);
+                    self.request_view(bot, stats).await?;
+                }
+                event = bot.next_event() => match event {
+                    Some(event) => self.handle(event, stats),
+                    None => {
+                        return Err(match bot.close_reason() {
+                            Some(reason) => anyhow!("Connection closed: {reason}"),
+                            None => anyhow!("Connection closed"),
+                        });
+                    }
+                },
+            }
+        }
+    }
+
+    async fn request_view(&mut self, bot: &mut Bot, stats: &mut Stats) -> anyhow::Result<()> {
+        bot.update_view(self.view).await?;
+        stats.count("view changes");
+        let missing: HashSet<ChunkPos> = self
+            .view
+            .positions()
+            .into_iter()
+            .filter(|pos| !self.loaded.contains(pos))
+            .collect();
+        if let Some((_, previous)) = &self.pending_chunks
+            && !previous.is_empty()
+        {
+            stats.count("views superseded before loading");
+        }
+        self.pending_chunks = (!missing.is_empty()).then(|| (Instant::now(), missing));
+        Ok(())
+    }
+
+    async fn send_input(&mut self, bot: &mut Bot, stats: &mut Stats) -> anyhow::Result<()> {
+        // Wanders, changing direction every couple of seconds at the default rate
+        if self.rng.random_bool(0.02) || self.movement == [0.0, 0.0] {
+            let angle = self.rng.random_range(0.0..std::f32::consts::TAU);
+            self.movement = [angle.cos(), angle.sin()];
+        }
+        let input_id = bot
+            .send_input(self.movement, ActionFlags::default())
+            .await?;
+        self.inputs.push_back((input_id, Instant::now()));
+        stats.count("inputs sent");
+        Ok(())
+    }
+
+    async fn say(&mut self, bot: &mut Bot, stats: &mut Stats) -> anyhow::Result<()> {
+        let text = format!("{} says {}", self.username, self.next_chat);
+        self.next_chat += 1;
+        bot.say(&text).await?;
+        self.chats.insert(text, Instant::now());
+        stats.count("chat sent");
+        Ok(())
+    }
+
+    fn handle(&mut self, event: BotEvent, stats: &mut Stats) {
+        match event {
+            BotEvent::Snapshot(snapshot) => {
+                stats.count("snapshots received");
+                let Some(ack) = snapshot.ack_input_id else {
+                    return;
+                };
+                // Ids wrap, anything up to half the range behind the ack counts as acknowledged
+                while let Some(&(input_id, sent)) = self.inputs.front()
+                    && ack.wrapping_sub(input_id) < u32::MAX / 2
+                {
+                    self.inputs.pop_front();
+                    stats.record("input ack", sent.elapsed());
+                }
+            }
+            BotEvent::Chunk(ChunkStreamMessage::Load(chunk)) => {
+                stats.count("chunks loaded");
+                self.loaded.insert(chunk.pos);
+                if let Some((requested, missing)) = &mut self.pending_chunks {
+                    missing.remove(&chunk.pos);
+                    if missing.is_empty() {
+                        stats.record("view loaded", requested.elapsed());
+                        self.pending_chunks = None;
+                    }
+                }
+            }
+            BotEvent::Chunk(ChunkStreamMessage::Unload(pos)) => {
+                self.loaded.remove(&pos);
+            }
+            BotEvent::Chat(ServerChatMessage::Message { from, text }) => {
+                stats.count("chat received");
+                if from == self.username
+                    && let Some(sent) = self.chats.remove(&text)
+                {
+                    stats.record("chat echo", sent.elapsed());
+                }
+            }
+            BotEvent::Chat(ServerChatMessage::Rejected(reason)) => {
+                stats.count_server_error(format!("chat rejected: {reason}"));
+            }
+            BotEvent::Control(ServerControlStreamMessage::ProtocolError(error)) => {
+                stats.count_server_error(format!("protocol error: {error}"));
+            }
+            BotEvent::Control(ServerControlStreamMessage::Disconnected(reason)) => {
+                stats.count_server_error(format!("disconnected: {reason}"));
+            }
+            BotEvent::Control(_) => {}
+        }
+    }
+}
+
+/// Ticks `rate` times a second starting one period from now, never for a rate of 0.
+fn interval(rate: f64) -> Option<Interval> {
+    (rate > 0.0).then(|| {
+        let period = Duration::from_secs_f64(1.0 / rate);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    })
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+struct Report<'a> {
+    stats: &'a Stats,
+    bots: usize,
+}
+
+impl<'a> Report<'a> {
+    fn new(stats: &'a Stats, bots: usize) -> Self {
+        Self { stats, bots }
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats;
+        writeln!(f, "Joined the world: {}/{}", stats.joined, self.bots)?;
+        write_counts(f, "Failures", &stats.failures)?;
+        write_counts(f, "Server errors", &stats.server_errors)?;
+
+        writeln!(
+            f,
+            "\n{:<20} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "Latency", "count", "p50", "p90", "p99", "max"
+        )?;
+        for (name, samples) in &stats.latencies {
+            let mut samples = samples.clone();
+            samples.sort();
+            writeln!(
+                f,
+                "{name:<20} {:>8} {:>10} {:>10} {:>10} {:>10}",
+                samples.len(),
+                Millis(percentile(&samples, 0.5)),
+                Millis(percentile(&samples, 0.9)),
+                Millis(percentile(&samples, 0.99)),
+                Millis(samples.last().copied()),
+            )?;
+        }
+
+        writeln!(f)?;
+        for (name, count) in &stats.counts {
+            writeln!(f, "{name:<32} {count:>10}")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_counts(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    counts: &BTreeMap<String, usize>,
+) -> fmt::Result {
+    if counts.is_empty() {
+        return writeln!(f, "{title}: none");
+    }
+    writeln!(f, "{title}:")?;
+    for (message, count) in counts {
+        writeln!(f, "  {count:>6} × {message}")?;
+    }
+    Ok(())
+}
+
+/// Nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[Duration], quantile: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+struct Millis(Option<Duration>);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self.0 {
+            Some(duration) => format!("{:.1} ms", duration.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        f.pad(&text)
+    }
+}
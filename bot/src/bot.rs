@@ -0,0 +1,557 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail};
+use quinn::{
+    Connection, ConnectionError, Endpoint, RecvStream, SendStream, VarInt,
+    crypto::rustls::QuicClientConfig,
+};
+use shared::{
+    AccountCredentials, AccountInfo, ActionFlags, ChunkStreamMessage, ChunkView, ClientChatMessage,
+    ClientControlStreamMessage, ClientDatagram, ClientHello, ClockSync, CodecError, Compression,
+    FrameCodec, FrameLimits, KnownServers, PING_INTERVAL, PlayerInput, ServerChatMessage,
+    ServerControlStreamMessage, ServerDatagram, Snapshot, SnapshotDecoder, StreamKind,
+    decode_payload, open_stream, pinned_client_config, receive_message, send_datagram,
+    send_message,
+};
+use tokio::{
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::JoinHandle,
+};
+
+/// What the server told the bot when it joined the world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorldInfo {
+    pub entity_id: u32,
+    pub chunk_size: usize,
+    pub max_view_radius: usize,
+}
+
+/// Anything the server sent, for bots that react to whatever comes next.
+#[derive(Debug)]
+pub enum BotEvent {
+    Control(ServerControlStreamMessage),
+    Chunk(ChunkStreamMessage),
+    Snapshot(Snapshot),
+    Chat(ServerChatMessage),
+}
+
+/// A headless client speaking the full protocol, for scripting and tests. Each step of the
+/// login flow is one call that waits for the server's answer.
+pub struct Bot {
+    _endpoint: Endpoint,
+    connection: Connection,
+    codec: FrameCodec,
+    send: SendStream,
+    control_rx: UnboundedReceiver<ServerControlStreamMessage>,
+    /// Control messages that arrived while waiting for the answer to something else
+    unsolicited: VecDeque<ServerControlStreamMessage>,
+    chunk_rx: UnboundedReceiver<ChunkStreamMessage>,
+    snapshot_rx: UnboundedReceiver<Snapshot>,
+    /// Opened by the first `say`
+    chat_send: Option<SendStream>,
+    chat_tx: UnboundedSender<ServerChatMessage>,
+    chat_rx: UnboundedReceiver<ServerChatMessage>,
+    /// Handed out with each login, resumes it from another connection
+    resume_token: Option<String>,
+    clock: Arc<Mutex<ClockSync>>,
+    next_input_id: u32,
+    started: Instant,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Bot {
+    /// Connects and completes the handshake. The server's certificate is pinned in
+    /// `known_servers` the first time, and must match on every later connection.
+    pub async fn connect(addr: SocketAddr, known_servers: &Path) -> anyhow::Result<Self> {
+        let known_servers = KnownServers::load(known_servers)?;
+        let client_crypto = pinned_client_config(addr.to_string(), known_servers.clone())?;
+        let client_config =
+            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
+        let bind: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(client_config);
+
+        let connection = endpoint.connect(addr, "localhost")?.await?;
+        known_servers.pin_connected(&addr.to_string(), &connection)?;
+        let (mut send, mut recv) = open_stream(&connection, StreamKind::Control).await?;
+        let mut codec = FrameCodec::default();
+        let features = Compression::ALL
+            .iter()
+            .map(|compression| compression.feature().to_string())
+            .collect();
+        codec
+            .send(
+                &mut send,
+                ClientControlStreamMessage::ConnectionRequest(ClientHello::new(features)),
+            )
+            .await?;
+        match codec
+            .receive::<ServerControlStreamMessage>(&mut recv)
+            .await?
+        {
+            ServerControlStreamMessage::Connected(hello) => {
+                codec.compression = Compression::from_features(&hello.features);
+            }
+            ServerControlStreamMessage::IncompatibleVersion {
+                server_version,
+                server_build,
+                ..
+            } => {
+                bail!("Server runs incompatible protocol version {server_version} ({server_build})")
+            }
+            other => bail!("Expected the server's hello, got {other:?}"),
+        }
+
+        let (control_tx, control_rx) = unbounded_channel();
+        let (chunk_tx, chunk_rx) = unbounded_channel();
+        let (chat_tx, chat_rx) = unbounded_channel();
+        let (snapshot_tx, snapshot_rx) = unbounded_channel();
+        let clock = Arc::new(Mutex::new(ClockSync::default()));
+        let chunk_codec = FrameCodec {
+            max_frame_size: FrameLimits::default().chunks,
+            ..codec
+        };
+        let tasks = vec![
+            tokio::spawn(receive_control(recv, codec, control_tx)),
+            tokio::spawn(accept_chunk_streams(
+                connection.clone(),
+                chunk_codec,
+                chunk_tx,
+            )),
+            tokio::spawn(handle_datagrams(
+                connection.clone(),
+                clock.clone(),
+                snapshot_tx,
+            )),
+        ];
+
+        Ok(Self {
+            _endpoint: endpoint,
+            connection,
+            codec,
+            send,
+            control_rx,
+            unsolicited: VecDeque::new(),
+            chunk_rx,
+            snapshot_rx,
+            chat_send: None,
+            chat_tx,
+            chat_rx,
+            resume_token: None,
+            clock,
+            next_input_id: 0,
+            started: Instant::now(),
+            tasks,
+        })
+    }
+
+    pub async fn create_account(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<AccountInfo> {
+        self.send(ClientControlStreamMessage::CreateAccount(
+            AccountCredentials::new(username.into(), password.into(), None),
+        ))
+        .await?;
+        let (account, resume_token) = self
+            .wait_for(|message| match message {
+                ServerControlStreamMessage::Authenticated {
+                    account,
+                    resume_token,
+                } => Some(Ok((account.clone(), resume_token.clone()))),
+                ServerControlStreamMessage::AccountCreateDenied(reason) => {
+                    Some(Err(anyhow!("Account creation denied: {reason}")))
+                }
+                _ => None,
+            })
+            .await?;
+        self.resume_token = Some(resume_token);
+        Ok(account)
+    }
+
+    pub async fn login(&mut self, username: &str, password: &str) -> anyhow::Result<AccountInfo> {
+        self.send(ClientControlStreamMessage::Login(AccountCredentials::new(
+            username.into(),
+            password.into(),
+            None,
+        )))
+        .await?;
+        let (account, resume_token) = self
+            .wait_for(|message| match message {
+                ServerControlStreamMessage::Authenticated {
+                    account,
+                    resume_token,
+                } => Some(Ok((account.clone(), resume_token.clone()))),
+                ServerControlStreamMessage::LoginDenied(reason) => {
+                    Some(Err(anyhow!("Login denied: {reason}")))
+                }
+                _ => None,
+            })
+            .await?;
+        self.resume_token = Some(resume_token);
+        Ok(account)
+    }
+
+    /// Takes over a login that dropped, with the token another bot got from `resume_token`.
+    pub async fn resume(&mut self, token: &str) -> anyhow::Result<AccountInfo> {
+        self.send(ClientControlStreamMessage::Resume(token.into()))
+            .await?;
+        let (account, resume_token) = self
+            .wait_for(|message| match message {
+                ServerControlStreamMessage::Resumed {
+                    account,
+                    resume_token,
+                    ..
+                } => Some(Ok((account.clone(), resume_token.clone()))),
+                ServerControlStreamMessage::ResumeDenied(reason) => {
+                    Some(Err(anyhow!("Resume denied: {reason}")))
+                }
+                _ => None,
+            })
+            .await?;
+        self.resume_token = Some(resume_token);
+        Ok(account)
+    }
+
+    /// The token from the last login or resume, single use.
+    pub fn resume_token(&self) -> Option<&str> {
+        self.resume_token.as_deref()
+    }
+
+    /// Creates a character and selects it.
+    pub async fn create_character(&mut self, name: &str) -> anyhow::Result<()> {
+        self.send(ClientControlStreamMessage::CreateCharacter(name.into()))
+            .await?;
+        self.wait_for_character().await
+    }
+
+    pub async fn select_character(&mut self, character_id: i64) -> anyhow::Result<()> {
+        self.send(ClientControlStreamMessage::SelectCharacter(character_id))
+            .await?;
+        self.wait_for_character().await
+    }
+
+    /// Enters the world with the selected character. Chunks only start arriving once a view is
+    /// sent with `update_view`.
+    pub async fn join_world(&mut self) -> anyhow::Result<WorldInfo> {
+        self.send(ClientControlStreamMessage::JoinWorldRequest)
+            .await?;
+        self.wait_for(|message| match message {
+            ServerControlStreamMessage::WorldJoined {
+                max_view_radius,
+                chunk_size,
+                entity_id,
+            } => Some(Ok(WorldInfo {
+                entity_id: *entity_id,
+                chunk_size: *chunk_size,
+                max_view_radius: *max_view_radius,
+            })),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn update_view(&mut self, view: ChunkView) -> anyhow::Result<()> {
+        self.send(ClientControlStreamMessage::UpdateView(view))
+            .await
+    }
+
+    /// The next chunk load or unload, `None` once the chunk stream has closed.
+    pub async fn next_chunk_message(&mut self) -> Option<ChunkStreamMessage> {
+        self.chunk_rx.recv().await
+    }
+
+    /// Sends one frame of movement and returns its input id.
+    pub async fn send_input(
+        &mut self,
+        movement: [f32; 2],
+        actions: ActionFlags,
+    ) -> anyhow::Result<u32> {
+        let input = PlayerInput {
+            input_id: self.next_input_id,
+            client_time_ms: self.started.elapsed().as_millis() as u64,
+            movement,
+            actions,
+        };
+        self.next_input_id = self.next_input_id.wrapping_add(1);
+        send_datagram(&self.connection, &ClientDatagram::Input(input)).await?;
+        Ok(input.input_id)
+    }
+
+    /// The next complete snapshot, `None` once the connection has closed.
+    pub async fn next_snapshot(&mut self) -> Option<Snapshot> {
+        self.snapshot_rx.recv().await
+    }
+
+    /// The next control message nothing else was waiting for, such as announcements.
+    pub async fn next_message(&mut self) -> anyhow::Result<ServerControlStreamMessage> {
+        if let Some(message) = self.unsolicited.pop_front() {
+            return Ok(message);
+        }
+        self.receive_control().await
+    }
+
+    /// Says `text` in chat, opening the chat stream the first time.
+    pub async fn say(&mut self, text: &str) -> anyhow::Result<()> {
+        let send = match &mut self.chat_send {
+            Some(send) => send,
+            None => {
+                let (send, recv) = open_stream(&self.connection, StreamKind::Chat).await?;
+                self.tasks
+                    .push(tokio::spawn(receive_chat(recv, self.chat_tx.clone())));
+                self.chat_send.insert(send)
+            }
+        };
+        Ok(send_message(send, ClientChatMessage::Say(text.into())).await?)
+    }
+
+    /// The next chat message, only ever arriving once the bot has said something.
+    pub async fn next_chat_message(&mut self) -> Option<ServerChatMessage> {
+        self.chat_rx.recv().await
+    }
+
+    /// Whatever the server sends next on any stream, `None` once the connection has closed.
+    /// Unlike waiting on each stream in turn, this is safe to cancel in a `select!`.
+    pub async fn next_event(&mut self) -> Option<BotEvent> {
+        if let Some(message) = self.unsolicited.pop_front() {
+            return Some(BotEvent::Control(message));
+        }
+        tokio::select! {
+            message = self.control_rx.recv() => message.map(BotEvent::Control),
+            Some(message) = self.chunk_rx.recv() => Some(BotEvent::Chunk(message)),
+            Some(snapshot) = self.snapshot_rx.recv() => Some(BotEvent::Snapshot(snapshot)),
+            Some(message) = self.chat_rx.recv() => Some(BotEvent::Chat(message)),
+        }
+    }
+
+    /// Why the connection closed, `None` while it is still open.
+    pub fn close_reason(&self) -> Option<ConnectionError> {
+        self.connection.close_reason()
+    }
+
+    /// Round trip time, jitter and server clock offset measured so far.
+    pub fn clock(&self) -> ClockSync {
+        match self.clock.lock() {
+            Ok(clock) => clock.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn disconnect(self) {
+        self.connection
+            .close(VarInt::from_u32(0), b"Bot disconnected");
+    }
+
+    async fn send(&mut self, message: ClientControlStreamMessage) -> anyhow::Result<()> {
+        Ok(self.codec.send(&mut self.send, message).await?)
+    }
+
+    async fn wait_for_character(&mut self) -> anyhow::Result<()> {
+        self.wait_for(|message| match message {
+            ServerControlStreamMessage::CharacterSelected => Some(Ok(())),
+            ServerControlStreamMessage::CharacterDenied(reason) => {
+                Some(Err(anyhow!("Character denied: {reason}")))
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    /// Reads control messages until `reply` recognises the answer, queueing any others.
+    async fn wait_for<T>(
+        &mut self,
+        mut reply: impl FnMut(&ServerControlStreamMessage) -> Option<anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        loop {
+            let message = self.receive_control().await?;
+            if let Some(result) = reply(&message) {
+                return result;
+            }
+            match message {
+                ServerControlStreamMessage::Disconnected(reason) => {
+                    bail!("Disconnected by server: {reason}")
+                }
+                ServerControlStreamMessage::ProtocolError(error) => {
+                    bail!("Server rejected message: {error}")
+                }
+                other => self.unsolicited.push_back(other),
+            }
+        }
+    }
+
+    async fn receive_control(&mut self) -> anyhow::Result<ServerControlStreamMessage> {
+        match self.control_rx.recv().await {
+            Some(message) => Ok(message),
+            None => match self.connection.close_reason() {
+                Some(reason) => bail!("Connection closed: {reason}"),
+                None => bail!("Control stream closed"),
+            },
+        }
+    }
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Reads the control stream on its own task, so nothing waiting on it can drop half a frame.
+async fn receive_control(
+    mut recv: RecvStream,
+    codec: FrameCodec,
+    control_tx: UnboundedSender<ServerControlStreamMessage>,
+) {
+    loop {
+        match codec.receive(&mut recv).await {
+            Ok(message) => {
+                if control_tx.send(message).is_err() {
+                    return;
+                }
+            }
+            // The connection going away is reported by `close_reason`
+            Err(CodecError::Closed | CodecError::Read(_)) => return,
+            Err(e) => {
+                eprintln!("Bot stopped receiving control messages: {e}");
+                return;
+            }
+        }
+    }
+}
+
+async fn receive_chat(mut recv: RecvStream, chat_tx: UnboundedSender<ServerChatMessage>) {
+    loop {
+        match receive_message(&mut recv).await {
+            Ok(message) => {
+                if chat_tx.send(message).is_err() {
+                    return;
+                }
+            }
+            // The connection going away is reported by `close_reason`
+            Err(CodecError::Closed | CodecError::Read(_)) => return,
+            Err(e) => {
+                eprintln!("Bot stopped receiving chat: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Reads every chunk stream the server opens, on its own task per stream so a partially read
+/// chunk is never dropped.
+async fn accept_chunk_streams(
+    connection: Connection,
+    codec: FrameCodec,
+    chunk_tx: UnboundedSender<ChunkStreamMessage>,
+) {
+    while let Ok(recv) = connection.accept_uni().await {
+        tokio::spawn(receive_chunks(recv, codec, chunk_tx.clone()));
+    }
+}
+
+async fn receive_chunks(
+    mut recv: RecvStream,
+    codec: FrameCodec,
+    chunk_tx: UnboundedSender<ChunkStreamMessage>,
+) {
+    match receive_message::<StreamKind>(&mut recv).await {
+        Ok(StreamKind::Chunks) => {}
+        Ok(kind) => {
+            eprintln!("Bot ignoring unexpected {kind:?} stream");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Bot could not read stream header: {e}");
+            return;
+        }
+    }
+
+    loop {
+        match codec.receive::<ChunkStreamMessage>(&mut recv).await {
+            Ok(message) => {
+                if chunk_tx.send(message).is_err() {
+                    return;
+                }
+            }
+            // The connection going away is reported by `close_reason`
+            Err(CodecError::Closed | CodecError::Read(_)) => return,
+            Err(e) => {
+                eprintln!("Bot stopped receiving chunks: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Decodes and acknowledges snapshots, and keeps the ping exchange going.
+async fn handle_datagrams(
+    connection: Connection,
+    clock: Arc<Mutex<ClockSync>>,
+    snapshot_tx: UnboundedSender<Snapshot>,
+) {
+    let mut snapshots = SnapshotDecoder::new();
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+    loop {
+        let reply = tokio::select! {
+            _ = ping_interval.tick() => {
+                let Ok(mut clock) = clock.lock() else {
+                    return;
+                };
+                ClientDatagram::Ping(clock.next_ping(Instant::now()))
+            }
+            datagram = connection.read_datagram() => {
+                let Ok(datagram) = datagram else {
+                    return;
+                };
+                match decode_payload::<ServerDatagram>(&datagram) {
+                    Ok(ServerDatagram::Snapshot(part)) => match snapshots.receive(&part) {
+                        Ok(Some(snapshot)) => {
+                            let tick = snapshot.tick;
+                            if snapshot_tx.send(snapshot).is_err() {
+                                return;
+                            }
+                            ClientDatagram::SnapshotAck(tick)
+                        }
+                        Ok(None) => continue,
+                        Err(e) => {
+                            eprintln!("Bot dropping snapshot: {e}");
+                            continue;
+                        }
+                    },
+                    Ok(ServerDatagram::Ping(ping)) => {
+                        let Ok(clock) = clock.lock() else {
+                            return;
+                        };
+                        ClientDatagram::Pong(ping.reply(clock.local_time_us(Instant::now())))
+                    }
+                    Ok(ServerDatagram::Pong(pong)) => {
+                        if let Ok(mut clock) = clock.lock() {
+                            clock.receive_pong(pong, Instant::now());
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("Bot dropping malformed datagram: {e}");
+                        continue;
+                    }
+                }
+            }
+        };
+        if let Err(e) = send_datagram(&connection, &reply).await {
+            eprintln!("Bot could not send datagram: {e}");
+        }
+    }
+}
@@ -0,0 +1,2 @@
+mod bot;
+pub use bot::*;
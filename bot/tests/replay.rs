@@ -0,0 +1,189 @@
+use std::{fs, net::SocketAddr, path::Path, time::Duration};
+
+use bot::Bot;
+use server_lib::{
+    GameManager, GameStartOption,
+    config::ConfigOverrides,
+    recording::Recording,
+    replay::{Replay, ReplayedPlayer},
+    start_server,
+    thread_manager::ThreadManager,
+};
+use shared::{ActionFlags, ChunkPos, ChunkView, ServerChatMessage, ServerControlStreamMessage};
+use tokio::{sync::watch, time::timeout};
+
+const WAIT: Duration = Duration::from_secs(10);
+/// Apart from single player's, so this can run alongside the other tests
+const PORT: u16 = 5414;
+
+/// Records a live session, then replays it into a fresh world and expects the same outcome.
+#[tokio::test]
+async fn replay_rebuilds_a_recorded_session() -> anyhow::Result<()> {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+    let data_dir = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+    let known_servers = data_dir.join("known_servers");
+    let thread_manager = ThreadManager::new();
+    let (ready_tx, _ready_rx) = watch::channel(false);
+    let server = start_server(
+        GameStartOption::NewGame("recorded".into()),
+        data_dir.clone(),
+        ConfigOverrides {
+            port: Some(PORT),
+            record: Some(true),
+            ..ConfigOverrides::single_player()
+        },
+        ready_tx,
+        thread_manager.child().await,
+    )
+    .await?;
+
+    let live = play(
+        server.game_manager.config.addr(),
+        &known_servers,
+        &server.game_manager,
+    )
+    .await;
+    server.shutdown().await;
+    thread_manager.shutdown().await;
+    let result = match live {
+        Ok(live) => {
+            let recording = server
+                .game_manager
+                .recorder
+                .as_ref()
+                .expect("recording was enabled")
+                .path()
+                .to_path_buf();
+            check_replay(&recording, &data_dir, live).await
+        }
+        Err(e) => Err(e),
+    };
+
+    let _ = fs::remove_dir_all(&data_dir);
+    result
+}
+
+/// Plays through a session and returns where Alice's player ended up.
+async fn play(
+    addr: SocketAddr,
+    known_servers: &Path,
+    game_manager: &GameManager,
+) -> anyhow::Result<ReplayedPlayer> {
+    let mut alice = Bot::connect(addr, known_servers).await?;
+    alice.create_account("alice", "alice password").await?;
+    alice.create_character("Alice").await?;
+    let world = alice.join_world().await?;
+    alice
+        .update_view(ChunkView::new(ChunkPos::new(0, 0), 1))
+        .await?;
+
+    let mut last_input = 0;
+    for i in 0..30 {
+        let movement = if i < 15 { [1.0, 0.0] } else { [0.0, -1.0] };
+        last_input = alice.send_input(movement, ActionFlags::default()).await?;
+        tokio::time::sleep(Duration::from_millis(16)).await;
+    }
+    timeout(WAIT, async {
+        while alice
+            .next_snapshot()
+            .await
+            .expect("snapshots stopped")
+            .ack_input_id
+            != Some(last_input)
+        {}
+    })
+    .await?;
+
+    alice.say("hello").await?;
+    match timeout(WAIT, alice.next_chat_message()).await? {
+        Some(ServerChatMessage::Message { from, text }) => {
+            assert_eq!((from.as_str(), text.as_str()), ("alice", "hello"));
+        }
+        other => panic!("expected chat, got {other:?}"),
+    }
+
+    let mut bob = Bot::connect(addr, known_servers).await?;
+    assert!(bob.login("alice", "wrong password").await.is_err());
+    bob.create_account("bob", "bob password").await?;
+    let token = bob
+        .resume_token()
+        .expect("a new account is logged in")
+        .to_string();
+    bob.disconnect();
+
+    // Carol picks up Bob's login where he dropped it
+    let mut carol = Bot::connect(addr, known_servers).await?;
+    let account = carol.resume(&token).await?;
+    assert_eq!(account.username, "bob");
+
+    let live = game_manager
+        .players
+        .lock()
+        .expect("player list poisoned")
+        .iter()
+        .map(|(_, player)| ReplayedPlayer {
+            entity_id: player.entity_id,
+            position: player.position,
+            last_input_id: player.last_input_id,
+        })
+        .find(|player| player.entity_id == world.entity_id)
+        .expect("Alice is in the world");
+    assert_eq!(live.last_input_id, Some(last_input));
+
+    alice.disconnect();
+    carol.disconnect();
+    Ok(live)
+}
+
+async fn check_replay(
+    recording: &Path,
+    data_dir: &Path,
+    live: ReplayedPlayer,
+) -> anyhow::Result<()> {
+    // The replay starts from nothing, which only matches a session that did too
+    let mut loaded_world = Recording::load(recording)?;
+    loaded_world.header.new_world = false;
+    assert!(
+        Replay::start(loaded_world, &data_dir.join("replay"))
+            .await
+            .is_err()
+    );
+
+    let mut replay = Replay::start(Recording::load(recording)?, &data_dir.join("replay")).await?;
+    replay.run().await?;
+
+    let clients = replay.clients();
+    assert_eq!(clients.len(), 3);
+    let alice = &clients[&0];
+    // Tick by tick the same inputs put Alice in exactly the same place
+    assert_eq!(alice.player, Some(live));
+    assert!(alice.received.iter().any(|message| matches!(
+        message,
+        ServerControlStreamMessage::WorldJoined { entity_id, .. } if *entity_id == live.entity_id
+    )));
+    assert!(alice.chat_received.iter().any(|message| matches!(
+        message,
+        ServerChatMessage::Message { text, .. } if text == "hello"
+    )));
+
+    // Passwords only survive as digests, yet the wrong one is still refused
+    let bob = &clients[&1];
+    assert!(matches!(
+        bob.received.as_slice(),
+        [
+            ServerControlStreamMessage::Connected(_),
+            ServerControlStreamMessage::LoginDenied(_),
+            ServerControlStreamMessage::Authenticated { .. },
+            ..
+        ]
+    ));
+
+    // The recorded token is only a digest, the replay resumes with the one it issued
+    let carol = &clients[&2];
+    assert!(carol.received.iter().any(|message| matches!(
+        message,
+        ServerControlStreamMessage::Resumed { account, .. } if account.username == "bob"
+    )));
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use bot::Bot;
+use server_lib::{
+    GameStartOption, ServerHandle, config::ConfigOverrides, start_server,
+    thread_manager::ThreadManager,
+};
+use shared::ServerControlStreamMessage;
+use tokio::{sync::watch, time::timeout};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// A dedicated server on `port` holding at most `max_players`, so bots count against the cap.
+async fn dedicated(
+    name: &str,
+    port: u16,
+    max_players: usize,
+) -> anyhow::Result<(ServerHandle, Arc<ThreadManager>, PathBuf)> {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+    let data_dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    let thread_manager = ThreadManager::new();
+    let (ready_tx, _ready_rx) = watch::channel(false);
+    let server = start_server(
+        GameStartOption::NewGame(name.into()),
+        data_dir.clone(),
+        ConfigOverrides {
+            bind_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            port: Some(port),
+            max_players: Some(max_players),
+            ..ConfigOverrides::default()
+        },
+        ready_tx,
+        thread_manager.child().await,
+    )
+    .await?;
+    Ok((server, thread_manager, data_dir))
+}
+
+/// Two accounts created at once on a server with one slot left, the refused name stays free.
+#[tokio::test]
+async fn refused_create_leaves_the_name_free() -> anyhow::Result<()> {
+    let (server, thread_manager, data_dir) = dedicated("create-full", 5415, 1).await?;
+    let addr = server.game_manager.config.addr();
+    let known_servers = data_dir.join("known_servers");
+
+    let result = async {
+        let mut alice = Bot::connect(addr, &known_servers).await?;
+        let mut bob = Bot::connect(addr, &known_servers).await?;
+
+        // Both get past the early capacity check while the passwords hash
+        let (alice_created, bob_created) = tokio::join!(
+            alice.create_account("alice", "alice password"),
+            bob.create_account("bob", "bob password"),
+        );
+        let (winner, mut loser, name, refused) = match (alice_created, bob_created) {
+            (Ok(_), Err(refused)) => (alice, bob, "bob", refused),
+            (Err(refused), Ok(_)) => (bob, alice, "alice", refused),
+            (alice, bob) => anyhow::bail!("expected exactly one refusal: {alice:?}, {bob:?}"),
+        };
+        assert!(refused.to_string().contains("Server is full"), "{refused}");
+
+        // Once the slot frees up the refused name can be created after all
+        winner.disconnect();
+        timeout(WAIT, async {
+            loop {
+                match loser.create_account(name, "second try").await {
+                    Err(e) if e.to_string().contains("Server is full") => {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                    created => return created,
+                }
+            }
+        })
+        .await??;
+        Ok(())
+    }
+    .await;
+
+    server.shutdown().await;
+    thread_manager.shutdown().await;
+    let _ = fs::remove_dir_all(&data_dir);
+    result
+}
+
+/// A reconnect on a full server takes over its old connection before the server notices it drop.
+#[tokio::test]
+async fn resume_takes_over_a_live_connection() -> anyhow::Result<()> {
+    let (server, thread_manager, data_dir) = dedicated("resume-full", 5416, 1).await?;
+    let addr = server.game_manager.config.addr();
+    let known_servers = data_dir.join("known_servers");
+
+    let result = async {
+        let mut stale = Bot::connect(addr, &known_servers).await?;
+        stale.create_account("alice", "alice password").await?;
+        let token = stale
+            .resume_token()
+            .expect("logged in bots have a token")
+            .to_string();
+
+        let mut reconnected = Bot::connect(addr, &known_servers).await?;
+        let account = reconnected.resume(&token).await?;
+        assert_eq!(account.username, "alice");
+
+        // The old connection is told it was taken over
+        let message = timeout(WAIT, stale.next_message()).await??;
+        assert!(
+            matches!(
+                &message,
+                ServerControlStreamMessage::Disconnected(reason) if reason == "Session resumed elsewhere"
+            ),
+            "{message:?}"
+        );
+        Ok(())
+    }
+    .await;
+
+    server.shutdown().await;
+    thread_manager.shutdown().await;
+    let _ = fs::remove_dir_all(&data_dir);
+    result
+}
@@ -0,0 +1,162 @@
+use std::{collections::HashSet, fs, path::Path, time::Duration};
+
+use bot::Bot;
+use server_lib::{
+    GameManager, GameStartOption, start_single_player, thread_manager::ThreadManager,
+};
+use shared::{ActionFlags, ChunkPos, ChunkStreamMessage, ChunkView};
+use tokio::{sync::watch, time::timeout};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Runs the whole login flow for two bots against an in-process single player server.
+#[tokio::test]
+async fn bots_play_through_the_login_flow() -> anyhow::Result<()> {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+    // Single player always uses the same port, so this is the only test that starts one
+    let world = format!("bot-test-{}", std::process::id());
+    let data_dir = std::env::temp_dir().join(&world);
+    let thread_manager = ThreadManager::new();
+    let (ready_tx, _ready_rx) = watch::channel(false);
+    let server = start_single_player(
+        GameStartOption::NewGame(world.clone()),
+        data_dir.clone(),
+        ready_tx,
+        thread_manager.child().await,
+    )
+    .await?;
+    let addr = server.game_manager.config.addr();
+    let known_servers = std::env::temp_dir().join(format!("{world}-known_servers"));
+
+    let result = play(addr, &known_servers, &server.game_manager).await;
+
+    server.shutdown().await;
+    thread_manager.shutdown().await;
+    let _ = fs::remove_dir_all(&data_dir);
+    let _ = fs::remove_file(&known_servers);
+    result
+}
+
+async fn play(
+    addr: std::net::SocketAddr,
+    known_servers: &Path,
+    game_manager: &GameManager,
+) -> anyhow::Result<()> {
+    let mut alice = Bot::connect(addr, known_servers).await?;
+    let account = alice.create_account("alice", "alice password").await?;
+    assert_eq!(account.username, "alice");
+    assert!(account.characters.is_empty());
+    alice.create_character("Alice").await?;
+    let world = alice.join_world().await?;
+
+    // Every chunk in view arrives
+    let view = ChunkView::new(ChunkPos::new(0, 0), 1.min(world.max_view_radius));
+    alice.update_view(view).await?;
+    let mut expected: HashSet<ChunkPos> = view.positions().into_iter().collect();
+    while !expected.is_empty() {
+        match timeout(WAIT, alice.next_chunk_message()).await? {
+            Some(ChunkStreamMessage::Load(chunk)) => {
+                assert!(expected.remove(&chunk.pos), "unexpected {:?}", chunk.pos);
+            }
+            other => panic!("expected a chunk, got {other:?}"),
+        }
+    }
+
+    // Inputs are applied and acknowledged in snapshots that include our own entity
+    let mut last_input = 0;
+    for _ in 0..20 {
+        last_input = alice.send_input([1.0, 0.0], ActionFlags::default()).await?;
+        tokio::time::sleep(Duration::from_millis(16)).await;
+    }
+    let moved_to = timeout(WAIT, async {
+        loop {
+            let snapshot = alice.next_snapshot().await.expect("snapshots stopped");
+            let own = snapshot
+                .entities
+                .iter()
+                .find(|entity| entity.entity_id == world.entity_id)
+                .expect("own entity in snapshot")
+                .position;
+            if snapshot.ack_input_id == Some(last_input) {
+                break own;
+            }
+        }
+    })
+    .await?;
+    assert!(moved_to.x > 0.5);
+
+    // Wrong passwords and taken names are refused, and the bot can carry on afterwards
+    let mut bob = Bot::connect(addr, known_servers).await?;
+    assert!(bob.login("alice", "wrong password").await.is_err());
+    assert!(bob.create_account("alice", "bob password").await.is_err());
+    bob.create_account("bob", "bob password").await?;
+    bob.create_character("Bob").await?;
+    let bob_world = bob.join_world().await?;
+    assert_ne!(bob_world.entity_id, world.entity_id);
+
+    // Alice sees Bob once he has joined
+    timeout(WAIT, async {
+        loop {
+            let snapshot = alice.next_snapshot().await.expect("snapshots stopped");
+            if snapshot
+                .entities
+                .iter()
+                .any(|entity| entity.entity_id == bob_world.entity_id)
+            {
+                break;
+            }
+        }
+    })
+    .await?;
+
+    // The ping exchange runs in the background
+    timeout(WAIT, async {
+        while alice.clock().rtt().is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+
+    // Leaving saves where Alice was, and she comes back there
+    alice.disconnect();
+    timeout(WAIT, async {
+        while in_world(game_manager, world.entity_id) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+    let mut alice = Bot::connect(addr, known_servers).await?;
+    let account = alice.login("alice", "alice password").await?;
+    alice
+        .select_character(account.characters[0].character_id)
+        .await?;
+    let rejoined = alice.join_world().await?;
+    let position = timeout(WAIT, async {
+        loop {
+            let snapshot = alice.next_snapshot().await.expect("snapshots stopped");
+            if let Some(own) = snapshot
+                .entities
+                .iter()
+                .find(|entity| entity.entity_id == rejoined.entity_id)
+            {
+                break own.position;
+            }
+        }
+    })
+    .await?;
+    assert!((position.x - moved_to.x).abs() < 0.1 && (position.y - moved_to.y).abs() < 0.1);
+
+    alice.disconnect();
+    bob.disconnect();
+    Ok(())
+}
+
+fn in_world(game_manager: &GameManager, entity_id: u32) -> bool {
+    game_manager
+        .players
+        .lock()
+        .expect("player list poisoned")
+        .iter()
+        .any(|(_, player)| player.entity_id == entity_id)
+}
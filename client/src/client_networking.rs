@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, path::Path, sync::Arc};
 
 use quinn::crypto::rustls::QuicClientConfig;
 use rustls::{
@@ -6,6 +6,10 @@ use rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
     pki_types::{CertificateDer, ServerName, UnixTime},
 };
+use shared::{KnownServers, pinned_client_config};
+
+/// Inside the client's data directory.
+const KNOWN_SERVERS_FILE_NAME: &str = "known_servers";
 
 #[derive(Debug)]
 pub struct AllowAnyLocalhostCert;
@@ -63,3 +67,20 @@ pub fn get_single_player_endpoint() -> anyhow::Result<quinn::Endpoint> {
 
     Ok(endpoint)
 }
+
+/// Also returns the known servers, to pin the server's certificate once connected.
+pub fn get_remote_endpoint(
+    server: SocketAddr,
+    data_dir: &Path,
+) -> anyhow::Result<(quinn::Endpoint, Arc<KnownServers>)> {
+    let known_servers = KnownServers::load(&data_dir.join(KNOWN_SERVERS_FILE_NAME))?;
+    let client_crypto = pinned_client_config(server.to_string(), known_servers.clone())?;
+
+    let client_config: quinn::ClientConfig =
+        quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
+
+    let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse()?)?;
+    endpoint.set_default_client_config(client_config);
+
+    Ok((endpoint, known_servers))
+}
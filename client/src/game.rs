@@ -1,5 +1,6 @@
 use std::{
     collections::HashSet,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -12,7 +13,14 @@ use winit::{
     window::{Fullscreen, Window},
 };
 
-use crate::{game_state::GameState, graphics::Graphics};
+use shared::ActionFlags;
+
+use crate::{
+    game_state::{GameState, ServerTarget},
+    graphics::Graphics,
+};
+
+const TITLE: &str = "Isometric Game!";
 
 struct GameManager {
     last_frame: Instant,
@@ -20,6 +28,7 @@ struct GameManager {
 
     window: Option<Arc<Window>>,
     fullscreen: bool,
+    title: String,
 
     graphics: Option<Graphics>,
 
@@ -27,23 +36,29 @@ struct GameManager {
     pressed_keys: HashSet<SmolStr>,
     cursor_location: (f32, f32),
 
+    server_target: ServerTarget,
+    /// Holds the single player worlds and known servers
+    data_dir: PathBuf,
     game_state: Option<GameState>,
 }
 
 impl GameManager {
-    pub async fn new() -> anyhow::Result<Self> {
+    pub async fn new(server_target: ServerTarget, data_dir: PathBuf) -> anyhow::Result<Self> {
         Ok(Self {
             last_frame: Instant::now(),
             target_frame_duration: Duration::from_secs_f64(1.0 / 120.0),
 
             window: None,
             fullscreen: false,
+            title: TITLE.into(),
 
             graphics: None,
 
             pressed_named_keys: HashSet::new(),
             pressed_keys: HashSet::new(),
             cursor_location: (0.0, 0.0),
+            server_target,
+            data_dir,
             game_state: None,
         })
     }
@@ -68,6 +83,56 @@ impl GameManager {
         self.cursor_location = location;
     }
 
+    /// Reads WASD as screen directions and turns them into a tile-space movement vector.
+    pub fn movement_input(&self) -> ([f32; 2], ActionFlags) {
+        // Screen up runs along +x +y on the isometric grid, screen right along +x -y
+        let mut movement = [0.0f32; 2];
+        for (key, [x, y]) in [
+            ("w", [1.0, 1.0]),
+            ("s", [-1.0, -1.0]),
+            ("d", [1.0, -1.0]),
+            ("a", [-1.0, 1.0]),
+        ] {
+            // Shift turns the logical key upper case while sprinting
+            if self
+                .pressed_keys
+                .iter()
+                .any(|pressed| pressed.eq_ignore_ascii_case(key))
+            {
+                movement[0] += x;
+                movement[1] += y;
+            }
+        }
+        let length = (movement[0] * movement[0] + movement[1] * movement[1]).sqrt();
+        if length > 0.0 {
+            movement = movement.map(|axis| axis / length);
+        }
+
+        let mut actions = ActionFlags::default();
+        if self.pressed_named_keys.contains(&NamedKey::Shift) {
+            actions.insert(ActionFlags::SPRINT);
+        }
+        if self.pressed_named_keys.contains(&NamedKey::Space) {
+            actions.insert(ActionFlags::JUMP);
+        }
+        (movement, actions)
+    }
+
+    /// Shows the round trip time in the title bar, only touching the window when it changes.
+    pub fn update_title(&mut self) {
+        let (Some(window), Some(game_state)) = (&self.window, &self.game_state) else {
+            return;
+        };
+        let title = match game_state.latency().rtt() {
+            Some(rtt) => format!("{TITLE} ({} ms)", rtt.as_millis()),
+            None => TITLE.into(),
+        };
+        if title != self.title {
+            window.set_title(&title);
+            self.title = title;
+        }
+    }
+
     pub fn update_window(&mut self) {
         if self.pressed_named_keys.contains(&NamedKey::F11) {
             if let Some(ref window) = self.window {
@@ -106,6 +171,11 @@ impl ApplicationHandler for GameManager {
         }
 
         if now >= next_frame_time || matches!(cause, StartCause::Init) {
+            let (movement, actions) = self.movement_input();
+            if let Some(game_state) = &mut self.game_state {
+                game_state.send_input(movement, actions);
+            }
+            self.update_title();
             if let Some(window) = &self.window {
                 window.request_redraw();
             }
@@ -120,7 +190,7 @@ impl ApplicationHandler for GameManager {
         if self.window.is_none() {
             if let Ok(window) = event_loop.create_window(
                 Window::default_attributes()
-                    .with_title("Isometric Game!")
+                    .with_title(TITLE)
                     .with_maximized(true)
                     .with_visible(false),
             ) {
@@ -144,7 +214,7 @@ impl ApplicationHandler for GameManager {
         if self.game_state.is_none()
             && let Some(graphics) = &self.graphics
         {
-            match pollster::block_on(GameState::new(graphics)) {
+            match pollster::block_on(GameState::new(graphics, self.server_target, &self.data_dir)) {
                 Ok(game_state) => self.game_state = Some(game_state),
                 Err(e) => {
                     eprintln!("Could not create game state: {e}");
@@ -211,10 +281,10 @@ pub struct Game {
 }
 
 impl Game {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(server_target: ServerTarget, data_dir: PathBuf) -> anyhow::Result<Self> {
         let event_loop = EventLoop::new()?;
         event_loop.set_control_flow(ControlFlow::Poll);
-        let game_manager = pollster::block_on(GameManager::new())?;
+        let game_manager = pollster::block_on(GameManager::new(server_target, data_dir))?;
         Ok(Self {
             event_loop,
             game_manager,
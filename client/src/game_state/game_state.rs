@@ -1,26 +1,62 @@
-use shared::AccountCredentials;
+use std::{path::Path, time::Instant};
+
+use shared::{
+    AccountCredentials, AccountInfo, ActionFlags, ChunkPos, ChunkStreamMessage, ChunkView,
+    ClockSync, PlayerInput, Snapshot,
+};
 use tokio::sync::mpsc::error::TryRecvError;
 
 use crate::{
-    game_state::ServerState,
+    game_state::{
+        DEFAULT_INTERPOLATION_DELAY, InterpolationBuffer, Prediction, ServerState, ServerTarget,
+        Terrain,
+    },
     graphics::{Graphics, Renderable},
-    mesh::ChunkMeshes,
+    mesh::{ChunkMeshes, EntityMeshes},
 };
 
+/// How many chunks around the player the client asks for; the server may clamp it lower.
+const VIEW_RADIUS: usize = 4;
+
+/// What the server told us when we joined the world, and what we have learned since.
+struct JoinedWorld {
+    entity_id: u32,
+    chunk_size: usize,
+    view: ChunkView,
+    terrain: Terrain,
+    prediction: Prediction,
+    interpolation: InterpolationBuffer,
+}
+
 pub struct GameState {
     server_state: ServerState,
     render_chunks: ChunkMeshes,
+    render_entities: EntityMeshes,
+    resume_token: Option<String>,
+    world: Option<JoinedWorld>,
+    next_input_id: u32,
+    started: Instant,
 }
 
 impl GameState {
-    pub async fn new(graphics: &Graphics) -> anyhow::Result<Self> {
-        let server_state = ServerState::new().await;
+    pub async fn new(
+        graphics: &Graphics,
+        target: ServerTarget,
+        data_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let server_state = ServerState::new(target, data_dir).await;
 
         let render_chunks = ChunkMeshes::new(graphics)?;
+        let render_entities = EntityMeshes::new(graphics)?;
 
         Ok(Self {
             server_state,
             render_chunks,
+            render_entities,
+            resume_token: None,
+            world: None,
+            next_input_id: 0,
+            started: Instant::now(),
         })
     }
     pub fn update(&mut self, graphics: &Graphics) {
@@ -44,40 +80,62 @@ impl GameState {
             use shared::ServerControlStreamMessage::*;
 
             match msg {
-                Connected => {
-                    if let Err(e) =
-                        self.server_state
-                            .client_tx
-                            .send(shared::ClientControlStreamMessage::Login(
-                                AccountCredentials {
-                                    username: "Test".into(),
-                                    user_password: "Test".into(),
-                                    server_password: None,
-                                },
-                            ))
-                    {
-                        eprintln!("Error sending account message to server: {e}");
+                Connected(_) => {
+                    self.world = None;
+                    match self.resume_token.clone() {
+                        Some(token) => {
+                            if let Err(e) = self
+                                .server_state
+                                .client_tx
+                                .send(shared::ClientControlStreamMessage::Resume(token))
+                            {
+                                eprintln!("Error sending resume request to server: {e}");
+                            }
+                        }
+                        None => self.login(),
                     }
                 }
-                Authenticated(account_info) => {
-                    if account_info.characters.len() > 1 {
-                        if let Err(e) = self.server_state.client_tx.send(
-                            shared::ClientControlStreamMessage::SelectCharacter(
-                                account_info.characters[0].character_id,
-                            ),
-                        ) {
-                            eprintln!("Error requesting character from server: {e}");
-                        }
-                    } else {
-                        if let Err(e) = self.server_state.client_tx.send(
-                            shared::ClientControlStreamMessage::CreateCharacter(
-                                "TestCharacter".into(),
-                            ),
-                        ) {
-                            eprintln!("Error requesting create character from server: {e}");
+                Resumed {
+                    account,
+                    character_id,
+                    resume_token,
+                } => {
+                    self.resume_token = Some(resume_token);
+                    match character_id {
+                        Some(_) => {
+                            if let Err(e) = self
+                                .server_state
+                                .client_tx
+                                .send(shared::ClientControlStreamMessage::JoinWorldRequest)
+                            {
+                                eprintln!("Error requesting to rejoin the world: {e}");
+                            }
                         }
+                        None => self.choose_character(&account),
                     }
                 }
+                IncompatibleVersion {
+                    server_version,
+                    server_build,
+                    client_version,
+                    client_build,
+                } => {
+                    eprintln!(
+                        "Server runs protocol version {server_version} ({server_build}) but this client uses {client_version} ({client_build})"
+                    );
+                }
+                ResumeDenied(reason) => {
+                    eprintln!("Could not resume session: {reason}");
+                    self.resume_token = None;
+                    self.login();
+                }
+                Authenticated {
+                    account: account_info,
+                    resume_token,
+                } => {
+                    self.resume_token = Some(resume_token);
+                    self.choose_character(&account_info);
+                }
                 CharacterSelected => {
                     if let Err(e) = self
                         .server_state
@@ -87,11 +145,22 @@ impl GameState {
                         eprintln!("Error requesting to join the world: {e}");
                     }
                 }
-                InitialWorld { chunks } => {
-                    for (chunk_pos, chunk) in chunks {
-                        self.render_chunks
-                            .insert(&graphics.device, chunk_pos, chunk);
-                    }
+                WorldJoined {
+                    max_view_radius,
+                    chunk_size,
+                    entity_id,
+                } => {
+                    let view =
+                        ChunkView::new(ChunkPos::new(0, 0), VIEW_RADIUS.min(max_view_radius));
+                    self.world = Some(JoinedWorld {
+                        entity_id,
+                        chunk_size,
+                        view,
+                        terrain: Terrain::new(chunk_size),
+                        prediction: Prediction::default(),
+                        interpolation: InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY),
+                    });
+                    self.send_view(view);
                 }
                 AccountCreateDenied(reason) => {
                     eprintln!("Could not create account: {reason}")
@@ -103,15 +172,181 @@ impl GameState {
                     eprintln!("Could not create/select character: {reason}");
                 }
                 Disconnected(reason) => {
+                    self.world = None;
                     eprintln!("Disconnected from server: {reason}")
                 }
+                Announcement(message) => {
+                    println!("[Server] {message}")
+                }
+                ProtocolError(error) => {
+                    eprintln!("Server rejected message: {error}")
+                }
             }
         }
+
+        while let Ok(snapshot) = self.server_state.snapshot_rx.try_recv() {
+            self.apply_snapshot(snapshot);
+        }
+
+        while let Ok(msg) = self.server_state.chunk_rx.try_recv() {
+            match msg {
+                ChunkStreamMessage::Load(chunk) => {
+                    if let Some(world) = &mut self.world {
+                        world.terrain.insert(chunk.clone());
+                    }
+                    self.render_chunks
+                        .insert(&graphics.device, chunk.pos, chunk);
+                }
+                ChunkStreamMessage::Unload(chunk_pos) => {
+                    if let Some(world) = &mut self.world {
+                        world.terrain.remove(chunk_pos);
+                    }
+                    self.render_chunks.remove(chunk_pos);
+                }
+            }
+        }
+
+        self.update_entities(graphics);
+    }
+
+    /// Shows our own entity where we predict it to be and everyone else slightly in the past.
+    fn update_entities(&mut self, graphics: &Graphics) {
+        let clock = self.server_state.clock_rx.borrow().clone();
+        let (player, remote) = match &self.world {
+            Some(world) => (
+                world.prediction.render_position(),
+                world
+                    .interpolation
+                    .sample_all(&clock, Instant::now())
+                    .into_iter()
+                    .filter(|entity| entity.entity_id != world.entity_id)
+                    .map(|entity| entity.position)
+                    .collect(),
+            ),
+            None => (None, Vec::new()),
+        };
+        self.render_entities.update(graphics, player, &remote);
+    }
+
+    /// Round trip time, jitter and server clock offset of the current connection.
+    pub fn latency(&self) -> ClockSync {
+        self.server_state.clock_rx.borrow().clone()
+    }
+
+    /// Sends this frame's input, the server expects one every frame while in the world. The input
+    /// is applied locally straight away rather than waiting for the server to echo it back.
+    pub fn send_input(&mut self, movement: [f32; 2], actions: ActionFlags) {
+        let Some(world) = &mut self.world else {
+            return;
+        };
+
+        let input = PlayerInput {
+            input_id: self.next_input_id,
+            client_time_ms: self.started.elapsed().as_millis() as u64,
+            movement,
+            actions,
+        };
+        self.next_input_id = self.next_input_id.wrapping_add(1);
+        world
+            .prediction
+            .apply_input(input, |x, y| world.terrain.height_at(x, y));
+        if let Err(e) = self.server_state.input_tx.send(input) {
+            eprintln!("Error queueing input for server: {e}");
+        }
+        self.follow_player();
+    }
+
+    /// Buffers everyone's states for interpolation and corrects our predicted position against
+    /// where the server says we are.
+    fn apply_snapshot(&mut self, snapshot: Snapshot) {
+        let Some(world) = &mut self.world else {
+            return;
+        };
+        world.interpolation.push(&snapshot);
+        let Some(player) = snapshot
+            .entities
+            .iter()
+            .find(|entity| entity.entity_id == world.entity_id)
+        else {
+            return;
+        };
+
+        world
+            .prediction
+            .reconcile(player.position, snapshot.ack_input_id, |x, y| {
+                world.terrain.height_at(x, y)
+            });
+        self.follow_player();
+    }
+
+    /// Asks for the chunks around our own entity whenever it enters a new chunk.
+    fn follow_player(&mut self) {
+        let Some(world) = &mut self.world else {
+            return;
+        };
+        let Some(position) = world.prediction.render_position() else {
+            return;
+        };
+
+        let (x, y) = position.tile();
+        let center = ChunkPos::from_tile(x, y, world.chunk_size);
+        if center != world.view.center {
+            world.view.center = center;
+            let view = world.view;
+            self.send_view(view);
+        }
+    }
+
+    fn send_view(&self, view: ChunkView) {
+        if let Err(e) = self
+            .server_state
+            .client_tx
+            .send(shared::ClientControlStreamMessage::UpdateView(view))
+        {
+            eprintln!("Error sending view to server: {e}");
+        }
+    }
+
+    fn choose_character(&self, account_info: &AccountInfo) {
+        if account_info.characters.len() > 1 {
+            if let Err(e) = self.server_state.client_tx.send(
+                shared::ClientControlStreamMessage::SelectCharacter(
+                    account_info.characters[0].character_id,
+                ),
+            ) {
+                eprintln!("Error requesting character from server: {e}");
+            }
+        } else if let Err(e) =
+            self.server_state
+                .client_tx
+                .send(shared::ClientControlStreamMessage::CreateCharacter(
+                    "TestCharacter".into(),
+                ))
+        {
+            eprintln!("Error requesting create character from server: {e}");
+        }
+    }
+
+    fn login(&self) {
+        if let Err(e) = self
+            .server_state
+            .client_tx
+            .send(shared::ClientControlStreamMessage::Login(
+                AccountCredentials {
+                    username: "Test".into(),
+                    user_password: "Test".into(),
+                    server_password: None,
+                },
+            ))
+        {
+            eprintln!("Error sending account message to server: {e}");
+        }
     }
 }
 
 impl Renderable for GameState {
     fn render(&self, render_pass: &mut wgpu::RenderPass) {
         self.render_chunks.render(render_pass);
+        self.render_entities.render(render_pass);
     }
 }
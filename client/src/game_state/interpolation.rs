@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    f32::consts::{PI, TAU},
+    time::{Duration, Instant},
+};
+
+use shared::{ClockSync, EntityState, PlayerPos, Snapshot, TICK_RATE};
+
+/// How far behind the synchronised server time remote entities are shown. Enough for a few lost or
+/// late snapshots to still have a state on either side.
+pub const DEFAULT_INTERPOLATION_DELAY: Duration = Duration::from_millis(100);
+/// How long an entity keeps moving along its last velocity once its states run out.
+const MAX_EXTRAPOLATION: Duration = Duration::from_millis(250);
+/// States kept per entity, about half a second of ticks.
+const ENTITY_HISTORY: usize = 32;
+/// Entities missing from every snapshot for this long are forgotten.
+const ENTITY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Recent states of every entity in the snapshots, for showing them at a steady point slightly in
+/// the past regardless of how unevenly the snapshots arrive. Where that point is comes from the
+/// connection's `ClockSync`, server time being the tick times the snapshots are stamped with.
+pub struct InterpolationBuffer {
+    delay: Duration,
+    newest_tick: Option<u32>,
+    /// Each entity's states with their tick, oldest first
+    entities: HashMap<u32, VecDeque<(u32, EntityState)>>,
+}
+
+impl InterpolationBuffer {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            newest_tick: None,
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Records every entity in `snapshot`. Snapshots may arrive in any order; ones older than
+    /// everything already held for an entity are still slotted in where they belong.
+    pub fn push(&mut self, snapshot: &Snapshot) {
+        if self.newest_tick.is_none_or(|newest| snapshot.tick > newest) {
+            self.newest_tick = Some(snapshot.tick);
+        }
+
+        for entity in &snapshot.entities {
+            let history = self.entities.entry(entity.entity_id).or_default();
+            let index = history.partition_point(|(tick, _)| *tick < snapshot.tick);
+            if history
+                .get(index)
+                .is_some_and(|(tick, _)| *tick == snapshot.tick)
+            {
+                continue;
+            }
+            history.insert(index, (snapshot.tick, *entity));
+            if history.len() > ENTITY_HISTORY {
+                history.pop_front();
+            }
+        }
+
+        if let Some(newest) = self.newest_tick {
+            let timeout = (ENTITY_TIMEOUT.as_secs_f64() * TICK_RATE as f64) as u32;
+            self.entities.retain(|_, history| {
+                history
+                    .back()
+                    .is_some_and(|(tick, _)| newest.saturating_sub(*tick) <= timeout)
+            });
+        }
+    }
+
+    /// Where `entity_id` should be shown at local time `now`, `None` if it is not known. Until
+    /// `clock` has its first sample entities are shown at their newest state.
+    pub fn sample(&self, entity_id: u32, clock: &ClockSync, now: Instant) -> Option<EntityState> {
+        let history = self.entities.get(&entity_id)?;
+        let Some(time) = self.render_time(clock, now) else {
+            return history.back().map(|(_, state)| *state);
+        };
+
+        let next = history.partition_point(|(tick, _)| tick_seconds(*tick) <= time);
+        if next == 0 {
+            return history.front().map(|(_, state)| *state);
+        }
+        let (from_tick, from) = history[next - 1];
+        match history.get(next) {
+            Some(&(to_tick, to)) => {
+                let from_time = tick_seconds(from_tick);
+                let alpha = (time - from_time) / (tick_seconds(to_tick) - from_time);
+                Some(interpolate(&from, &to, alpha as f32))
+            }
+            None => {
+                let ahead = (time - tick_seconds(from_tick)).min(MAX_EXTRAPOLATION.as_secs_f64());
+                Some(extrapolate(&from, ahead as f32))
+            }
+        }
+    }
+
+    /// Every known entity as it should be shown at local time `now`.
+    pub fn sample_all(&self, clock: &ClockSync, now: Instant) -> Vec<EntityState> {
+        self.entities
+            .keys()
+            .filter_map(|entity_id| self.sample(*entity_id, clock, now))
+            .collect()
+    }
+
+    /// The server time, in seconds, that entities are shown at.
+    fn render_time(&self, clock: &ClockSync, now: Instant) -> Option<f64> {
+        let server = clock.peer_time_us(now)? as f64 / 1_000_000.0;
+        Some(server - self.delay.as_secs_f64())
+    }
+}
+
+fn tick_seconds(tick: u32) -> f64 {
+    tick as f64 / TICK_RATE as f64
+}
+
+fn interpolate(from: &EntityState, to: &EntityState, alpha: f32) -> EntityState {
+    let lerp = |a: f32, b: f32| a + (b - a) * alpha;
+    // Turn the short way round
+    let turn = (to.facing - from.facing + PI).rem_euclid(TAU) - PI;
+
+    EntityState {
+        entity_id: to.entity_id,
+        position: PlayerPos::new(
+            lerp(from.position.x, to.position.x),
+            lerp(from.position.y, to.position.y),
+            lerp(from.position.z, to.position.z),
+        ),
+        velocity: [0, 1].map(|axis| lerp(from.velocity[axis], to.velocity[axis])),
+        facing: (from.facing + turn * alpha).rem_euclid(TAU),
+        animation: if alpha < 0.5 {
+            from.animation
+        } else {
+            to.animation
+        },
+    }
+}
+
+fn extrapolate(state: &EntityState, seconds: f32) -> EntityState {
+    EntityState {
+        position: PlayerPos::new(
+            state.position.x + state.velocity[0] * seconds,
+            state.position.y + state.velocity[1] * seconds,
+            state.position.z,
+        ),
+        ..*state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::Animation;
+
+    use super::*;
+
+    /// Tiles per second the test entity walks along x.
+    const SPEED: f32 = 6.0;
+
+    fn snapshot(tick: u32) -> Snapshot {
+        Snapshot {
+            tick,
+            ack_input_id: None,
+            entities: vec![EntityState {
+                entity_id: 1,
+                position: PlayerPos::new(tick_seconds(tick) as f32 * SPEED, 0.0, 0.0),
+                velocity: [SPEED, 0.0],
+                facing: 0.0,
+                animation: Animation::Walking,
+            }],
+        }
+    }
+
+    /// A clock that has measured the server `ahead` seconds in front of local time `start`.
+    fn synced(ahead: f64) -> (ClockSync, Instant) {
+        let mut clock = ClockSync::default();
+        let start = Instant::now();
+        let ping = clock.next_ping(start);
+        clock.receive_pong(ping.reply((ahead * 1_000_000.0) as u64), start);
+        (clock, start)
+    }
+
+    fn at(start: Instant, seconds: f64) -> Instant {
+        start + Duration::from_secs_f64(seconds)
+    }
+
+    /// Where the entity should be drawn at server time `seconds` with the default delay.
+    fn expected_x(seconds: f64) -> f32 {
+        (seconds - DEFAULT_INTERPOLATION_DELAY.as_secs_f64()) as f32 * SPEED
+    }
+
+    fn sample_x(buffer: &InterpolationBuffer, clock: &ClockSync, now: Instant) -> f32 {
+        buffer.sample(1, clock, now).unwrap().position.x
+    }
+
+    #[test]
+    fn interpolates_between_surrounding_states() {
+        let (clock, start) = synced(0.0);
+        let mut buffer = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        for tick in 0..=30 {
+            buffer.push(&snapshot(tick));
+        }
+
+        // Halfway between two ticks
+        let seconds = tick_seconds(20) + 0.5 / TICK_RATE as f64;
+        let x = sample_x(&buffer, &clock, at(start, seconds));
+        assert!((x - expected_x(seconds)).abs() < 1e-3, "{x}");
+    }
+
+    #[test]
+    fn follows_the_synchronised_server_clock() {
+        // The server started 5 s before this client
+        let (clock, start) = synced(5.0);
+        let mut buffer = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        for tick in TICK_RATE * 5..=TICK_RATE * 5 + 20 {
+            buffer.push(&snapshot(tick));
+        }
+
+        let x = sample_x(&buffer, &clock, at(start, 0.2));
+        assert!((x - expected_x(5.2)).abs() < 1e-3, "{x}");
+    }
+
+    #[test]
+    fn shows_newest_state_until_the_clock_is_measured() {
+        let clock = ClockSync::default();
+        let mut buffer = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        for tick in 0..=30 {
+            buffer.push(&snapshot(tick));
+        }
+
+        let x = sample_x(&buffer, &clock, Instant::now());
+        assert_eq!(x, snapshot(30).entities[0].position.x);
+    }
+
+    #[test]
+    fn jitter_keeps_motion_smooth() {
+        let (clock, start) = synced(0.0);
+        let mut buffer = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        // Up to 30 ms of extra delay, varying every snapshot
+        let mut arrivals: Vec<(f64, u32)> = (0..=120)
+            .map(|tick| (tick_seconds(tick) + (tick * 7 % 11) as f64 * 0.003, tick))
+            .collect();
+        arrivals.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut arrivals = arrivals.into_iter().peekable();
+
+        let mut previous = None;
+        for tick in 30..=120 {
+            // Frames keep a steady pace however the snapshots arrive
+            let frame = tick_seconds(tick) + 0.03;
+            while let Some((_, arrived)) = arrivals.next_if(|(arrival, _)| *arrival <= frame) {
+                buffer.push(&snapshot(arrived));
+            }
+
+            let x = sample_x(&buffer, &clock, at(start, frame));
+            assert!((x - expected_x(frame)).abs() < 1e-3, "tick {tick}: {x}");
+            if let Some(previous) = previous {
+                assert!(x >= previous, "moved backwards at tick {tick}");
+            }
+            previous = Some(x);
+        }
+    }
+
+    #[test]
+    fn reordered_snapshots_are_slotted_in_place() {
+        let (clock, start) = synced(0.0);
+        let mut in_order = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        let mut reordered = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        for tick in 0..=30 {
+            in_order.push(&snapshot(tick));
+        }
+        // Each odd tick overtakes the even one before it, and the last arrives twice
+        let mut order: Vec<u32> = (0..=30).collect();
+        order.chunks_mut(2).for_each(|pair| pair.reverse());
+        order.push(30);
+        for tick in order {
+            reordered.push(&snapshot(tick));
+        }
+
+        for step in 0..=10 {
+            let now = at(start, tick_seconds(20) + step as f64 * 0.003);
+            assert_eq!(
+                sample_x(&in_order, &clock, now),
+                sample_x(&reordered, &clock, now)
+            );
+        }
+    }
+
+    #[test]
+    fn interpolates_across_lost_snapshots() {
+        let (clock, start) = synced(0.0);
+        let mut buffer = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        for tick in (0..=30).filter(|tick| !(10..20).contains(tick)) {
+            buffer.push(&snapshot(tick));
+        }
+
+        // Tick 15 was lost, it is shown between ticks 9 and 20
+        let seconds = tick_seconds(15) + DEFAULT_INTERPOLATION_DELAY.as_secs_f64();
+        let x = sample_x(&buffer, &clock, at(start, seconds));
+        assert!((x - expected_x(seconds)).abs() < 1e-3, "{x}");
+    }
+
+    #[test]
+    fn extrapolates_briefly_when_snapshots_stop() {
+        let (clock, start) = synced(0.0);
+        let mut buffer = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        for tick in 0..=30 {
+            buffer.push(&snapshot(tick));
+        }
+        let last = tick_seconds(30) + DEFAULT_INTERPOLATION_DELAY.as_secs_f64();
+
+        // Keeps walking for a little while
+        let seconds = last + 0.1;
+        let x = sample_x(&buffer, &clock, at(start, seconds));
+        assert!((x - expected_x(seconds)).abs() < 1e-3, "{x}");
+
+        // Then stops rather than running off
+        let limit = expected_x(last + MAX_EXTRAPOLATION.as_secs_f64());
+        assert!((sample_x(&buffer, &clock, at(start, last + 5.0)) - limit).abs() < 1e-3);
+    }
+
+    #[test]
+    fn forgets_entities_that_stop_appearing() {
+        let (clock, start) = synced(0.0);
+        let mut buffer = InterpolationBuffer::new(DEFAULT_INTERPOLATION_DELAY);
+        buffer.push(&snapshot(0));
+
+        let empty = |tick| Snapshot {
+            tick,
+            ack_input_id: None,
+            entities: Vec::new(),
+        };
+        buffer.push(&empty(TICK_RATE / 2));
+        assert!(buffer.sample(1, &clock, at(start, 0.5)).is_some());
+        buffer.push(&empty(TICK_RATE * 2));
+        assert!(buffer.sample(1, &clock, at(start, 2.0)).is_none());
+    }
+}
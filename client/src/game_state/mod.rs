@@ -3,3 +3,12 @@ pub use server_state::*;
 
 mod game_state;
 pub use game_state::*;
+
+mod interpolation;
+pub use interpolation::*;
+
+mod prediction;
+pub use prediction::*;
+
+mod terrain;
+pub use terrain::*;
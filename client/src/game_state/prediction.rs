@@ -0,0 +1,105 @@
+use std::{collections::VecDeque, time::Duration};
+
+use shared::{PlayerInput, PlayerPos, apply_input, input_duration};
+
+/// Inputs kept for replay, a little over four seconds at 60 frames per second.
+const INPUT_HISTORY: usize = 256;
+/// Corrections further than this many tiles are snapped to instead of smoothed out.
+const SNAP_DISTANCE: f32 = 2.0;
+/// How quickly a visible correction is blended away, per second.
+const CORRECTION_RATE: f32 = 10.0;
+
+/// Moves our own entity as soon as input is sent instead of waiting for the server, then corrects
+/// it whenever a snapshot shows where the server actually put it.
+#[derive(Default)]
+pub struct Prediction {
+    /// Sent inputs the server has not acknowledged yet, oldest first
+    history: VecDeque<PlayerInput>,
+    /// Client time of the last input the server acknowledged
+    acked_client_time_ms: Option<u64>,
+    /// Client time of the last input applied locally
+    last_client_time_ms: Option<u64>,
+    /// Unknown until the first snapshot that includes us
+    position: Option<PlayerPos>,
+    /// Offset from the predicted to the displayed position, shrinks towards zero over time
+    correction: [f32; 3],
+}
+
+impl Prediction {
+    /// Applies an input the moment it is sent and remembers it until the server acknowledges it.
+    pub fn apply_input(&mut self, input: PlayerInput, height_at: impl Fn(i64, i64) -> Option<i64>) {
+        let dt = input_duration(self.last_client_time_ms, &input);
+        self.last_client_time_ms = Some(input.client_time_ms);
+        if let Some(position) = self.position {
+            self.position = Some(apply_input(position, &input, dt, height_at));
+        }
+        self.decay_correction(dt);
+
+        if self.history.len() == INPUT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(input);
+    }
+
+    /// Snaps to the server's position for `ack_input_id` and replays every input sent after it.
+    /// Small differences from the previous prediction are kept as a correction that fades out.
+    pub fn reconcile(
+        &mut self,
+        server_position: PlayerPos,
+        ack_input_id: Option<u32>,
+        height_at: impl Fn(i64, i64) -> Option<i64>,
+    ) {
+        if let Some(ack) = ack_input_id {
+            // Input ids wrap, so compare by distance rather than magnitude
+            while let Some(input) = self.history.front()
+                && (input.input_id.wrapping_sub(ack) as i32) <= 0
+            {
+                self.acked_client_time_ms = Some(input.client_time_ms);
+                self.history.pop_front();
+            }
+        }
+
+        let mut position = server_position;
+        let mut last_client_time_ms = self.acked_client_time_ms;
+        for input in &self.history {
+            let dt = input_duration(last_client_time_ms, input);
+            position = apply_input(position, input, dt, &height_at);
+            last_client_time_ms = Some(input.client_time_ms);
+        }
+
+        if let Some(previous) = self.position {
+            let error = [
+                previous.x - position.x,
+                previous.y - position.y,
+                previous.z - position.z,
+            ];
+            let correction = [0, 1, 2].map(|axis| self.correction[axis] + error[axis]);
+            let distance = correction
+                .iter()
+                .map(|axis| axis * axis)
+                .sum::<f32>()
+                .sqrt();
+            self.correction = if distance > SNAP_DISTANCE {
+                [0.0; 3]
+            } else {
+                correction
+            };
+        }
+        self.position = Some(position);
+    }
+
+    /// The predicted position with the remaining correction applied, for display.
+    pub fn render_position(&self) -> Option<PlayerPos> {
+        let position = self.position?;
+        Some(PlayerPos::new(
+            position.x + self.correction[0],
+            position.y + self.correction[1],
+            position.z + self.correction[2],
+        ))
+    }
+
+    fn decay_correction(&mut self, dt: Duration) {
+        let keep = (-CORRECTION_RATE * dt.as_secs_f32()).exp();
+        self.correction = self.correction.map(|axis| axis * keep);
+    }
+}
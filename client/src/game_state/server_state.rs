@@ -1,44 +1,320 @@
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
 use server_lib::thread_manager::ThreadManager;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use shared::{
+    ChunkStreamMessage, ClientControlStreamMessage, ClientDatagram, ClientHello, ClockSync,
+    CodecError, Compression, FrameCodec, FrameLimits, PING_INTERVAL, PlayerInput,
+    ServerControlStreamMessage, ServerDatagram, Snapshot, SnapshotDecoder, StreamKind,
+    decode_payload, forward_frames, open_stream, receive_message, send_datagram,
+};
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        watch,
+    },
+    task::JoinHandle,
+};
 
 use crate::client_networking;
 
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy)]
+pub enum ServerTarget {
+    SinglePlayer,
+    Remote(SocketAddr),
+}
+
+enum LinkEnd {
+    Cancelled,
+    Closed,
+    Lost(CodecError),
+}
+
+/// The game's ends of the channels a link forwards over, kept across reconnects.
+struct LinkChannels {
+    server_tx: UnboundedSender<ServerControlStreamMessage>,
+    chunk_tx: UnboundedSender<ChunkStreamMessage>,
+    snapshot_tx: UnboundedSender<Snapshot>,
+    clock_tx: watch::Sender<ClockSync>,
+    client_rx: UnboundedReceiver<ClientControlStreamMessage>,
+    input_rx: UnboundedReceiver<PlayerInput>,
+}
+
+struct ServerLink {
+    _endpoint: Endpoint,
+    connection: Connection,
+    codec: FrameCodec,
+    send: SendStream,
+    /// Control frames from `reader`, decoded here once compression is known
+    frames: UnboundedReceiver<Result<Vec<u8>, CodecError>>,
+    reader: JoinHandle<()>,
+    snapshots: SnapshotDecoder,
+    clock: ClockSync,
+}
+
+impl Drop for ServerLink {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl ServerLink {
+    /// Opens a fresh endpoint and control stream, so a reconnect comes from a new local address.
+    async fn connect(target: ServerTarget, data_dir: &Path) -> anyhow::Result<Self> {
+        let (endpoint, addr, known_servers) = match target {
+            ServerTarget::SinglePlayer => (
+                client_networking::get_single_player_endpoint()?,
+                SocketAddr::from_str("127.0.0.1:5250")?,
+                None,
+            ),
+            ServerTarget::Remote(addr) => {
+                let (endpoint, known_servers) = client_networking::get_remote_endpoint(addr, data_dir)?;
+                (endpoint, addr, Some(known_servers))
+            }
+        };
+        let connection = endpoint.connect(addr, "localhost")?.await?;
+        if let Some(known_servers) = known_servers {
+            known_servers.pin_connected(&addr.to_string(), &connection)?;
+        }
+        let (mut send, recv) = open_stream(&connection, StreamKind::Control).await?;
+        let codec = FrameCodec::default();
+        let features = Compression::ALL
+            .iter()
+            .map(|compression| compression.feature().to_string())
+            .collect();
+        codec
+            .send(
+                &mut send,
+                ClientControlStreamMessage::ConnectionRequest(ClientHello::new(features)),
+            )
+            .await?;
+        // Read on its own task, so the other branches of `forward` never cut a frame off halfway
+        let (frame_tx, frames) = unbounded_channel();
+        let reader = tokio::spawn(forward_frames(recv, codec, frame_tx));
+
+        Ok(Self {
+            _endpoint: endpoint,
+            connection,
+            codec,
+            send,
+            frames,
+            reader,
+            snapshots: SnapshotDecoder::new(),
+            clock: ClockSync::default(),
+        })
+    }
+
+    async fn forward(
+        mut self,
+        thread_manager: &Arc<ThreadManager>,
+        channels: &mut LinkChannels,
+    ) -> LinkEnd {
+        let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = thread_manager.await_cancel() => {
+                    println!("Shutting down connection loop to game server");
+                    return LinkEnd::Cancelled;
+                }
+                frame = self.frames.recv() => {
+                    // The reader only stops after passing on the error that ended the stream
+                    let frame = frame.unwrap_or(Err(CodecError::Closed));
+                    match frame.and_then(|frame| self.codec.decode::<ServerControlStreamMessage>(&frame)) {
+                        Ok(msg) => {
+                            if let ServerControlStreamMessage::Connected(hello) = &msg {
+                                self.codec.compression = Compression::from_features(&hello.features);
+                            }
+                            let closed = matches!(
+                                msg,
+                                ServerControlStreamMessage::Disconnected(_)
+                                    | ServerControlStreamMessage::IncompatibleVersion { .. }
+                            );
+                            if let Err(e) = channels.server_tx.send(msg) {
+                                eprintln!("Error forwarding message from server to client: {e}");
+                            }
+                            if closed {
+                                return LinkEnd::Closed;
+                            }
+                        },
+                        Err(e) => return LinkEnd::Lost(e),
+                    }
+                }
+                Some(msg) = channels.client_rx.recv() => {
+                    if let Err(e) = self.codec.send(&mut self.send, msg).await {
+                        return LinkEnd::Lost(e);
+                    }
+                }
+                Some(input) = channels.input_rx.recv() => {
+                    // Inputs are resent every frame, so one that does not fit is simply skipped
+                    if let Err(e) = send_datagram(&self.connection, &ClientDatagram::Input(input)).await {
+                        eprintln!("Error sending input to server: {e}");
+                    }
+                }
+                _ = ping_interval.tick() => {
+                    let ping = ClientDatagram::Ping(self.clock.next_ping(Instant::now()));
+                    if let Err(e) = send_datagram(&self.connection, &ping).await {
+                        eprintln!("Error pinging server: {e}");
+                    }
+                }
+                Ok(datagram) = self.connection.read_datagram() => {
+                    match decode_payload::<ServerDatagram>(&datagram) {
+                        Ok(ServerDatagram::Snapshot(part)) => {
+                            if let Some(snapshot) = self.receive_snapshot_part(&part).await
+                                && channels.snapshot_tx.send(snapshot).is_err()
+                            {
+                                return LinkEnd::Cancelled;
+                            }
+                        }
+                        Ok(ServerDatagram::Ping(ping)) => {
+                            let pong = ClientDatagram::Pong(ping.reply(self.clock.local_time_us(Instant::now())));
+                            if let Err(e) = send_datagram(&self.connection, &pong).await {
+                                eprintln!("Error answering ping from server: {e}");
+                            }
+                        }
+                        Ok(ServerDatagram::Pong(pong)) => {
+                            self.clock.receive_pong(pong, Instant::now());
+                            channels.clock_tx.send_replace(self.clock.clone());
+                        }
+                        Err(e) => eprintln!("Dropping malformed datagram from server: {e}"),
+                    }
+                }
+                Ok(recv) = self.connection.accept_uni() => {
+                    let codec = FrameCodec {
+                        max_frame_size: FrameLimits::default().chunks,
+                        ..self.codec
+                    };
+                    let chunk_tx = channels.chunk_tx.clone();
+                    thread_manager
+                        .spawn(move || receive_chunks(recv, codec, chunk_tx))
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Feeds a snapshot part to the decoder, acknowledging the snapshot once it is complete.
+    async fn receive_snapshot_part(&mut self, part: &[u8]) -> Option<Snapshot> {
+        match self.snapshots.receive(part) {
+            Ok(Some(snapshot)) => {
+                let ack = ClientDatagram::SnapshotAck(snapshot.tick);
+                if let Err(e) = send_datagram(&self.connection, &ack).await {
+                    eprintln!("Error acknowledging snapshot: {e}");
+                }
+                Some(snapshot)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Dropping snapshot: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// Reads the chunk stream on its own task so a partially received chunk is never dropped.
+async fn receive_chunks(
+    mut recv: RecvStream,
+    codec: FrameCodec,
+    chunk_tx: UnboundedSender<ChunkStreamMessage>,
+) {
+    match receive_message::<StreamKind>(&mut recv).await {
+        Ok(StreamKind::Chunks) => {}
+        Ok(kind) => {
+            eprintln!("Ignoring unexpected {kind:?} stream from server");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Error reading stream header from server: {e}");
+            return;
+        }
+    }
+
+    loop {
+        match codec.receive::<ChunkStreamMessage>(&mut recv).await {
+            Ok(msg) => {
+                if chunk_tx.send(msg).is_err() {
+                    return;
+                }
+            }
+            Err(CodecError::Closed) => return,
+            Err(e) => {
+                eprintln!("Error receiving chunks from server: {e}");
+                return;
+            }
+        }
+    }
+}
+
 pub struct ServerState {
     pub thread_manager: Arc<ThreadManager>,
     pub client_tx: UnboundedSender<shared::ClientControlStreamMessage>,
     pub server_rx: UnboundedReceiver<shared::ServerControlStreamMessage>,
+    pub chunk_rx: UnboundedReceiver<ChunkStreamMessage>,
+    pub input_tx: UnboundedSender<PlayerInput>,
+    pub snapshot_rx: UnboundedReceiver<Snapshot>,
+    /// Latest round trip and server clock estimate, reset on every reconnect
+    pub clock_rx: watch::Receiver<ClockSync>,
 }
 
 impl ServerState {
-    pub async fn new() -> Self {
+    pub async fn new(target: ServerTarget, data_dir: &Path) -> Self {
         let thread_manager = ThreadManager::new();
         let (ready_tx, mut ready_rx) = tokio::sync::watch::channel(false);
 
-        let child = thread_manager.child().await;
-        thread_manager
-            .spawn({
-                let thread_manager = thread_manager.clone();
-                move || async move {
-                    if let Err(e) = server_lib::start_single_player(
-                        server_lib::GameStartOption::LoadGame("blah".into()),
-                        ready_tx,
-                        child,
-                    )
-                    .await
-                    {
-                        eprintln!("Error with single player server: {e}");
-                        thread_manager.abort_async().await;
-                    }
-                }
-            })
-            .await;
-        let (mut client_tx, mut client_rx) =
+        match target {
+            ServerTarget::SinglePlayer => {
+                let child = thread_manager.child().await;
+                let data_dir = data_dir.to_path_buf();
+                thread_manager
+                    .spawn({
+                        let thread_manager = thread_manager.clone();
+                        move || async move {
+                            if let Err(e) = server_lib::start_single_player(
+                                server_lib::GameStartOption::LoadGame("blah".into()),
+                                data_dir,
+                                ready_tx,
+                                child,
+                            )
+                            .await
+                            {
+                                eprintln!("Error with single player server: {e}");
+                                thread_manager.abort_async().await;
+                            }
+                        }
+                    })
+                    .await;
+            }
+            ServerTarget::Remote(_) => {
+                let _ = ready_tx.send(true);
+            }
+        }
+        let (client_tx, client_rx) =
             unbounded_channel::<shared::ClientControlStreamMessage>();
-        let (mut server_tx, mut server_rx) =
+        let (server_tx, server_rx) =
             unbounded_channel::<shared::ServerControlStreamMessage>();
+        let (chunk_tx, chunk_rx) = unbounded_channel::<ChunkStreamMessage>();
+        let (input_tx, input_rx) = unbounded_channel::<PlayerInput>();
+        let (snapshot_tx, snapshot_rx) = unbounded_channel::<Snapshot>();
+        let (clock_tx, clock_rx) = watch::channel(ClockSync::default());
+        let mut channels = LinkChannels {
+            server_tx,
+            chunk_tx,
+            snapshot_tx,
+            clock_tx,
+            client_rx,
+            input_rx,
+        };
 
+        let data_dir = data_dir.to_path_buf();
         thread_manager
             .spawn({
                 let thread_manager = thread_manager.clone();
@@ -50,78 +326,35 @@ impl ServerState {
                             return;
                         }
                     }
-                    let endpoint = match client_networking::get_single_player_endpoint() {
-                        Ok(endpoint) => endpoint,
-                        Err(e) => {
-                            eprintln!("Could not get single player endpoint: {e}");
-                            thread_manager.shutdown().await;
-                            return;
-                        }
-                    };
-                    let addr = match SocketAddr::from_str("127.0.0.1:5250") {
-                        Ok(addr) => addr,
-                        Err(e) => {
-                            eprintln!("Failed to parse socket address for server: {e}");
-                            thread_manager.shutdown().await;
-                            return;
-                        }
-                    };
-                    let connecting = match endpoint.connect(addr, "localhost".into()) {
-                        Ok(connecting) => connecting,
-                        Err(e) => {
-                            eprintln!("Failed to establish connection to server: {e}");
-                            thread_manager.shutdown().await;
-                            return;
-                        }
-                    };
-                    let connection = match connecting.await {
-                        Ok(connection) => connection,
-                        Err(e) => {
-                            eprintln!("Connection to server error: {e}");
-                            thread_manager.shutdown().await;
-                            return;
-                        }
-                    };
-                    let (mut send, mut recv) = match connection.open_bi().await {
-                        Ok((send, recv)) => (send, recv),
-                        Err(e) => {
-                            eprintln!("Error opening bi-directional stream to server: {e}");
-                            thread_manager.shutdown().await;
-                            return;
-                        }
-                    };
-
-                    if let Err(e) = shared::send_message(&mut send, shared::ClientControlStreamMessage::ConnectionRequest).await {
-                        eprintln!("Failed to send connection request to server: {e}");
-                        thread_manager.shutdown().await;
-                        return;
-                    }
-
+                    let mut attempt = 0;
                     loop {
-                        tokio::select! {
-                            _ = thread_manager.await_cancel() => {
-                                println!("Shutting down connection loop to game server");
-                                break;
-                            }
-                            result = shared::receive_message::<shared::ServerControlStreamMessage>(&mut recv) => {
-                                match result {
-                                    Ok(msg) => {
-                                        if let Err(e) = server_tx.send(msg) {
-                                            eprintln!("Error forwarding message from server to client: {e}");
-                                        }
-                                    },
-                                    Err(e) => {
-                                        eprintln!("Error receiving message from server: {e}");
-                                        thread_manager.shutdown().await;
-                                        break;
-                                    }
+                        let link = match ServerLink::connect(target, &data_dir).await {
+                            Ok(link) => link,
+                            Err(e) if attempt < MAX_RECONNECT_ATTEMPTS => {
+                                attempt += 1;
+                                eprintln!("Could not reach server ({e}), retrying ({attempt}/{MAX_RECONNECT_ATTEMPTS})");
+                                tokio::select! {
+                                    _ = thread_manager.await_cancel() => return,
+                                    _ = tokio::time::sleep(RECONNECT_BACKOFF * attempt) => continue,
                                 }
                             }
-                            Some(msg) = client_rx.recv() => {
-                                if let Err(e) = shared::send_message::<shared::ClientControlStreamMessage>(&mut send, msg).await {
-                                    eprintln!("Error sending message to the server: {e}");
-                                    return;
-                                }
+                            Err(e) => {
+                                eprintln!("Could not connect to server: {e}");
+                                thread_manager.shutdown().await;
+                                return;
+                            }
+                        };
+                        attempt = 0;
+                        channels.clock_tx.send_replace(link.clock.clone());
+
+                        match link.forward(&thread_manager, &mut channels).await {
+                            LinkEnd::Cancelled => return,
+                            LinkEnd::Closed => {
+                                thread_manager.shutdown().await;
+                                return;
+                            }
+                            LinkEnd::Lost(e) => {
+                                eprintln!("Lost connection to server ({e}), reconnecting");
                             }
                         }
                     }
@@ -133,6 +366,10 @@ impl ServerState {
             thread_manager: thread_manager.clone(),
             client_tx,
             server_rx,
+            chunk_rx,
+            input_tx,
+            snapshot_rx,
+            clock_rx,
         }
     }
 }
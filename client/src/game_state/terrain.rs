@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+
+use shared::{Chunk, ChunkPos};
+
+/// The chunks the server has streamed to us, kept so local movement can be predicted against the
+/// same ground the server uses.
+pub struct Terrain {
+    chunk_size: usize,
+    chunks: HashMap<ChunkPos, Chunk>,
+}
+
+impl Terrain {
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size,
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, chunk: Chunk) {
+        self.chunks.insert(chunk.pos, chunk);
+    }
+
+    pub fn remove(&mut self, pos: ChunkPos) {
+        self.chunks.remove(&pos);
+    }
+
+    /// Height of the column at `x`, `y`, `None` if it has no ground or its chunk is not loaded.
+    pub fn height_at(&self, x: i64, y: i64) -> Option<i64> {
+        self.chunks
+            .get(&ChunkPos::from_tile(x, y, self.chunk_size))?
+            .height_at(x, y)
+    }
+}
@@ -1,4 +1,6 @@
-use crate::game::Game;
+use std::{net::SocketAddr, path::PathBuf};
+
+use crate::{game::Game, game_state::ServerTarget};
 use anyhow::anyhow;
 
 mod client_networking;
@@ -14,6 +16,23 @@ async fn main() -> anyhow::Result<()> {
         return Err(anyhow!("Error installing default crypto provider: {:?}", e));
     }
 
-    Game::new()?.run()?;
+    let mut args = std::env::args().skip(1);
+    let server_target = match (args.next().as_deref(), args.next()) {
+        (Some("--connect"), Some(addr)) => ServerTarget::Remote(addr.parse::<SocketAddr>()?),
+        (None, _) => ServerTarget::SinglePlayer,
+        _ => return Err(anyhow!("Usage: client [--connect <address:port>]")),
+    };
+
+    Game::new(server_target, data_dir()?)?.run()?;
     Ok(())
 }
+
+/// Single player worlds and known servers live next to the executable, wherever the client is
+/// started from.
+fn data_dir() -> anyhow::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let dir = exe
+        .parent()
+        .ok_or_else(|| anyhow!("Executable {exe:?} has no parent directory"))?;
+    Ok(dir.join("data"))
+}
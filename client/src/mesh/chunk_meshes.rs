@@ -28,6 +28,9 @@ impl ChunkMeshes {
             ChunkMesh::new(device, chunk, self.texture.clone(), 0.1),
         );
     }
+    pub fn remove(&mut self, chunk_pos: ChunkPos) {
+        self.meshes.remove(&(-chunk_pos.x, -chunk_pos.y));
+    }
 }
 
 impl Renderable for ChunkMeshes {
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use glam::{Mat4, Vec3};
+use shared::PlayerPos;
+
+use crate::{
+    graphics::{Graphics, Renderable, Texture},
+    mesh::{InstanceData, InstanceMesh, TileMesh},
+};
+
+/// Tint of our own entity.
+const PLAYER_COLOR: [u8; 4] = [120, 160, 255, 255];
+/// Tint of everyone else.
+const REMOTE_COLOR: [u8; 4] = [255, 120, 120, 255];
+
+/// Draws every entity as a tinted tile standing on the ground below it.
+pub struct EntityMeshes {
+    mesh: Option<InstanceMesh>,
+    texture: Arc<Texture>,
+    scale: f32,
+}
+
+impl EntityMeshes {
+    pub fn new(graphics: &Graphics) -> anyhow::Result<Self> {
+        let texture: Arc<Texture> =
+            Arc::new(Texture::from_file(graphics, "src/assets/grass_block.png")?);
+        Ok(Self {
+            mesh: None,
+            texture,
+            scale: 0.1,
+        })
+    }
+
+    /// Replaces the drawn entities, reusing the instance buffer while the count stays the same.
+    pub fn update(&mut self, graphics: &Graphics, player: Option<PlayerPos>, remote: &[PlayerPos]) {
+        let transform_scale = Mat4::from_scale(Vec3::new(self.scale, self.scale, 0.0));
+        let instance = |pos: &PlayerPos, color| {
+            // One tile up so it stands on the ground rather than in it
+            let z = pos.z + 1.0;
+            let model = Mat4::from_translation(Vec3 {
+                x: (pos.x - pos.y) * self.scale,
+                y: (pos.x + pos.y) * 0.5 * self.scale + (z * self.scale),
+                z: 0.0,
+            }) * transform_scale;
+            InstanceData::new(model, color)
+        };
+        let instances: Vec<InstanceData> = remote
+            .iter()
+            .map(|pos| instance(pos, REMOTE_COLOR))
+            .chain(player.iter().map(|pos| instance(pos, PLAYER_COLOR)))
+            .collect();
+
+        match &self.mesh {
+            _ if instances.is_empty() => self.mesh = None,
+            Some(mesh) if mesh.instance_count as usize == instances.len() => {
+                graphics.queue.write_buffer(
+                    &mesh.instance_buffer,
+                    0,
+                    bytemuck::cast_slice(&instances),
+                );
+            }
+            _ => {
+                let mesh_data = TileMesh::to_mesh_data();
+                self.mesh = Some(InstanceMesh::new(
+                    &graphics.device,
+                    &mesh_data.vertices,
+                    mesh_data.indices,
+                    &instances,
+                ));
+            }
+        }
+    }
+}
+
+impl Renderable for EntityMeshes {
+    fn render(&self, render_pass: &mut wgpu::RenderPass) {
+        if let Some(mesh) = &self.mesh {
+            render_pass.set_bind_group(0, &self.texture.bind_group, &[]);
+            mesh.render(render_pass);
+        }
+    }
+}
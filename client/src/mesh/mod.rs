@@ -15,3 +15,6 @@ pub use chunk_mesh::*;
 
 mod chunk_meshes;
 pub use chunk_meshes::*;
+
+mod entity_meshes;
+pub use entity_meshes::*;
@@ -1,5 +1,6 @@
 use std::{
     net::SocketAddr,
+    path::PathBuf,
     str::FromStr,
     sync::{Arc, Mutex},
 };
@@ -27,13 +28,14 @@ impl ServerState {
         thread_manager: Arc<ThreadManager>,
         ready: watch::Sender<bool>,
         option: GameStartOption,
+        data_dir: PathBuf,
     ) {
         let child = thread_manager.child().await;
         thread_manager
             .spawn({
                 let thread_manager = thread_manager.clone();
                 move || async move {
-                    if let Err(e) = start_single_player(option, ready, child).await {
+                    if let Err(e) = start_single_player(option, data_dir, ready, child).await {
                         eprintln!("Error with the server: {e}");
                         thread_manager.abort_async().await;
                     }
@@ -89,7 +91,7 @@ impl ServerState {
                             return;
                         }
                     };
-                    let (send, recv) = &mut match connection.open_bi().await {
+                    let (send, recv) = &mut match shared::open_stream(&connection, shared::StreamKind::Control).await {
                         Ok((send, recv)) => (send, recv),
                         Err(e) => {
                             eprintln!("Failed openeing bi-directional stream to server: {e}");
@@ -99,7 +101,7 @@ impl ServerState {
                     };
 
                     if let Err(e) =
-                        send_message(send, ClientControlStreamMessage::ConnectionRequest).await
+                        send_message(send, ClientControlStreamMessage::ConnectionRequest(shared::ClientHello::new(vec![]))).await
                     {
                         eprintln!("Failed to send initial connection request to server: {e}");
                         thread_manager.shutdown().await;
@@ -144,10 +146,10 @@ impl ServerState {
         self.thread_manager.is_cancelled()
     }
 
-    pub async fn new(option: GameStartOption) -> Arc<Self> {
+    pub async fn new(option: GameStartOption, data_dir: PathBuf) -> Arc<Self> {
         let thread_manager = ThreadManager::new();
         let (server_ready_tx, mut server_ready_rx) = watch::channel(false);
-        Self::start_server(thread_manager.clone(), server_ready_tx, option).await;
+        Self::start_server(thread_manager.clone(), server_ready_tx, option, data_dir).await;
 
         let (server_tx, server_rx) = unbounded_channel::<ServerControlStreamMessage>();
         let (client_tx, client_rx) = unbounded_channel::<ClientControlStreamMessage>();
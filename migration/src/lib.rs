@@ -1,12 +1,18 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20261018_120000_create_bans_table;
+mod m20261018_130000_add_character_position;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20261018_120000_create_bans_table::Migration),
+            Box::new(m20261018_130000_add_character_position::Migration),
+        ]
     }
 }
@@ -0,0 +1,51 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// SQLite can only add one column per statement.
+const POSITION_COLUMNS: [Characters; 3] = [
+    Characters::PositionX,
+    Characters::PositionY,
+    Characters::PositionZ,
+];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in POSITION_COLUMNS {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Characters::Table)
+                        .add_column(ColumnDef::new(column).float().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in POSITION_COLUMNS {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Characters::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Iden, Clone, Copy)]
+enum Characters {
+    Table,
+    PositionX,
+    PositionY,
+    PositionZ,
+}
@@ -0,0 +1,5 @@
+mod link;
+pub use link::*;
+
+mod relay;
+pub use relay::*;
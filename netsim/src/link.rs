@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+/// How long datagrams may queue behind a bandwidth cap before new ones are dropped, like a
+/// router's buffer filling up.
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(250);
+
+/// What one direction of a simulated link does to the datagrams crossing it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkConditions {
+    /// One-way delay added to every datagram
+    pub latency: Duration,
+    /// Each datagram's delay varies by up to this much either way, without reordering
+    pub jitter: Duration,
+    /// Chance from 0 to 1 that a datagram is dropped
+    pub loss: f64,
+    /// Chance from 0 to 1 that a datagram is held back long enough for later ones to overtake it
+    pub reorder: f64,
+    /// How much longer a reordered datagram is held
+    pub reorder_delay: Duration,
+    /// Bytes per second the link can carry, unlimited when `None`
+    pub bandwidth: Option<u64>,
+}
+
+/// One direction of a simulated link, deciding when each datagram arrives, if at all.
+pub struct Link {
+    conditions: LinkConditions,
+    rng: StdRng,
+    /// Arrival of the last in-order datagram, later ones never arrive before it
+    last_arrival: Option<Instant>,
+    /// When the bandwidth cap has finished sending everything queued so far
+    busy_until: Option<Instant>,
+}
+
+impl Link {
+    /// The same `seed` and traffic always give the same drops and delays.
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            last_arrival: None,
+            busy_until: None,
+        }
+    }
+
+    /// When a datagram of `len` bytes sent at `now` arrives at the far end, `None` if it is lost.
+    pub fn schedule(&mut self, now: Instant, len: usize) -> Option<Instant> {
+        let conditions = self.conditions;
+        if conditions.loss > 0.0 && self.rng.random_bool(conditions.loss.min(1.0)) {
+            return None;
+        }
+
+        let mut sent = now;
+        if let Some(bandwidth) = conditions.bandwidth {
+            let start = self.busy_until.map_or(now, |busy| busy.max(now));
+            if start - now > MAX_QUEUE_DELAY {
+                return None;
+            }
+            let transmit = Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64);
+            self.busy_until = Some(start + transmit);
+            sent = start + transmit;
+        }
+
+        let mut arrival = sent + conditions.latency;
+        if !conditions.jitter.is_zero() {
+            let jitter = conditions.jitter.as_secs_f64();
+            let offset = self.rng.random_range(-jitter..=jitter);
+            arrival = if offset >= 0.0 {
+                arrival + Duration::from_secs_f64(offset)
+            } else {
+                arrival
+                    .checked_sub(Duration::from_secs_f64(-offset))
+                    .unwrap_or(sent)
+                    .max(sent)
+            };
+        }
+
+        if conditions.reorder > 0.0 && self.rng.random_bool(conditions.reorder.min(1.0)) {
+            // Held back outside the ordering, so anything sent after it may arrive first
+            return Some(arrival + conditions.reorder_delay);
+        }
+        if let Some(last) = self.last_arrival {
+            arrival = arrival.max(last);
+        }
+        self.last_arrival = Some(arrival);
+        Some(arrival)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_many(
+        link: &mut Link,
+        start: Instant,
+        count: usize,
+        spacing: Duration,
+    ) -> Vec<Option<Instant>> {
+        (0..count)
+            .map(|i| link.schedule(start + spacing * i as u32, 1200))
+            .collect()
+    }
+
+    #[test]
+    fn drops_about_the_configured_share() {
+        let conditions = LinkConditions {
+            loss: 0.1,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let arrivals = send_many(&mut Link::new(conditions, 1), start, 10_000, Duration::ZERO);
+        let lost = arrivals.iter().filter(|arrival| arrival.is_none()).count();
+        assert!((800..1200).contains(&lost), "{lost}");
+
+        // Same seed, same drops
+        let again = send_many(&mut Link::new(conditions, 1), start, 10_000, Duration::ZERO);
+        assert_eq!(arrivals, again);
+    }
+
+    #[test]
+    fn jitter_varies_delay_but_keeps_order() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut link = Link::new(conditions, 2);
+        let arrivals: Vec<Instant> = (0..1000)
+            .map(|i| {
+                let sent = start + Duration::from_millis(i);
+                let arrival = link.schedule(sent, 100).unwrap();
+                assert!(arrival - sent >= Duration::from_millis(30));
+                arrival
+            })
+            .collect();
+
+        assert!(arrivals.is_sorted());
+        let delays: Vec<Duration> = arrivals
+            .iter()
+            .enumerate()
+            .map(|(i, arrival)| *arrival - (start + Duration::from_millis(i as u64)))
+            .collect();
+        assert!(
+            *delays.iter().max().unwrap() - *delays.iter().min().unwrap()
+                > Duration::from_millis(20)
+        );
+    }
+
+    #[test]
+    fn reordered_datagrams_are_overtaken() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(10),
+            reorder: 0.2,
+            reorder_delay: Duration::from_millis(30),
+            ..Default::default()
+        };
+        let link = &mut Link::new(conditions, 3);
+        let arrivals: Vec<Instant> =
+            send_many(link, Instant::now(), 1000, Duration::from_millis(5))
+                .into_iter()
+                .map(Option::unwrap)
+                .collect();
+
+        let overtaken = arrivals.windows(2).filter(|pair| pair[1] < pair[0]).count();
+        assert!((100..300).contains(&overtaken), "{overtaken}");
+    }
+
+    #[test]
+    fn bandwidth_spaces_out_and_then_drops() {
+        // 12 kB/s sends one 1200 byte datagram every 100 ms
+        let conditions = LinkConditions {
+            bandwidth: Some(12_000),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let arrivals = send_many(&mut Link::new(conditions, 4), start, 10, Duration::ZERO);
+
+        assert_eq!(arrivals[0], Some(start + Duration::from_millis(100)));
+        assert_eq!(arrivals[1], Some(start + Duration::from_millis(200)));
+        // The queue holds 250 ms, so the fourth datagram onward is dropped
+        assert!(arrivals[3..].iter().all(Option::is_none));
+    }
+}
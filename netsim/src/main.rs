@@ -0,0 +1,88 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use clap::Parser;
+use netsim::{LinkConditions, Relay, RelayConditions};
+
+/// Relays a game server's UDP traffic through a simulated bad network, so latency, loss and
+/// reordering can be reproduced on one machine. Point the client at `--listen` with
+/// `client --connect <listen>`.
+#[derive(Parser, Debug)]
+#[command(version, about = "UDP relay that simulates network conditions")]
+struct Args {
+    /// Address clients connect to
+    #[arg(long, default_value = "127.0.0.1:5260")]
+    listen: SocketAddr,
+
+    /// Address of the game server
+    #[arg(long, default_value = "127.0.0.1:5250")]
+    server: SocketAddr,
+
+    /// Address the relay's sockets towards the server are bound to, so the server sees clients
+    /// coming from it. Defaults to the unspecified address, the OS then picks by route
+    #[arg(long)]
+    upstream_bind: Option<IpAddr>,
+
+    /// One-way delay in milliseconds, applied in each direction
+    #[arg(long, default_value_t = 0)]
+    latency: u64,
+
+    /// Random variation of the delay in milliseconds, either way
+    #[arg(long, default_value_t = 0)]
+    jitter: u64,
+
+    /// Share of datagrams dropped in each direction, from 0 to 1
+    #[arg(long, default_value_t = 0.0)]
+    loss: f64,
+
+    /// Share of datagrams held back so later ones overtake them, from 0 to 1
+    #[arg(long, default_value_t = 0.0)]
+    reorder: f64,
+
+    /// How long a reordered datagram is held back, in milliseconds
+    #[arg(long, default_value_t = 20)]
+    reorder_delay: u64,
+
+    /// Bandwidth cap in kilobytes per second for each direction
+    #[arg(long)]
+    bandwidth: Option<u64>,
+
+    /// Different seeds give different drops and delays for the same traffic
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let link = LinkConditions {
+        latency: Duration::from_millis(args.latency),
+        jitter: Duration::from_millis(args.jitter),
+        loss: args.loss.clamp(0.0, 1.0),
+        reorder: args.reorder.clamp(0.0, 1.0),
+        reorder_delay: Duration::from_millis(args.reorder_delay),
+        bandwidth: args.bandwidth.map(|kilobytes| kilobytes * 1000),
+    };
+    let relay = Relay::start(
+        args.listen,
+        args.server,
+        args.upstream_bind,
+        RelayConditions {
+            upstream: link,
+            downstream: link,
+            seed: args.seed,
+        },
+    )
+    .await?;
+    println!(
+        "Relaying {} -> {} with {link:?}",
+        relay.local_addr(),
+        args.server
+    );
+
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::{Link, LinkConditions};
+
+/// Comfortably above the largest datagram QUIC sends.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+/// A client that has sent nothing for this long is forgotten, QUIC would have timed it out too.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Conditions for both directions of a relayed connection.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RelayConditions {
+    /// Client to server
+    pub upstream: LinkConditions,
+    /// Server to client
+    pub downstream: LinkConditions,
+    /// Seeds every link, each client gets its own derived from this
+    pub seed: u64,
+}
+
+/// Forwards UDP between clients and a server, putting every datagram through a simulated link.
+/// Each client gets its own socket towards the server, so the server sees one peer per client
+/// just as it would without the relay.
+pub struct Relay {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Relay {
+    /// Listens on `listen` and relays to `server` until dropped. The sockets towards the server
+    /// are bound to `upstream_bind`, or to the unspecified address when `None`, which decides
+    /// the address the server sees its clients coming from.
+    pub async fn start(
+        listen: SocketAddr,
+        server: SocketAddr,
+        upstream_bind: Option<IpAddr>,
+        conditions: RelayConditions,
+    ) -> anyhow::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(listen).await?);
+        let local_addr = socket.local_addr()?;
+        let task = tokio::spawn(async move {
+            if let Err(e) = relay(socket, server, upstream_bind, conditions).await {
+                eprintln!("Relay stopped: {e}");
+            }
+        });
+
+        Ok(Self { local_addr, task })
+    }
+
+    /// Where clients should connect, useful when listening on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Client {
+    upstream_socket: Arc<UdpSocket>,
+    upstream: Link,
+    last_seen: Instant,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn relay(
+    socket: Arc<UdpSocket>,
+    server: SocketAddr,
+    upstream_bind: Option<IpAddr>,
+    conditions: RelayConditions,
+) -> anyhow::Result<()> {
+    let upstream_bind = SocketAddr::new(
+        upstream_bind.unwrap_or(if server.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        }),
+        0,
+    );
+    let mut clients: HashMap<SocketAddr, Client> = HashMap::new();
+    let mut next_seed = conditions.seed;
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        let now = Instant::now();
+        clients.retain(|_, client| now - client.last_seen < IDLE_TIMEOUT);
+
+        let client = match clients.entry(addr) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let seed = next_seed;
+                next_seed = next_seed.wrapping_add(2);
+                // One client failing to get a socket mustn't take the others down with it
+                let upstream_socket = match connect_upstream(upstream_bind, server).await {
+                    Ok(upstream_socket) => Arc::new(upstream_socket),
+                    Err(e) => {
+                        eprintln!("Couldn't relay {addr} to {server}: {e}");
+                        continue;
+                    }
+                };
+                let task = tokio::spawn(relay_downstream(
+                    upstream_socket.clone(),
+                    socket.clone(),
+                    addr,
+                    Link::new(conditions.downstream, seed.wrapping_add(1)),
+                ));
+                entry.insert(Client {
+                    upstream_socket,
+                    upstream: Link::new(conditions.upstream, seed),
+                    last_seen: now,
+                    task,
+                })
+            }
+        };
+        client.last_seen = now;
+
+        if let Some(arrival) = client.upstream.schedule(now, len) {
+            let upstream_socket = client.upstream_socket.clone();
+            let datagram = buf[..len].to_vec();
+            deliver(arrival, async move {
+                let _ = upstream_socket.send(&datagram).await;
+            });
+        }
+    }
+}
+
+async fn connect_upstream(bind: SocketAddr, server: SocketAddr) -> std::io::Result<UdpSocket> {
+    let upstream_socket = UdpSocket::bind(bind).await?;
+    upstream_socket.connect(server).await?;
+    Ok(upstream_socket)
+}
+
+/// Carries everything the server sends on one client's socket back to that client.
+async fn relay_downstream(
+    upstream_socket: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    mut link: Link,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let len = match upstream_socket.recv(&mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                eprintln!("Error receiving from server for {client}: {e}");
+                return;
+            }
+        };
+        if let Some(arrival) = link.schedule(Instant::now(), len) {
+            let socket = socket.clone();
+            let datagram = buf[..len].to_vec();
+            deliver(arrival, async move {
+                let _ = socket.send_to(&datagram, client).await;
+            });
+        }
+    }
+}
+
+/// Runs `send` at `arrival`. Each datagram waits on its own task so a held back one never
+/// delays those behind it.
+fn deliver(arrival: Instant, send: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(async move {
+        tokio::time::sleep_until(arrival.into()).await;
+        send.await;
+    });
+}
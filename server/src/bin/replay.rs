@@ -0,0 +1,70 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use server_lib::{recording::Recording, replay::Replay};
+
+/// Rebuilds the world from a session recorded with `server --record`, tick by tick in a
+/// throwaway world directory, then reports what each client got back and where its player
+/// ended up. Only sessions that started on a new world can be replayed, a loaded world's saved
+/// accounts and chunks are not part of the recording.
+#[derive(Parser, Debug)]
+#[command(version, about = "Replays a recorded server session")]
+struct Args {
+    /// Recording file, from the world's recordings folder
+    recording: PathBuf,
+
+    /// Print every message the server sent each client
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let recording = Recording::load(&args.recording)?;
+    println!(
+        "Replaying {} events recorded by build {}",
+        recording.frames.len(),
+        recording.header.build_id
+    );
+
+    let data_dir = std::env::temp_dir().join(format!("replay-{}", std::process::id()));
+    let result = replay(recording, &data_dir, args.verbose).await;
+    let _ = fs::remove_dir_all(&data_dir);
+    result
+}
+
+async fn replay(recording: Recording, data_dir: &Path, verbose: bool) -> anyhow::Result<()> {
+    let mut replay = Replay::start(recording, data_dir).await?;
+    replay.run().await?;
+    println!("Replayed {} ticks", replay.tick());
+
+    for (id, client) in replay.clients() {
+        println!(
+            "Client {id} ({}): {} control and {} chat messages received",
+            if client.local { "local" } else { "remote" },
+            client.received.len(),
+            client.chat_received.len()
+        );
+        if let Some(player) = client.player {
+            let position = player.position;
+            println!(
+                "  entity {} last at ({:.2}, {:.2}, {:.0}) after input {:?}",
+                player.entity_id, position.x, position.y, position.z, player.last_input_id
+            );
+        }
+        if verbose {
+            for message in &client.received {
+                println!("  {message:?}");
+            }
+            for message in &client.chat_received {
+                println!("  {message:?}");
+            }
+        }
+    }
+    Ok(())
+}
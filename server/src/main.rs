@@ -1,10 +1,116 @@
-use std::sync::{Arc, atomic::AtomicBool};
+use std::{net::IpAddr, path::PathBuf};
 
-use server_lib::{GameStartOption, start_single_player};
+use anyhow::anyhow;
+use clap::Parser;
+use server_lib::{
+    GameStartOption, admin::run_admin_console, config::ConfigOverrides, start_server,
+    thread_manager::ThreadManager,
+};
+use tokio::sync::watch;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Headless dedicated game server")]
+struct Args {
+    /// Name of the world directory inside the data directory
+    #[arg(short, long)]
+    world: String,
+
+    /// Create a new world instead of loading an existing one
+    #[arg(long)]
+    new: bool,
+
+    /// Address to listen on, overriding the world's server.toml
+    #[arg(long)]
+    bind: Option<IpAddr>,
+
+    /// UDP port to listen on, overriding the world's server.toml
+    #[arg(short, long, env = "SERVER_PORT")]
+    port: Option<u16>,
+
+    /// Directory that holds all world directories
+    #[arg(long, default_value = "data")]
+    data_dir: PathBuf,
+
+    /// Maximum number of players allowed in the world at once, overriding the world's server.toml
+    #[arg(long, env = "MAX_PLAYERS")]
+    max_players: Option<usize>,
+
+    /// Password players must supply to join, overriding the world's server.toml
+    #[arg(long, env = "SERVER_PASSWORD")]
+    password: Option<String>,
+
+    /// Terrain seed of a new world, saved to its server.toml
+    #[arg(long)]
+    seed: Option<u32>,
+
+    /// Record every session to the world's recordings folder, for the replay tool. Only a new
+    /// world's first session can be replayed
+    #[arg(long)]
+    record: bool,
+}
+
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // let running = Arc::new(AtomicBool::new(true));
-    // start_single_player(running, GameStartOption::NewGame("seed".into())).await?;
-    Ok(())
-}
\ No newline at end of file
+    if let Err(e) = rustls::crypto::aws_lc_rs::default_provider().install_default() {
+        return Err(anyhow!("Error installing default crypto provider: {:?}", e));
+    }
+
+    let args = Args::parse();
+
+    let option = if args.new {
+        GameStartOption::NewGame(args.world)
+    } else {
+        GameStartOption::LoadGame(args.world)
+    };
+
+    let overrides = ConfigOverrides {
+        bind_address: args.bind,
+        port: args.port,
+        max_players: args.max_players,
+        server_password: args.password,
+        seed: args.seed,
+        record: args.record.then_some(true),
+        single_player: false,
+    };
+
+    let thread_manager = ThreadManager::new();
+    let (ready_tx, _ready_rx) = watch::channel(false);
+
+    let server = start_server(
+        option,
+        args.data_dir,
+        overrides,
+        ready_tx,
+        thread_manager.child().await,
+    )
+    .await?;
+
+    println!("Type 'help' for a list of admin commands");
+    let result = tokio::select! {
+        result = shutdown_signal() => result,
+        result = run_admin_console(server.game_manager.clone()) => result,
+    };
+    println!("Shutting down server");
+    server.shutdown().await;
+    thread_manager.shutdown().await;
+
+    result
+}
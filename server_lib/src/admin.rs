@@ -0,0 +1,145 @@
+use std::sync::Arc;
+
+use shared::{PlayerPos, ServerControlStreamMessage};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::state::GameManager;
+
+const HELP: &str = "Commands:
+  list              Show connected sessions
+  kick <user>       Disconnect a user
+  ban <user>        Ban a user and disconnect them
+  save              Save the world
+  say <message>     Broadcast a message to every player
+  stats             Show server tick timings
+  shutdown          Stop the server";
+
+pub enum AdminCommand {
+    Help,
+    List,
+    Kick(String),
+    Ban(String),
+    Save,
+    Say(String),
+    Stats,
+    Shutdown,
+}
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        let require_argument = |name: &str| {
+            if argument.is_empty() {
+                Err(format!("Usage: {command} <{name}>"))
+            } else {
+                Ok(argument.to_string())
+            }
+        };
+
+        match command {
+            "help" => Ok(Self::Help),
+            "list" => Ok(Self::List),
+            "kick" => Ok(Self::Kick(require_argument("user")?)),
+            "ban" => Ok(Self::Ban(require_argument("user")?)),
+            "save" => Ok(Self::Save),
+            "say" => Ok(Self::Say(require_argument("message")?)),
+            "stats" => Ok(Self::Stats),
+            "shutdown" | "stop" => Ok(Self::Shutdown),
+            _ => Err(format!(
+                "Unknown command '{command}', type 'help' for a list"
+            )),
+        }
+    }
+}
+
+pub async fn list_sessions(game_manager: &GameManager) -> String {
+    let sessions = game_manager.session_manager.all();
+    if sessions.is_empty() {
+        return "No sessions connected".into();
+    }
+
+    let mut lines: Vec<String> = vec![format!("{} session(s):", sessions.len())];
+    for session in sessions {
+        let session = session.lock().await;
+        let auth = match session.username() {
+            Some(username) => format!("{:?} as {username}", session.phase()),
+            None => "waiting for credentials".to_string(),
+        };
+        let mut character = match session.character_id() {
+            Some(id) => format!("character {id}"),
+            None => "no character".into(),
+        };
+        if let Ok(players) = game_manager.players.lock()
+            && let Some(player) = players.get(&session.addr)
+        {
+            let PlayerPos { x, y, z } = player.position;
+            character.push_str(&format!(" at ({x:.1}, {y:.1}, {z})"));
+        }
+        lines.push(format!(
+            "  {} [{:?}] {auth}, {character}, {}",
+            session.addr, session.role, session.clock
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Runs the console until `shutdown` is entered. If stdin is closed the console goes quiet but never returns.
+pub async fn run_admin_console(game_manager: Arc<GameManager>) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let command = match AdminCommand::parse(&line) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("{e}");
+                continue;
+            }
+        };
+
+        match command {
+            AdminCommand::Help => println!("{HELP}"),
+            AdminCommand::List => println!("{}", list_sessions(&game_manager).await),
+            AdminCommand::Kick(username) => {
+                match game_manager
+                    .kick(&username, "Kicked by an administrator")
+                    .await
+                {
+                    0 => println!("No session is logged in as {username}"),
+                    count => println!("Kicked {username} ({count} session(s))"),
+                }
+            }
+            AdminCommand::Ban(username) => match game_manager.ban(&username).await {
+                Ok(count) => println!("Banned {username}, disconnected {count} session(s)"),
+                Err(e) => eprintln!("Could not ban {username}: {e}"),
+            },
+            AdminCommand::Save => match game_manager.save_world().await {
+                Ok(count) => println!("World saved ({count} chunk(s) written)"),
+                Err(e) => eprintln!("Could not save world: {e}"),
+            },
+            AdminCommand::Say(message) => {
+                game_manager
+                    .session_manager
+                    .broadcast(ServerControlStreamMessage::Announcement(message))
+                    .await;
+            }
+            AdminCommand::Stats => match game_manager.tick_stats.lock() {
+                Ok(stats) => println!("{stats}"),
+                Err(e) => eprintln!("Could not read tick stats: {e}"),
+            },
+            AdminCommand::Shutdown => return Ok(()),
+        }
+    }
+
+    std::future::pending::<()>().await;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use quinn::{RecvStream, SendStream};
+use shared::{
+    ClientChatMessage, CodecError, MAX_CHAT_LENGTH, ServerChatMessage, receive_message,
+    send_message,
+};
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::{
+    state::{GameManager, ServerSession},
+    thread_manager::ThreadManager,
+};
+
+/// Relays one client's chat stream: what they say goes to every logged in player with a chat
+/// stream open, including themselves.
+pub async fn handle_chat_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    session: Arc<tokio::sync::Mutex<ServerSession>>,
+    game_manager: Arc<GameManager>,
+    thread_manager: Arc<ThreadManager>,
+) -> anyhow::Result<()> {
+    let (chat_tx, mut chat_rx) = unbounded_channel::<ServerChatMessage>();
+    session.lock().await.chat = Some(chat_tx.clone());
+
+    // Written from its own task so a busy room never holds up reading what this client says
+    thread_manager
+        .spawn(move || async move {
+            while let Some(message) = chat_rx.recv().await {
+                if let Err(e) = send_message(&mut send, message).await {
+                    eprintln!("Error sending chat to client: {e}");
+                    break;
+                }
+            }
+            let _ = send.finish();
+        })
+        .await;
+
+    let addr = session.lock().await.addr;
+    loop {
+        let message = match receive_message(&mut recv).await {
+            Ok(message) => message,
+            Err(CodecError::Closed) => break,
+            Err(e) => {
+                eprintln!("Error receiving chat from client: {e}");
+                break;
+            }
+        };
+        if let Some(recorder) = &game_manager.recorder {
+            recorder.chat(addr, &message);
+        }
+
+        if let Err(rejection) = say(message, &session, &game_manager).await {
+            let _ = chat_tx.send(ServerChatMessage::Rejected(rejection));
+        }
+    }
+
+    let mut session = session.lock().await;
+    if session
+        .chat
+        .as_ref()
+        .is_some_and(|chat| chat.same_channel(&chat_tx))
+    {
+        session.chat = None;
+    }
+    Ok(())
+}
+
+/// Passes what the session said on to everyone, or returns why it was refused.
+pub(crate) async fn say(
+    message: ClientChatMessage,
+    session: &Arc<tokio::sync::Mutex<ServerSession>>,
+    game_manager: &GameManager,
+) -> Result<(), String> {
+    let ClientChatMessage::Say(text) = message;
+    let from = session.lock().await.username().map(str::to_string);
+    match from {
+        None => Err("Not logged in".to_string()),
+        Some(_) if text.chars().count() > MAX_CHAT_LENGTH => Err(format!(
+            "Messages are limited to {MAX_CHAT_LENGTH} characters"
+        )),
+        Some(from) => {
+            game_manager
+                .session_manager
+                .broadcast_chat(ServerChatMessage::Message { from, text })
+                .await;
+            Ok(())
+        }
+    }
+}
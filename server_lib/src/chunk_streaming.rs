@@ -0,0 +1,81 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use quinn::SendStream;
+use shared::{ChunkPos, ChunkStreamMessage, ChunkView, FrameCodec};
+use tokio::{sync::watch, time::Instant};
+
+use crate::state::GameManager;
+
+/// Views are followed at most this often, so a client can't keep the terrain busy generating.
+const MIN_VIEW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Feeds one client's chunk stream, following the view it reports over the control stream.
+pub struct ChunkStreamer {
+    game_manager: Arc<GameManager>,
+    send: SendStream,
+    codec: FrameCodec,
+    view: watch::Receiver<ChunkView>,
+    sent: HashSet<ChunkPos>,
+}
+
+impl ChunkStreamer {
+    pub fn new(
+        game_manager: Arc<GameManager>,
+        send: SendStream,
+        codec: FrameCodec,
+        view: watch::Receiver<ChunkView>,
+    ) -> Self {
+        Self {
+            game_manager,
+            send,
+            codec,
+            view,
+            sent: HashSet::new(),
+        }
+    }
+
+    /// Sends nothing until the client first reports its view, then runs until the session drops
+    /// its end of the view channel or the stream fails.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let mut next_view = Instant::now();
+        while self.view.changed().await.is_ok() {
+            // Changes made in the meantime are folded into the newest one
+            tokio::time::sleep_until(next_view).await;
+            next_view = Instant::now() + MIN_VIEW_INTERVAL;
+            let view = *self.view.borrow_and_update();
+
+            // Unload first so the client frees memory before new chunks arrive
+            let stale: Vec<ChunkPos> = self
+                .sent
+                .iter()
+                .filter(|pos| !view.contains(**pos))
+                .copied()
+                .collect();
+            for pos in stale {
+                self.codec
+                    .send(&mut self.send, ChunkStreamMessage::Unload(pos))
+                    .await?;
+                self.sent.remove(&pos);
+            }
+
+            for pos in view.positions() {
+                // Start over from the new center rather than finishing a view the client has left
+                if self.view.has_changed().unwrap_or(true) {
+                    break;
+                }
+                if self.sent.contains(&pos) {
+                    continue;
+                }
+
+                let chunk = self.game_manager.chunk(pos).await?;
+                self.codec
+                    .send(&mut self.send, ChunkStreamMessage::Load(chunk))
+                    .await?;
+                self.sent.insert(pos);
+            }
+        }
+
+        let _ = self.send.finish();
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use shared::{DEFAULT_COMPRESSION_THRESHOLD, FrameLimits};
+
+pub const CONFIG_FILE_NAME: &str = "server.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub frame_limits: FrameLimits,
+    /// Frames at least this large are compressed when the client supports it
+    pub compression_threshold: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 5250,
+            frame_limits: FrameLimits::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldConfig {
+    pub seed: u32,
+    pub chunk_size: usize,
+    pub spawn_radius: i64,
+    pub view_radius: usize,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            chunk_size: 4,
+            spawn_radius: 2,
+            view_radius: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutosaveConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl AutosaveConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    /// Refuse the new login while the account is in use
+    Reject,
+    /// Disconnect the older session in favour of the new one
+    KickExisting,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayerConfig {
+    pub max_players: usize,
+    pub server_password: Option<String>,
+    pub resume_grace_secs: u64,
+    pub duplicate_login: DuplicateLoginPolicy,
+}
+
+impl PlayerConfig {
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_secs)
+    }
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        Self {
+            max_players: 5,
+            server_password: None,
+            resume_grace_secs: 120,
+            duplicate_login: DuplicateLoginPolicy::KickExisting,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityConfig {
+    pub bcrypt_cost: u32,
+    pub hashing_threads: usize,
+    pub max_failed_logins: u32,
+    pub failed_login_window_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            hashing_threads: 2,
+            max_failed_logins: 5,
+            failed_login_window_secs: 300,
+            lockout_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// Records every session to the world's recordings folder for later replay
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub network: NetworkConfig,
+    pub world: WorldConfig,
+    pub autosave: AutosaveConfig,
+    pub players: PlayerConfig,
+    pub security: SecurityConfig,
+    pub recording: RecordingConfig,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read server config '{:?}': {e}", path))?;
+        toml::from_str(&contents)
+            .map_err(|e| anyhow!("Could not parse server config '{:?}': {e}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Loads the config from `game_dir`, writing the defaults there first if it has none yet.
+    pub fn load_or_create(game_dir: &Path) -> anyhow::Result<Self> {
+        let path = game_dir.join(CONFIG_FILE_NAME);
+        if path.exists() {
+            Self::load(&path)
+        } else {
+            let config = Self::default();
+            config.save(&path)?;
+            Ok(config)
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.network.bind_address, self.network.port)
+    }
+}
+
+/// Values that take precedence over the world's config file for a single run. They are never
+/// saved, except for the seed of a new world.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub bind_address: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub max_players: Option<usize>,
+    pub server_password: Option<String>,
+    pub seed: Option<u32>,
+    pub record: Option<bool>,
+    /// Makes clients on this machine the server's master, exempt from the server password and
+    /// player cap. Only for the server behind a single player game.
+    pub single_player: bool,
+}
+
+impl ConfigOverrides {
+    pub fn single_player() -> Self {
+        Self {
+            bind_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            single_player: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn apply(&self, config: &mut ServerConfig) {
+        if let Some(bind_address) = self.bind_address {
+            config.network.bind_address = bind_address;
+        }
+        if let Some(port) = self.port {
+            config.network.port = port;
+        }
+        if let Some(max_players) = self.max_players {
+            config.players.max_players = max_players;
+        }
+        if let Some(server_password) = &self.server_password {
+            config.players.server_password = Some(server_password.clone());
+        }
+        if let Some(seed) = self.seed {
+            config.world.seed = seed;
+        }
+        if let Some(record) = self.record {
+            config.recording.enabled = record;
+        }
+    }
+}
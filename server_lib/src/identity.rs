@@ -0,0 +1,72 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::anyhow;
+use rcgen::{CertificateParams, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use shared::certificate_fingerprint;
+
+const CERT_FILE_NAME: &str = "identity.crt";
+const KEY_FILE_NAME: &str = "identity.key";
+
+pub struct ServerIdentity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+    pub fingerprint: String,
+}
+
+impl ServerIdentity {
+    pub fn load_or_create(game_dir: &Path) -> anyhow::Result<Self> {
+        let cert_path = game_dir.join(CERT_FILE_NAME);
+        let key_path = game_dir.join(KEY_FILE_NAME);
+
+        let (cert, key) = match (cert_path.exists(), key_path.exists()) {
+            (true, true) => (fs::read(&cert_path)?, fs::read(&key_path)?),
+            (false, false) => {
+                let signing_key = KeyPair::generate()?;
+                let cert = CertificateParams::new(vec!["localhost".to_string()])?
+                    .self_signed(&signing_key)?;
+                let (cert, key) = (cert.der().to_vec(), signing_key.serialize_der());
+
+                write_private(&key_path, &key)?;
+                fs::write(&cert_path, &cert)?;
+
+                (cert, key)
+            }
+            // Replacing half an identity would lock out every client that pinned it
+            _ => {
+                return Err(anyhow!(
+                    "Server identity is incomplete, '{:?}' and '{:?}' must both exist or both be removed",
+                    cert_path,
+                    key_path
+                ));
+            }
+        };
+
+        Ok(Self {
+            fingerprint: certificate_fingerprint(&cert),
+            cert: CertificateDer::from(cert),
+            key: PrivatePkcs8KeyDer::from(key),
+        })
+    }
+
+    pub fn cert_chain(&self) -> Vec<CertificateDer<'static>> {
+        vec![self.cert.clone()]
+    }
+
+    pub fn private_key(&self) -> PrivateKeyDer<'static> {
+        self.key.clone_key().into()
+    }
+}
+
+/// Writes a file only the owner can read, from the moment it is created.
+fn write_private(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
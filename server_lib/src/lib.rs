@@ -1,19 +1,28 @@
 use crate::{
-    server_networking::handle_connection, state::GameManager, thread_manager::ThreadManager,
+    config::ConfigOverrides, server_networking::handle_connection, thread_manager::ThreadManager,
+    tick::run_tick_loop,
 };
-use quinn::{
-    Endpoint,
-    crypto::rustls::QuicServerConfig,
-    rustls::pki_types::{CertificateDer, PrivateKeyDer},
-};
-use rcgen::{CertifiedKey, KeyPair};
-use rustls::pki_types::PrivatePkcs8KeyDer;
-use std::{net::SocketAddr, sync::Arc};
+use quinn::{Endpoint, crypto::rustls::QuicServerConfig};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio::sync::watch;
 
+pub mod admin;
+mod chat;
+mod chunk_streaming;
+pub mod config;
+mod identity;
+mod login_throttle;
+mod password;
+pub mod recording;
+pub mod replay;
+mod server_handle;
 mod server_networking;
 mod state;
 pub mod thread_manager;
+pub mod tick;
+
+pub use server_handle::ServerHandle;
+pub use state::GameManager;
 
 pub enum GameStartOption {
     NewGame(String),
@@ -34,17 +43,21 @@ async fn run_accept_loop(
                 let endpoint = endpoint.clone();
                 let child = child.clone();
                 async move {
-                    if let Some(conn) = endpoint.accept().await {
-                        let gm = game_manager.clone();
-                        let child_2 = child.child().await;
-                        child
-                            .spawn(move || async move {
-                                let addr = conn.remote_address();
-                                if let Err(e) = handle_connection(conn, gm, child_2).await {
-                                    println!("Error handling connection from {addr}: {e}");
-                                }
-                            })
-                            .await;
+                    match endpoint.accept().await {
+                        Some(conn) => {
+                            let gm = game_manager.clone();
+                            let child_2 = child.child().await;
+                            child
+                                .spawn(move || async move {
+                                    let addr = conn.remote_address();
+                                    if let Err(e) = handle_connection(conn, gm, child_2).await {
+                                        println!("Error handling connection from {addr}: {e}");
+                                    }
+                                })
+                                .await;
+                        }
+                        // The endpoint has been closed, so park until the loop is cancelled
+                        None => child.await_cancel().await,
                     }
                 }
             }
@@ -52,32 +65,79 @@ async fn run_accept_loop(
         .await;
 }
 
+async fn run_autosave_loop(thread_manager: Arc<ThreadManager>, game_manager: Arc<GameManager>) {
+    if !game_manager.config.autosave.enabled {
+        return;
+    }
+
+    let interval = game_manager.config.autosave.interval();
+    thread_manager
+        .spawn_loop(move || {
+            let game_manager = game_manager.clone();
+            async move {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = game_manager.save_world().await {
+                    eprintln!("Autosave failed: {e}");
+                }
+            }
+        })
+        .await;
+}
+
 pub async fn start_single_player(
     option: GameStartOption,
+    data_dir: PathBuf,
     ready: watch::Sender<bool>,
     thread_manager: Arc<ThreadManager>,
-) -> anyhow::Result<()> {
-    let game_manager = GameManager::new(option).await?;
+) -> anyhow::Result<ServerHandle> {
+    start_server(
+        option,
+        data_dir,
+        ConfigOverrides::single_player(),
+        ready,
+        thread_manager,
+    )
+    .await
+}
 
-    let addr: SocketAddr = "127.0.0.1:5250".parse()?;
+pub async fn start_server(
+    option: GameStartOption,
+    data_dir: PathBuf,
+    overrides: ConfigOverrides,
+    ready: watch::Sender<bool>,
+    thread_manager: Arc<ThreadManager>,
+) -> anyhow::Result<ServerHandle> {
+    let game_manager = GameManager::new(option, &data_dir, &overrides).await?;
 
-    let cert: CertifiedKey<KeyPair> = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
-    let key: PrivateKeyDer = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()).into();
-    let certs: Vec<CertificateDer> = vec![cert.cert.into()];
+    let addr: SocketAddr = game_manager.config.addr();
 
     let server_crypto: rustls::ServerConfig = rustls::ServerConfig::builder()
         .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+        .with_single_cert(
+            game_manager.identity.cert_chain(),
+            game_manager.identity.private_key(),
+        )?;
 
     let server_config: quinn::ServerConfig =
         quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_crypto)?));
 
     let endpoint: quinn::Endpoint = quinn::Endpoint::server(server_config, addr)?;
     println!("Server listening on {addr}");
+    println!(
+        "Server certificate fingerprint: {}",
+        game_manager.identity.fingerprint
+    );
 
-    let _ = ready.send(true)?;
+    ready.send(true)?;
 
-    run_accept_loop(endpoint.clone(), thread_manager, game_manager).await;
+    run_accept_loop(
+        endpoint.clone(),
+        thread_manager.clone(),
+        game_manager.clone(),
+    )
+    .await;
+    run_autosave_loop(thread_manager.clone(), game_manager.clone()).await;
+    run_tick_loop(thread_manager.clone(), game_manager.clone()).await;
 
-    Ok(())
+    Ok(ServerHandle::new(game_manager, endpoint, thread_manager))
 }
@@ -0,0 +1,284 @@
+use std::{
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::config::SecurityConfig;
+
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct FailedAttempts {
+    count: u32,
+    /// Attempts still being checked, counted as failures until they are resolved
+    in_flight: u32,
+    window_start: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl FailedAttempts {
+    fn new(now: Instant) -> Self {
+        Self {
+            count: 0,
+            in_flight: 0,
+            window_start: now,
+            locked_until: None,
+        }
+    }
+}
+
+/// Tracks failed logins per address and per account, locking either out for a while once it fails too often.
+pub struct LoginThrottle {
+    max_failures: u32,
+    window: Duration,
+    lockout: Duration,
+    by_ip: DashMap<IpAddr, FailedAttempts>,
+    by_account: DashMap<String, FailedAttempts>,
+}
+
+/// A login being checked. It counts against the address and the account until it is resolved
+/// or dropped, so parallel guesses can't all slip in before the first one fails.
+pub struct LoginAttempt<'a> {
+    throttle: &'a LoginThrottle,
+    ip: IpAddr,
+    username: String,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &SecurityConfig) -> Self {
+        Self {
+            max_failures: config.max_failed_logins.max(1),
+            window: Duration::from_secs(config.failed_login_window_secs),
+            lockout: Duration::from_secs(config.lockout_secs),
+            by_ip: DashMap::new(),
+            by_account: DashMap::new(),
+        }
+    }
+
+    /// Starts a login attempt, or returns how long the caller has to wait if the address or the
+    /// account is locked out or already has as many attempts underway as it may still fail.
+    pub fn begin(&self, ip: IpAddr, username: &str) -> Result<LoginAttempt<'_>, Duration> {
+        self.begin_at(ip, username, Instant::now())
+    }
+
+    fn begin_at(
+        &self,
+        ip: IpAddr,
+        username: &str,
+        now: Instant,
+    ) -> Result<LoginAttempt<'_>, Duration> {
+        self.reserve(&self.by_ip, ip, now)?;
+        if let Err(remaining) = self.reserve(&self.by_account, username.to_string(), now) {
+            Self::release(&self.by_ip, &ip);
+            return Err(remaining);
+        }
+
+        Ok(LoginAttempt {
+            throttle: self,
+            ip,
+            username: username.to_string(),
+        })
+    }
+
+    fn reserve<K: Eq + Hash>(
+        &self,
+        map: &DashMap<K, FailedAttempts>,
+        key: K,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        self.prune(map, now);
+
+        let mut attempts = map.entry(key).or_insert(FailedAttempts::new(now));
+        if self.is_expired(&attempts, now) {
+            *attempts = FailedAttempts {
+                in_flight: attempts.in_flight,
+                ..FailedAttempts::new(now)
+            };
+        }
+
+        if let Some(until) = attempts.locked_until {
+            return Err(until - now);
+        }
+        if attempts.count + attempts.in_flight >= self.max_failures {
+            // Not locked out yet, but the attempts underway could all fail
+            return Err(Duration::ZERO);
+        }
+        attempts.in_flight += 1;
+        Ok(())
+    }
+
+    fn release<K, Q>(map: &DashMap<K, FailedAttempts>, key: &Q)
+    where
+        K: Eq + Hash + std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if let Some(mut attempts) = map.get_mut(key) {
+            attempts.in_flight = attempts.in_flight.saturating_sub(1);
+        }
+        map.remove_if(key, |_, attempts| {
+            attempts.in_flight == 0 && attempts.count == 0
+        });
+    }
+
+    fn record_failure<K: Eq + Hash>(&self, map: &DashMap<K, FailedAttempts>, key: K, now: Instant) {
+        let mut attempts = map.entry(key).or_insert(FailedAttempts::new(now));
+        if self.is_expired(&attempts, now) {
+            *attempts = FailedAttempts {
+                in_flight: attempts.in_flight,
+                ..FailedAttempts::new(now)
+            };
+        }
+
+        attempts.count += 1;
+        if attempts.count >= self.max_failures {
+            attempts.locked_until = Some(now + self.lockout);
+        }
+    }
+
+    fn record_success<K, Q>(map: &DashMap<K, FailedAttempts>, key: &Q)
+    where
+        K: Eq + Hash + std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if let Some(mut attempts) = map.get_mut(key) {
+            attempts.count = 0;
+            attempts.locked_until = None;
+        }
+    }
+
+    fn prune<K: Eq + Hash>(&self, map: &DashMap<K, FailedAttempts>, now: Instant) {
+        if map.len() > PRUNE_THRESHOLD {
+            map.retain(|_, attempts| attempts.in_flight > 0 || !self.is_expired(attempts, now));
+        }
+    }
+
+    fn is_expired(&self, attempts: &FailedAttempts, now: Instant) -> bool {
+        let window_over = now.duration_since(attempts.window_start) > self.window;
+        match attempts.locked_until {
+            Some(until) => until <= now,
+            None => window_over,
+        }
+    }
+}
+
+impl LoginAttempt<'_> {
+    pub fn failed(self) {
+        self.failed_at(Instant::now());
+    }
+
+    fn failed_at(self, now: Instant) {
+        let throttle = self.throttle;
+        throttle.record_failure(&throttle.by_ip, self.ip, now);
+        throttle.record_failure(&throttle.by_account, self.username.clone(), now);
+    }
+
+    pub fn succeeded(self) {
+        LoginThrottle::record_success(&self.throttle.by_ip, &self.ip);
+        LoginThrottle::record_success(&self.throttle.by_account, self.username.as_str());
+    }
+}
+
+impl Drop for LoginAttempt<'_> {
+    fn drop(&mut self) {
+        LoginThrottle::release(&self.throttle.by_ip, &self.ip);
+        LoginThrottle::release(&self.throttle.by_account, self.username.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(&SecurityConfig {
+            max_failed_logins: 3,
+            failed_login_window_secs: 60,
+            lockout_secs: 300,
+            ..Default::default()
+        })
+    }
+
+    fn fail(throttle: &LoginThrottle, ip: IpAddr, now: Instant) {
+        throttle
+            .begin_at(ip, "alice", now)
+            .expect("not locked out")
+            .failed_at(now);
+    }
+
+    #[test]
+    fn locks_out_the_address_and_account_until_the_lockout_ends() {
+        let throttle = throttle();
+        let start = Instant::now();
+        for _ in 0..3 {
+            fail(&throttle, IP, start);
+        }
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(
+            throttle.begin_at(IP, "bob", later).err(),
+            Some(Duration::from_secs(290))
+        );
+        assert!(throttle.begin_at(OTHER_IP, "alice", later).is_err());
+        assert!(throttle.begin_at(OTHER_IP, "bob", later).is_ok());
+
+        let over = start + Duration::from_secs(301);
+        assert!(throttle.begin_at(IP, "alice", over).is_ok());
+    }
+
+    #[test]
+    fn forgets_failures_once_the_window_passes() {
+        let throttle = throttle();
+        let start = Instant::now();
+        fail(&throttle, IP, start);
+        fail(&throttle, IP, start);
+
+        let after_window = start + Duration::from_secs(61);
+        fail(&throttle, IP, after_window);
+        fail(&throttle, IP, after_window);
+        assert!(throttle.begin_at(IP, "alice", after_window).is_ok());
+    }
+
+    #[test]
+    fn counts_attempts_underway_against_the_limit() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let attempts: Vec<LoginAttempt> = (0..3)
+            .map(|_| {
+                throttle
+                    .begin_at(IP, "alice", now)
+                    .expect("under the limit")
+            })
+            .collect();
+        assert_eq!(
+            throttle.begin_at(IP, "alice", now).err(),
+            Some(Duration::ZERO)
+        );
+
+        drop(attempts);
+        assert!(throttle.begin_at(IP, "alice", now).is_ok());
+    }
+
+    #[test]
+    fn success_clears_earlier_failures() {
+        let throttle = throttle();
+        let now = Instant::now();
+        fail(&throttle, IP, now);
+        fail(&throttle, IP, now);
+        throttle
+            .begin_at(IP, "alice", now)
+            .expect("not locked out")
+            .succeeded();
+
+        fail(&throttle, IP, now);
+        fail(&throttle, IP, now);
+        assert!(throttle.begin_at(IP, "alice", now).is_ok());
+    }
+}
@@ -1,9 +1,38 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
+use std::sync::Arc;
 
-pub fn hash_password(password: &str) -> anyhow::Result<String> {
-    Ok(hash(password, DEFAULT_COST)?)
+use bcrypt::{hash, verify};
+use tokio::sync::Semaphore;
+
+pub fn hash_password(password: &str, cost: u32) -> anyhow::Result<String> {
+    Ok(hash(password, cost)?)
 }
 
 pub fn verify_password(password: &str, hash: &str) -> anyhow::Result<bool> {
     Ok(verify(password, hash)?)
-}
\ No newline at end of file
+}
+
+/// Runs bcrypt on tokio's blocking pool, with at most `workers` hashes in flight at once.
+pub struct PasswordHasher {
+    cost: u32,
+    permits: Arc<Semaphore>,
+}
+
+impl PasswordHasher {
+    pub fn new(cost: u32, workers: usize) -> Self {
+        Self {
+            cost,
+            permits: Arc::new(Semaphore::new(workers.max(1))),
+        }
+    }
+
+    pub async fn hash(&self, password: String) -> anyhow::Result<String> {
+        let _permit = self.permits.clone().acquire_owned().await?;
+        let cost = self.cost;
+        tokio::task::spawn_blocking(move || hash_password(&password, cost)).await?
+    }
+
+    pub async fn verify(&self, password: String, hash: String) -> anyhow::Result<bool> {
+        let _permit = self.permits.clone().acquire_owned().await?;
+        tokio::task::spawn_blocking(move || verify_password(&password, &hash)).await?
+    }
+}
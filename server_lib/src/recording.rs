@@ -0,0 +1,454 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    hash::{BuildHasher, RandomState},
+    io::{BufReader, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use shared::{
+    AccountCredentials, BUILD_ID, ClientChatMessage, ClientControlStreamMessage, ClientDatagram,
+    PROTOCOL_VERSION, TICK_RATE,
+};
+
+use crate::config::ServerConfig;
+
+const MAGIC: &[u8; 4] = b"SREC";
+pub const RECORDING_VERSION: u32 = 1;
+pub const RECORDINGS_DIR_NAME: &str = "recordings";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub version: u32,
+    pub protocol_version: u32,
+    pub build_id: String,
+    /// The recording server's config as TOML, server password replaced by its digest
+    pub config: String,
+    /// Whether the world was created for this session. Only then can it be replayed, accounts,
+    /// characters and chunks saved by earlier sessions are not in the recording
+    pub new_world: bool,
+}
+
+/// Something a client sent, or the one thing the server decided that a replay can't work out
+/// for itself.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum RecordedEvent {
+    Connected {
+        client: u32,
+        local: bool,
+    },
+    Control {
+        client: u32,
+        message: ClientControlStreamMessage,
+    },
+    Datagram {
+        client: u32,
+        datagram: ClientDatagram,
+    },
+    Chat {
+        client: u32,
+        message: ClientChatMessage,
+    },
+    Disconnected {
+        client: u32,
+    },
+    /// The server handed the client a resume token, recorded as its digest so a replay can tell
+    /// which of its own tokens a recorded `Resume` stands for
+    TokenIssued {
+        client: u32,
+        token: String,
+    },
+    /// The tick left its inputs queued because chunk generation held the terrain
+    InputsDeferred,
+}
+
+/// An event and the tick whose input collection it happened before.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub tick: u64,
+    pub event: RecordedEvent,
+}
+
+/// Logs every inbound client message and datagram into a zstd compressed file, stamped with the
+/// tick that was next to collect inputs when it arrived. Passwords and resume tokens are
+/// replaced by digests keyed per recording, so the file holds no secrets while equal passwords
+/// still match when replayed.
+pub struct Recorder {
+    path: PathBuf,
+    secrets: RandomState,
+    state: Mutex<RecorderState>,
+}
+
+struct RecorderState {
+    /// `None` once finished or after a write failed
+    writer: Option<zstd::Encoder<'static, File>>,
+    tick: u64,
+    clients: HashMap<SocketAddr, u32>,
+    next_client: u32,
+}
+
+impl Recorder {
+    /// Starts a new recording in the game directory's recordings folder.
+    pub fn start(game_dir: &Path, config: &ServerConfig, new_world: bool) -> anyhow::Result<Self> {
+        let dir = game_dir.join(RECORDINGS_DIR_NAME);
+        fs::create_dir_all(&dir)?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Self::create(
+            &dir.join(format!("session-{started}.rec")),
+            config,
+            new_world,
+        )
+    }
+
+    pub fn create(path: &Path, config: &ServerConfig, new_world: bool) -> anyhow::Result<Self> {
+        let secrets = RandomState::new();
+        let mut config = config.clone();
+        config.players.server_password = config
+            .players
+            .server_password
+            .map(|password| digest(&secrets, &password));
+        let header = RecordingHeader {
+            version: RECORDING_VERSION,
+            protocol_version: PROTOCOL_VERSION,
+            build_id: BUILD_ID.into(),
+            config: toml::to_string(&config)?,
+            new_world,
+        };
+
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        let mut writer = zstd::Encoder::new(file, 0)?;
+        bincode::serde::encode_into_std_write(&header, &mut writer, bincode::config::standard())?;
+        writer.flush()?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            secrets,
+            state: Mutex::new(RecorderState {
+                writer: Some(writer),
+                tick: 0,
+                clients: HashMap::new(),
+                next_client: 0,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn connected(&self, addr: SocketAddr, local: bool) {
+        self.with_state(|state| {
+            let client = state.next_client;
+            state.next_client += 1;
+            state.clients.insert(addr, client);
+            state.write(RecordedEvent::Connected { client, local });
+        });
+    }
+
+    pub fn control(&self, addr: SocketAddr, message: &ClientControlStreamMessage) {
+        use ClientControlStreamMessage::*;
+        let message = match message {
+            Login(credentials) => Login(self.redact(credentials)),
+            CreateAccount(credentials) => CreateAccount(self.redact(credentials)),
+            Resume(token) => Resume(digest(&self.secrets, token)),
+            other => other.clone(),
+        };
+        self.record_from(addr, |client| RecordedEvent::Control { client, message });
+    }
+
+    pub fn datagram(&self, addr: SocketAddr, datagram: &ClientDatagram) {
+        self.record_from(addr, |client| RecordedEvent::Datagram {
+            client,
+            datagram: datagram.clone(),
+        });
+    }
+
+    pub fn chat(&self, addr: SocketAddr, message: &ClientChatMessage) {
+        self.record_from(addr, |client| RecordedEvent::Chat {
+            client,
+            message: message.clone(),
+        });
+    }
+
+    pub fn token_issued(&self, addr: SocketAddr, token: &str) {
+        let token = digest(&self.secrets, token);
+        self.record_from(addr, |client| RecordedEvent::TokenIssued { client, token });
+    }
+
+    pub fn disconnected(&self, addr: SocketAddr) {
+        self.with_state(|state| {
+            if let Some(client) = state.clients.remove(&addr) {
+                state.write(RecordedEvent::Disconnected { client });
+            }
+        });
+    }
+
+    /// Notes that `tick` deferred its inputs to the next one.
+    pub fn inputs_deferred(&self, tick: u64) {
+        self.with_state(|state| state.write_at(tick, RecordedEvent::InputsDeferred));
+    }
+
+    /// Called under the player lock as a tick collects its inputs, so every input recorded after
+    /// is stamped with the tick that will actually collect it.
+    pub fn begin_tick(&self, tick: u64) {
+        self.with_state(|state| {
+            state.tick = tick;
+            // Once a second, so a crash loses at most the last second
+            if tick.is_multiple_of(TICK_RATE as u64)
+                && let Some(writer) = &mut state.writer
+                && let Err(e) = writer.flush()
+            {
+                eprintln!("Stopped recording, could not write: {e}");
+                state.writer = None;
+            }
+        });
+    }
+
+    /// Completes the file, nothing more is recorded afterwards.
+    pub fn finish(&self) {
+        self.with_state(|state| {
+            if let Some(writer) = state.writer.take()
+                && let Err(e) = writer.finish().and_then(|mut file| file.flush())
+            {
+                eprintln!("Could not finish recording: {e}");
+            }
+        });
+    }
+
+    fn redact(&self, credentials: &AccountCredentials) -> AccountCredentials {
+        AccountCredentials {
+            username: credentials.username.clone(),
+            user_password: digest(&self.secrets, &credentials.user_password),
+            server_password: credentials
+                .server_password
+                .as_ref()
+                .map(|password| digest(&self.secrets, password)),
+        }
+    }
+
+    fn record_from(&self, addr: SocketAddr, event: impl FnOnce(u32) -> RecordedEvent) {
+        self.with_state(|state| {
+            if let Some(&client) = state.clients.get(&addr) {
+                state.write(event(client));
+            }
+        });
+    }
+
+    fn with_state(&self, f: impl FnOnce(&mut RecorderState)) {
+        match self.state.lock() {
+            Ok(mut state) => f(&mut state),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
+
+impl RecorderState {
+    fn write(&mut self, event: RecordedEvent) {
+        self.write_at(self.tick, event);
+    }
+
+    fn write_at(&mut self, tick: u64, event: RecordedEvent) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let frame = RecordedFrame { tick, event };
+        if let Err(e) =
+            bincode::serde::encode_into_std_write(&frame, writer, bincode::config::standard())
+        {
+            eprintln!("Stopped recording, could not write: {e}");
+            self.writer = None;
+        }
+    }
+}
+
+fn digest(secrets: &RandomState, secret: &str) -> String {
+    format!("{:016x}", secrets.hash_one(secret))
+}
+
+/// A recording read back in full.
+#[derive(Clone)]
+pub struct Recording {
+    pub header: RecordingHeader,
+    /// In the order they were recorded, which within a tick is the order they arrived in
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    /// Reads a recording, keeping everything up to the last complete frame of one cut short by
+    /// a crash.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("{path:?} is not a session recording");
+        }
+
+        let mut reader = zstd::Decoder::with_buffer(file)?;
+        let config = bincode::config::standard();
+        let header: RecordingHeader = bincode::serde::decode_from_std_read(&mut reader, config)
+            .map_err(|e| anyhow!("Could not read recording header: {e}"))?;
+        if header.version != RECORDING_VERSION {
+            bail!(
+                "Recording format {} is not supported, expected {RECORDING_VERSION}",
+                header.version
+            );
+        }
+        if header.protocol_version != PROTOCOL_VERSION {
+            bail!(
+                "Recorded with protocol version {} ({}), this build speaks {PROTOCOL_VERSION}",
+                header.protocol_version,
+                header.build_id
+            );
+        }
+
+        let mut frames = vec![];
+        loop {
+            match bincode::serde::decode_from_std_read(&mut reader, config) {
+                Ok(frame) => frames.push(frame),
+                Err(bincode::error::DecodeError::Io { inner, .. })
+                    if inner.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Recording ends early after {} frames: {e}", frames.len());
+                    break;
+                }
+            }
+        }
+
+        Ok(Self { header, frames })
+    }
+
+    pub fn config(&self) -> anyhow::Result<ServerConfig> {
+        Ok(toml::from_str(&self.header.config)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{ActionFlags, PlayerInput};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}.rec", std::process::id()))
+    }
+
+    #[test]
+    fn reads_back_what_was_recorded() -> anyhow::Result<()> {
+        let path = temp_path("recording-round-trip");
+        let addr: SocketAddr = "127.0.0.1:4000".parse()?;
+        let input = PlayerInput {
+            input_id: 7,
+            client_time_ms: 100,
+            movement: [1.0, 0.0],
+            actions: ActionFlags::default(),
+        };
+
+        let recorder = Recorder::create(&path, &ServerConfig::default(), true)?;
+        recorder.connected(addr, true);
+        recorder.begin_tick(3);
+        recorder.datagram(addr, &ClientDatagram::Input(input));
+        recorder.inputs_deferred(2);
+        recorder.disconnected(addr);
+        // Unknown clients are not recorded
+        recorder.datagram(addr, &ClientDatagram::SnapshotAck(1));
+        recorder.finish();
+
+        let recording = Recording::load(&path)?;
+        fs::remove_file(&path)?;
+        assert!(recording.header.new_world);
+        let ticks: Vec<u64> = recording.frames.iter().map(|frame| frame.tick).collect();
+        assert_eq!(ticks, [0, 3, 2, 3]);
+        assert!(matches!(
+            recording.frames[1].event,
+            RecordedEvent::Datagram {
+                client: 0,
+                datagram: ClientDatagram::Input(PlayerInput { input_id: 7, .. }),
+            }
+        ));
+        assert!(matches!(
+            recording.frames[3].event,
+            RecordedEvent::Disconnected { client: 0 }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_secrets_out_of_the_file() -> anyhow::Result<()> {
+        let path = temp_path("recording-secrets");
+        let addr: SocketAddr = "127.0.0.1:4001".parse()?;
+        let mut config = ServerConfig::default();
+        config.players.server_password = Some("hunter2".into());
+        let credentials = |password: &str| {
+            ClientControlStreamMessage::Login(AccountCredentials::new(
+                "alice".into(),
+                password.into(),
+                Some("hunter2".into()),
+            ))
+        };
+
+        let recorder = Recorder::create(&path, &config, true)?;
+        recorder.connected(addr, false);
+        recorder.control(addr, &credentials("correct horse"));
+        recorder.control(addr, &credentials("correct horse"));
+        recorder.control(addr, &credentials("wrong"));
+        recorder.token_issued(addr, "resume token");
+        recorder.control(
+            addr,
+            &ClientControlStreamMessage::Resume("resume token".into()),
+        );
+        recorder.finish();
+
+        let recording = Recording::load(&path)?;
+        fs::remove_file(&path)?;
+        let passwords: Vec<(String, Option<String>)> = recording
+            .frames
+            .iter()
+            .filter_map(|frame| match &frame.event {
+                RecordedEvent::Control {
+                    message: ClientControlStreamMessage::Login(credentials),
+                    ..
+                } => Some((
+                    credentials.user_password.clone(),
+                    credentials.server_password.clone(),
+                )),
+                _ => None,
+            })
+            .collect();
+
+        assert!(!passwords[0].0.contains("horse"));
+        assert_eq!(passwords[0], passwords[1]);
+        assert_ne!(passwords[0].0, passwords[2].0);
+        // The recorded config's password still matches the digest clients sent
+        let server_password = recording.config()?.players.server_password;
+        assert_eq!(server_password, passwords[0].1);
+        assert_ne!(server_password.as_deref(), Some("hunter2"));
+
+        // A resume token is replaced by the same digest where it was issued and where it was used
+        let [.., issued, resumed] = recording.frames.as_slice() else {
+            panic!("expected the token frames");
+        };
+        match (&issued.event, &resumed.event) {
+            (
+                RecordedEvent::TokenIssued { token: issued, .. },
+                RecordedEvent::Control {
+                    message: ClientControlStreamMessage::Resume(resumed),
+                    ..
+                },
+            ) => {
+                assert_eq!(issued, resumed);
+                assert_ne!(issued, "resume token");
+            }
+            _ => panic!("expected the token to be issued, then resumed"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail};
+use shared::{
+    ChunkPos, ChunkView, ClientControlStreamMessage, ClientDatagram, PlayerPos, ServerChatMessage,
+    ServerControlStreamMessage,
+};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, unbounded_channel},
+    watch,
+};
+
+use crate::{
+    GameStartOption,
+    chat::say,
+    config::{CONFIG_FILE_NAME, ConfigOverrides},
+    recording::{RecordedEvent, Recording},
+    server_networking::dispatch_control,
+    state::{ConnectionRole, GameManager, ServerSession, SessionEvent},
+    tick::TickLoop,
+};
+
+const REPLAY_WORLD_NAME: &str = "replay";
+
+/// Where a replayed player was when last seen in the world.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayedPlayer {
+    pub entity_id: u32,
+    pub position: PlayerPos,
+    pub last_input_id: Option<u32>,
+}
+
+/// A recorded client and everything the server sent it during the replay.
+pub struct ReplayedClient {
+    pub addr: SocketAddr,
+    pub local: bool,
+    /// Control stream messages, including any disconnect
+    pub received: Vec<ServerControlStreamMessage>,
+    pub chat_received: Vec<ServerChatMessage>,
+    pub player: Option<ReplayedPlayer>,
+    session: Arc<tokio::sync::Mutex<ServerSession>>,
+    outbound: UnboundedReceiver<SessionEvent>,
+    chat: Option<UnboundedReceiver<ServerChatMessage>>,
+    /// Stands in for the chunk stream once the player joins
+    view: Option<watch::Receiver<ChunkView>>,
+}
+
+/// Feeds a recording into a fresh `GameManager` built from the recorded config, rebuilding the
+/// world one tick at a time. Ticks run back to back rather than on the clock, each after the
+/// events recorded before it. The replay starts from an empty world, so only sessions that
+/// started on a new world can be replayed.
+pub struct Replay {
+    game_manager: Arc<GameManager>,
+    tick_loop: TickLoop,
+    /// Events by the tick they came before, in recorded order within each tick
+    events: BTreeMap<u64, Vec<RecordedEvent>>,
+    clients: BTreeMap<u32, ReplayedClient>,
+    /// Resume tokens this replay issued, by the digest of the one recorded in their place
+    tokens: HashMap<String, String>,
+}
+
+impl Replay {
+    /// Creates the replay's world in a `replay` directory inside `data_dir`.
+    pub async fn start(recording: Recording, data_dir: &Path) -> anyhow::Result<Self> {
+        if !recording.header.new_world {
+            bail!(
+                "Only sessions recorded on a new world can be replayed, this one loaded a world \
+                 whose accounts, characters and chunks are not in the recording"
+            );
+        }
+        let mut config = recording.config()?;
+        config.recording.enabled = false;
+        config.autosave.enabled = false;
+
+        let game_dir = data_dir.join(REPLAY_WORLD_NAME);
+        if game_dir.exists() {
+            bail!("Replay directory '{:?}' already exists", game_dir);
+        }
+        fs::create_dir_all(&game_dir)?;
+        config.save(&game_dir.join(CONFIG_FILE_NAME))?;
+        let game_manager = GameManager::new(
+            GameStartOption::LoadGame(REPLAY_WORLD_NAME.into()),
+            data_dir,
+            &ConfigOverrides::default(),
+        )
+        .await?;
+
+        let mut events: BTreeMap<u64, Vec<RecordedEvent>> = BTreeMap::new();
+        for frame in recording.frames {
+            events.entry(frame.tick).or_default().push(frame.event);
+        }
+
+        Ok(Self {
+            tick_loop: TickLoop::new(game_manager.clone()),
+            game_manager,
+            events,
+            clients: BTreeMap::new(),
+            tokens: HashMap::new(),
+        })
+    }
+
+    pub fn game_manager(&self) -> &Arc<GameManager> {
+        &self.game_manager
+    }
+
+    /// The number of ticks replayed so far.
+    pub fn tick(&self) -> u64 {
+        self.tick_loop.tick()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Recorded clients by the number the recording gave them, in the order they connected.
+    pub fn clients(&self) -> &BTreeMap<u32, ReplayedClient> {
+        &self.clients
+    }
+
+    /// Applies the events recorded before the next tick, then runs it.
+    pub async fn step(&mut self) -> anyhow::Result<()> {
+        let tick = self.tick();
+        let mut defer_inputs = false;
+        while let Some(entry) = self.events.first_entry()
+            && *entry.key() <= tick
+        {
+            for event in entry.remove() {
+                match event {
+                    RecordedEvent::InputsDeferred => defer_inputs = true,
+                    event => self.apply(event).await?,
+                }
+            }
+        }
+
+        // Holding the terrain makes the tick defer its inputs, just as chunk generation did
+        let terrain = if defer_inputs {
+            Some(self.game_manager.chunk_manager.write().await)
+        } else {
+            None
+        };
+        self.tick_loop.run_tick();
+        drop(terrain);
+
+        self.collect_outbound();
+        Ok(())
+    }
+
+    /// Steps until everything recorded has been applied.
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        while !self.is_finished() {
+            self.step().await?;
+        }
+        Ok(())
+    }
+
+    async fn apply(&mut self, event: RecordedEvent) -> anyhow::Result<()> {
+        let client_id = match &event {
+            RecordedEvent::Connected { client, local } => {
+                self.connect(*client, *local);
+                return Ok(());
+            }
+            RecordedEvent::Control { client, .. }
+            | RecordedEvent::Datagram { client, .. }
+            | RecordedEvent::Chat { client, .. }
+            | RecordedEvent::TokenIssued { client, .. }
+            | RecordedEvent::Disconnected { client } => *client,
+            RecordedEvent::InputsDeferred => return Ok(()),
+        };
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return Err(anyhow!(
+                "Recording uses client {client_id} before it connected"
+            ));
+        };
+        let addr = client.addr;
+        let session = client.session.clone();
+
+        match event {
+            RecordedEvent::Control { message, .. } => {
+                // Resumes with the token this replay issued, the recorded one is only a digest
+                let message = match message {
+                    ClientControlStreamMessage::Resume(token) => {
+                        ClientControlStreamMessage::Resume(
+                            self.tokens.get(&token).cloned().unwrap_or(token),
+                        )
+                    }
+                    message => message,
+                };
+                let mut view = None;
+                let reply = dispatch_control(message, &session, &self.game_manager, async || {
+                    let (view_tx, view_rx) = watch::channel(ChunkView::new(ChunkPos::new(0, 0), 0));
+                    view = Some(view_rx);
+                    Ok(view_tx)
+                })
+                .await?;
+                if let Some(reply) = reply {
+                    session.lock().await.send(reply);
+                }
+                if view.is_some() {
+                    client.view = view;
+                }
+
+                // Generated straight away, where the live server streams them in over a few ticks
+                if let Some(view) = &mut client.view
+                    && view.has_changed().unwrap_or(false)
+                {
+                    let view = *view.borrow_and_update();
+                    for pos in view.positions() {
+                        self.game_manager.chunk(pos).await?;
+                    }
+                }
+            }
+            RecordedEvent::Datagram { datagram, .. } => {
+                let mut players = self
+                    .game_manager
+                    .players
+                    .lock()
+                    .map_err(|_| anyhow!("Player list is poisoned"))?;
+                match datagram {
+                    ClientDatagram::Input(input) => players.receive_input(addr, input),
+                    ClientDatagram::SnapshotAck(tick) => players.acknowledge_snapshot(addr, tick),
+                    // Only measure the network, which a replay has none of
+                    ClientDatagram::Ping(_) | ClientDatagram::Pong(_) => {}
+                }
+            }
+            RecordedEvent::Chat { message, .. } => {
+                // The first message opens the chat stream, as it does for a real client
+                if client.chat.is_none() {
+                    let (chat_tx, chat_rx) = unbounded_channel();
+                    session.lock().await.chat = Some(chat_tx);
+                    client.chat = Some(chat_rx);
+                }
+                if let Err(rejection) = say(message, &session, &self.game_manager).await
+                    && let Some(chat) = &session.lock().await.chat
+                {
+                    let _ = chat.send(ServerChatMessage::Rejected(rejection));
+                }
+            }
+            RecordedEvent::TokenIssued { token, .. } => {
+                if let Some(issued) = session.lock().await.resume_token.clone() {
+                    self.tokens.insert(token, issued);
+                }
+            }
+            RecordedEvent::Disconnected { .. } => {
+                if let Some(player) = self.game_manager.leave_world(addr).await {
+                    client.player = Some(ReplayedPlayer {
+                        entity_id: player.entity_id,
+                        position: player.position,
+                        last_input_id: player.last_input_id,
+                    });
+                }
+                self.game_manager
+                    .session_manager
+                    .remove(&session, self.game_manager.config.players.resume_grace())
+                    .await;
+            }
+            RecordedEvent::Connected { .. } | RecordedEvent::InputsDeferred => {}
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self, client: u32, local: bool) {
+        // Made up addresses, loopback for clients that were local so they keep their privileges
+        let (ip, role) = if local {
+            (Ipv4Addr::LOCALHOST, ConnectionRole::LocalMaster)
+        } else {
+            (
+                Ipv4Addr::from(0x0a00_0000 + client),
+                ConnectionRole::RemoteClient,
+            )
+        };
+        let addr = SocketAddr::new(ip.into(), 1024 + (client % 64_000) as u16);
+
+        let (outbound_tx, outbound) = unbounded_channel();
+        let session = Arc::new(tokio::sync::Mutex::new(ServerSession::replayed(
+            addr,
+            role,
+            outbound_tx,
+        )));
+        self.game_manager
+            .session_manager
+            .sessions
+            .insert(addr, session.clone());
+        self.clients.insert(
+            client,
+            ReplayedClient {
+                addr,
+                local,
+                received: vec![],
+                chat_received: vec![],
+                player: None,
+                session,
+                outbound,
+                chat: None,
+                view: None,
+            },
+        );
+    }
+
+    fn collect_outbound(&mut self) {
+        let players = self.game_manager.players.lock().ok();
+        for client in self.clients.values_mut() {
+            while let Ok(event) = client.outbound.try_recv() {
+                client.received.push(match event {
+                    SessionEvent::Message(message) => message,
+                    SessionEvent::Disconnect { reason, .. } => {
+                        ServerControlStreamMessage::Disconnected(reason)
+                    }
+                });
+            }
+            if let Some(chat) = &mut client.chat {
+                while let Ok(message) = chat.try_recv() {
+                    client.chat_received.push(message);
+                }
+            }
+            if let Some(player) = players
+                .as_ref()
+                .and_then(|players| players.get(&client.addr))
+            {
+                client.player = Some(ReplayedPlayer {
+                    entity_id: player.entity_id,
+                    position: player.position,
+                    last_input_id: player.last_input_id,
+                });
+            }
+        }
+    }
+}
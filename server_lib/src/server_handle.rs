@@ -0,0 +1,72 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use quinn::Endpoint;
+use shared::CloseCode;
+
+use crate::{state::GameManager, thread_manager::ThreadManager};
+
+const SHUTDOWN_REASON: &str = "Server shutting down";
+const SESSION_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+const ENDPOINT_IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct ServerHandle {
+    pub game_manager: Arc<GameManager>,
+    endpoint: Endpoint,
+    thread_manager: Arc<ThreadManager>,
+}
+
+impl ServerHandle {
+    pub fn new(
+        game_manager: Arc<GameManager>,
+        endpoint: Endpoint,
+        thread_manager: Arc<ThreadManager>,
+    ) -> Self {
+        Self {
+            game_manager,
+            endpoint,
+            thread_manager,
+        }
+    }
+
+    /// Stops accepting players, tells everyone connected why they are leaving, saves the world
+    /// and closes the endpoint before the server's task tree is cancelled.
+    pub async fn shutdown(&self) {
+        self.endpoint.set_server_config(None);
+
+        for session in self.game_manager.session_manager.all() {
+            session
+                .lock()
+                .await
+                .disconnect(SHUTDOWN_REASON, CloseCode::ServerShutdown);
+        }
+
+        let started = Instant::now();
+        while !self.game_manager.session_manager.sessions.is_empty()
+            && started.elapsed() < SESSION_DRAIN_TIMEOUT
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        match self.game_manager.save_world().await {
+            Ok(count) => println!("World saved ({count} chunk(s) written)"),
+            Err(e) => eprintln!("Could not save world during shutdown: {e}"),
+        }
+        if let Some(recorder) = &self.game_manager.recorder {
+            recorder.finish();
+        }
+
+        self.endpoint
+            .close(CloseCode::ServerShutdown.code(), SHUTDOWN_REASON.as_bytes());
+        if tokio::time::timeout(ENDPOINT_IDLE_TIMEOUT, self.endpoint.wait_idle())
+            .await
+            .is_err()
+        {
+            eprintln!("Timed out waiting for connections to close");
+        }
+
+        self.thread_manager.shutdown().await;
+    }
+}
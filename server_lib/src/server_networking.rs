@@ -1,102 +1,283 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use anyhow::anyhow;
 use quinn::{Connection, Incoming, RecvStream, SendStream};
 use shared::{
-    ChunkPos, ClientControlStreamMessage, ServerControlStreamMessage, receive_message, send_message,
+    ChunkPos, ChunkView, ClientControlStreamMessage, ClientDatagram, CloseCode, Compression, FrameCodec,
+    PING_INTERVAL, ProtocolError, ServerControlStreamMessage, ServerDatagram, StreamKind,
+    decode_payload, forward_frames, open_uni_stream, receive_message, send_datagram, send_message,
+};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, unbounded_channel},
+    watch,
 };
 
 use crate::{
-    state::{AuthState, GameManager, ServerSession},
+    chat::handle_chat_stream,
+    chunk_streaming::ChunkStreamer,
+    state::{ConnectionRole, GameManager, ServerSession, SessionEvent},
     thread_manager::ThreadManager,
 };
 
+const STREAM_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Gives the client a moment to read what was last sent before the connection is closed.
+async fn close_control_stream(
+    send: &mut SendStream,
+    session: &Arc<tokio::sync::Mutex<ServerSession>>,
+    code: CloseCode,
+    reason: &str,
+) {
+    let _ = send.finish();
+    let _ = tokio::time::timeout(Duration::from_secs(1), send.stopped()).await;
+    if let Some(connection) = &session.lock().await.connection {
+        connection.close(code.code(), reason.as_bytes());
+    }
+}
+
+/// Opens the session's chunk stream and starts feeding it once the client reports a view.
+async fn start_chunk_stream(
+    session: &Arc<tokio::sync::Mutex<ServerSession>>,
+    game_manager: &Arc<GameManager>,
+    thread_manager: &Arc<ThreadManager>,
+) -> anyhow::Result<watch::Sender<ChunkView>> {
+    let (connection, compression) = {
+        let session = session.lock().await;
+        let Some(connection) = session.connection.clone() else {
+            return Err(anyhow!("Session has no connection to stream chunks to"));
+        };
+        (connection, Compression::from_features(&session.features))
+    };
+
+    let send = open_uni_stream(&connection, StreamKind::Chunks).await?;
+    let network = &game_manager.config.network;
+    let codec = FrameCodec::new(network.frame_limits.chunks)
+        .with_compression(compression, network.compression_threshold);
+    let (view_tx, view_rx) = watch::channel(ChunkView::new(ChunkPos::new(0, 0), 0));
+    let streamer = ChunkStreamer::new(game_manager.clone(), send, codec, view_rx);
+
+    let addr = connection.remote_address();
+    thread_manager
+        .spawn(move || async move {
+            if let Err(e) = streamer.run().await {
+                eprintln!("Chunk stream to {addr} stopped: {e}");
+            }
+        })
+        .await;
+
+    Ok(view_tx)
+}
+
+/// Records and handles one control message if the session's phase allows it, returning the
+/// reply for the client. Live connections and replays both come through here and only differ
+/// in how chunks reach a joining player, `open_chunk_stream` hands back where its view goes.
+pub(crate) async fn dispatch_control(
+    message: ClientControlStreamMessage,
+    session: &Arc<tokio::sync::Mutex<ServerSession>>,
+    game_manager: &Arc<GameManager>,
+    open_chunk_stream: impl AsyncFnOnce() -> anyhow::Result<watch::Sender<ChunkView>>,
+) -> anyhow::Result<Option<ServerControlStreamMessage>> {
+    use ClientControlStreamMessage::*;
+
+    let (phase, addr) = {
+        let session = session.lock().await;
+        (session.phase(), session.addr)
+    };
+    // A join is recorded by `spawn_player` as the player actually spawns
+    if let Some(recorder) = &game_manager.recorder
+        && !(matches!(message, JoinWorldRequest) && phase.allows(&message))
+    {
+        recorder.control(addr, &message);
+    }
+    if !phase.allows(&message) {
+        let error = ProtocolError::UnexpectedMessage {
+            message: message.name().into(),
+            phase,
+        };
+        return Ok(Some(ServerControlStreamMessage::ProtocolError(error)));
+    }
+
+    let reply = match message {
+        ConnectionRequest(hello) => game_manager.handshake(hello, session.clone()).await,
+        Login(credentials) => game_manager.login(credentials, session.clone()).await,
+        Resume(token) => game_manager.resume(token, session.clone()).await,
+        CreateAccount(credentials) => game_manager.create(credentials, session.clone()).await,
+        SelectCharacter(id) => game_manager.select_character(id, session.clone()).await,
+        CreateCharacter(name) => game_manager.create_character(name, session.clone()).await,
+        JoinWorldRequest => {
+            let entity_id = game_manager
+                .spawn_player(session)
+                .await
+                .map_err(|e| anyhow!("Could not spawn player: {e}"))?;
+            let view_tx = open_chunk_stream()
+                .await
+                .map_err(|e| anyhow!("Could not open chunk stream: {e}"))?;
+            let mut session = session.lock().await;
+            session.chunk_view = Some(view_tx);
+            session.enter_world();
+            ServerControlStreamMessage::WorldJoined {
+                max_view_radius: game_manager.config.world.view_radius,
+                chunk_size: game_manager.config.world.chunk_size,
+                entity_id,
+            }
+        }
+        UpdateView(view) => {
+            if let Some(view) = game_manager.allowed_view(addr, view)
+                && let Some(chunk_view) = &session.lock().await.chunk_view
+            {
+                let _ = chunk_view.send(view);
+            }
+            return Ok(None);
+        }
+    };
+    Ok(Some(reply))
+}
+
 pub async fn handle_control_stream(
     send: &mut SendStream,
-    recv: &mut RecvStream,
+    recv: RecvStream,
     session: Arc<tokio::sync::Mutex<ServerSession>>,
+    mut outbound: UnboundedReceiver<SessionEvent>,
     game_manager: Arc<GameManager>,
     thread_manager: Arc<ThreadManager>,
 ) -> anyhow::Result<()> {
-    use ClientControlStreamMessage::*;
+    let mut codec = FrameCodec::new(game_manager.config.network.frame_limits.control);
+    // Frames are read on their own task, so outbound events never cut one off halfway
+    let (frame_tx, mut frames) = unbounded_channel();
+    thread_manager
+        .spawn(move || forward_frames(recv, codec, frame_tx))
+        .await;
     loop {
         tokio::select! {
             _ = thread_manager.await_cancel() => {
                 println!("Server exiting control stream");
                 break;
             },
-            response = receive_message::<ClientControlStreamMessage>(recv) => {
-                match response {
-                    Ok(ConnectionRequest) => {
-                        send_message(send, ServerControlStreamMessage::Connected).await?;
-                    }
-                    Ok(Login(credentials)) => {
-                        let message = game_manager.login(credentials, session.clone()).await;
-                        send_message(send, message).await?;
-                    }
-                    Ok(CreateAccount(credentials)) => {
-                        let message = game_manager.create(credentials, session.clone()).await;
-                        send_message(send, message).await?;
-                    }
-                    Ok(SelectCharacter(id)) => {
-                        match session.lock().await.auth.clone() {
-                            AuthState::Authenticated{ username } => {
-                                let msg = game_manager.select_character(username, id).await;
-                                if let Err(e) = send_message(send, msg).await {
-                                    eprintln!("Error sending selected character to client: {e}");
-                                }
-                            },
-                            _ => {
-                                if let Err(e) = send_message(send, ServerControlStreamMessage::CharacterDenied("User not authenticated".into())).await {
-                                    eprintln!("Could not deny client character selection: {e}");
-                                }
-                            }
-                        }
+            Some(event) = outbound.recv() => {
+                match event {
+                    SessionEvent::Message(msg) => {
+                        codec.send(send, msg).await?;
                     }
-                    Ok(CreateCharacter(character_name)) => {
-                        match session.lock().await.auth.clone() {
-                            AuthState::Authenticated{ username } => {
-                                let msg = game_manager.create_character(username, character_name).await;
-                                if let Err(e) = send_message(send, msg).await {
-                                    eprintln!("Error sending created character to client: {e}");
-                                }
-                            },
-                            _ => {
-                                if let Err(e) = send_message(send, ServerControlStreamMessage::CharacterDenied("User not authenticated".into())).await {
-                                    eprintln!("Could not deny client character creation: {e}");
-                                }
-                            }
+                    SessionEvent::Disconnect { reason, code } => {
+                        // A kicked or banned player must log in again rather than resume
+                        session.lock().await.resume_token = None;
+                        if let Err(e) = codec.send(send, ServerControlStreamMessage::Disconnected(reason.clone())).await {
+                            eprintln!("Could not notify client of disconnect: {e}");
                         }
+                        close_control_stream(send, &session, code, &reason).await;
+                        break;
                     }
-                    Ok(JoinWorldRequest) => {
-                        if session.lock().await.is_authed() {
-                            if let Err(e) = send_message(send, ServerControlStreamMessage::InitialWorld {
-                                chunks: game_manager.chunk_manager.get_chunks_radius(ChunkPos::new(0, 0), 2),
-                            }).await {
-                                eprintln!("Error sending initial chunk data to client: {e}");
-                            }
-                        } else {
-                            if let Err(e) = send_message(send, ServerControlStreamMessage::CharacterDenied("User not authenticated".into())).await {
-                                eprintln!("Could not deny client join world request: {e}");
+                }
+            },
+            frame = frames.recv() => {
+                // The reader stops once it has passed on the error that ended the stream
+                let Some(frame) = frame else {
+                    break;
+                };
+                let message = match frame.and_then(|frame| codec.decode::<ClientControlStreamMessage>(&frame)) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("Error receiving message from client: {e}");
+                        if let Some(code) = e.close_code() {
+                            let mut session = session.lock().await;
+                            session.resume_token = None;
+                            if let Some(connection) = &session.connection {
+                                connection.close(code.code(), e.to_string().as_bytes());
                             }
+                            break;
                         }
+                        thread_manager.shutdown().await;
+                        continue;
                     }
+                };
+
+                let reply = match dispatch_control(message, &session, &game_manager, async || {
+                    start_chunk_stream(&session, &game_manager, &thread_manager).await
+                }).await {
+                    Ok(Some(reply)) => reply,
+                    Ok(None) => continue,
                     Err(e) => {
-                        eprintln!("Error receiving message from client: {e}");
-                        thread_manager.shutdown().await;
+                        eprintln!("{e}");
+                        break;
                     }
+                };
+                let compression = match &reply {
+                    ServerControlStreamMessage::Connected(hello) => Some(Compression::from_features(&hello.features)),
+                    _ => None,
+                };
+                let incompatible = matches!(reply, ServerControlStreamMessage::IncompatibleVersion { .. });
+                codec.send(send, reply).await?;
+                if incompatible {
+                    close_control_stream(send, &session, CloseCode::IncompatibleVersion, "Incompatible protocol version").await;
+                    break;
+                }
+                if let Some(compression) = compression {
+                    codec = codec.with_compression(compression, game_manager.config.network.compression_threshold);
                 }
             }
         }
     }
 
+    let addr = session.lock().await.addr;
+    game_manager.leave_world(addr).await;
     game_manager
         .session_manager
-        .sessions
-        .remove(&session.lock().await.addr.clone());
+        .remove(&session, game_manager.config.players.resume_grace())
+        .await;
 
     Ok(())
 }
 
+/// Reads the purpose a client declared for a new stream and hands the stream to its handler.
+async fn route_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    session: Arc<tokio::sync::Mutex<ServerSession>>,
+    control_outbound: Arc<tokio::sync::Mutex<Option<UnboundedReceiver<SessionEvent>>>>,
+    game_manager: Arc<GameManager>,
+    thread_manager: Arc<ThreadManager>,
+) -> anyhow::Result<()> {
+    let kind =
+        tokio::time::timeout(STREAM_HEADER_TIMEOUT, receive_message::<StreamKind>(&mut recv))
+            .await??;
+
+    match kind {
+        StreamKind::Control => {
+            let Some(outbound) = control_outbound.lock().await.take() else {
+                send_message(
+                    &mut send,
+                    ServerControlStreamMessage::Disconnected("Control stream already opened".into()),
+                )
+                .await?;
+                let _ = send.finish();
+                return Ok(());
+            };
+            handle_control_stream(
+                &mut send,
+                recv,
+                session,
+                outbound,
+                game_manager,
+                thread_manager,
+            )
+            .await
+        }
+        StreamKind::Chat => {
+            handle_chat_stream(send, recv, session, game_manager, thread_manager).await
+        }
+        // Chunks only ever flow from the server, and nothing uses bulk transfers yet
+        StreamKind::Chunks | StreamKind::Bulk => {
+            let _ = recv.stop(CloseCode::UnsupportedStream.code());
+            let _ = send.reset(CloseCode::UnsupportedStream.code());
+            Ok(())
+        }
+    }
+}
+
 pub async fn handle_connection(
     conn: Incoming,
     game_manager: Arc<GameManager>,
@@ -105,14 +286,25 @@ pub async fn handle_connection(
     let connection: Connection = conn.await?;
     let addr: SocketAddr = connection.remote_address();
 
-    let session = Arc::new(tokio::sync::Mutex::new(ServerSession::new(addr)));
+    let (outbound_tx, outbound_rx) = unbounded_channel::<SessionEvent>();
+    // Taken by the first control stream, later ones are refused
+    let control_outbound = Arc::new(tokio::sync::Mutex::new(Some(outbound_rx)));
+    let session = Arc::new(tokio::sync::Mutex::new(ServerSession::new(
+        connection.clone(),
+        game_manager.connection_role(addr),
+        outbound_tx,
+    )));
 
     game_manager
         .session_manager
         .sessions
         .insert(addr, session.clone());
+    if let Some(recorder) = &game_manager.recorder {
+        recorder.connected(addr, session.lock().await.role == ConnectionRole::LocalMaster);
+    }
 
     let child = thread_manager.child().await;
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
 
     loop {
         tokio::select! {
@@ -120,32 +312,76 @@ pub async fn handle_connection(
                 println!("Server exiting accept loop");
                 break;
             }
+            _ = ping_interval.tick() => {
+                let ping = session.lock().await.clock.next_ping(Instant::now());
+                if let Err(e) = send_datagram(&connection, &ServerDatagram::Ping(ping)).await {
+                    eprintln!("Error pinging {addr}: {e}");
+                }
+            }
+            datagram = connection.read_datagram() => {
+                let bytes = match datagram {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        eprintln!("Error reading datagram from client: {e}");
+                        break;
+                    }
+                };
+                let datagram = match decode_payload::<ClientDatagram>(&bytes) {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        eprintln!("Dropping malformed datagram from {addr}: {e}");
+                        continue;
+                    }
+                };
+                // Inputs and acks are recorded under the player lock, which the tick also takes to
+                // collect inputs, so the recording agrees with the tick on when each one arrived
+                let record = || {
+                    if let Some(recorder) = &game_manager.recorder {
+                        recorder.datagram(addr, &datagram);
+                    }
+                };
+                match datagram {
+                    ClientDatagram::Input(input) => {
+                        if let Ok(mut players) = game_manager.players.lock() {
+                            record();
+                            players.receive_input(addr, input);
+                        }
+                    }
+                    ClientDatagram::SnapshotAck(tick) => {
+                        if let Ok(mut players) = game_manager.players.lock() {
+                            record();
+                            players.acknowledge_snapshot(addr, tick);
+                        }
+                    }
+                    ClientDatagram::Ping(ping) => {
+                        record();
+                        let pong = ServerDatagram::Pong(ping.reply(game_manager.server_time_us()));
+                        if let Err(e) = send_datagram(&connection, &pong).await {
+                            eprintln!("Error answering ping from {addr}: {e}");
+                        }
+                    }
+                    ClientDatagram::Pong(pong) => {
+                        record();
+                        session.lock().await.clock.receive_pong(pong, Instant::now());
+                    }
+                }
+            }
             response = connection.accept_bi() => {
                 match response {
-                    Ok((mut send, mut recv)) => {
+                    Ok((send, recv)) => {
                         let session = session.clone();
-                        let mut session_guard = session.lock().await;
-                        if session_guard.control_stream_opened {
-                            drop(session_guard);
-                            let _ = send_message(
-                                &mut send,
-                                ServerControlStreamMessage::Disconnected(
-                                    "Control stream already opened".into(),
-                                ),
-                            );
-                        } else {
-                            session_guard.control_stream_opened = true;
-                            drop(session_guard);
-                            let game_manager = game_manager.clone();
-                            let child_2 = child.child().await;
-                            child
+                        let control_outbound = control_outbound.clone();
+                        let game_manager = game_manager.clone();
+                        let stream_manager = child.child().await;
+                        child
                             .spawn(|| async move {
-                                if let Err(e) = handle_control_stream(
-                                    &mut send,
-                                    &mut recv,
-                                    session.clone(),
+                                if let Err(e) = route_stream(
+                                    send,
+                                    recv,
+                                    session,
+                                    control_outbound,
                                     game_manager,
-                                    child_2,
+                                    stream_manager,
                                 )
                                 .await
                                 {
@@ -153,7 +389,6 @@ pub async fn handle_connection(
                                 }
                             })
                             .await;
-                        }
                     },
                     Err(e) => {
                         eprintln!("Error accepting bi-directional stream from client: {e}");
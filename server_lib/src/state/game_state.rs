@@ -1,51 +1,88 @@
 use anyhow::anyhow;
-use bcrypt::{hash, verify};
 use migration::{Migrator, MigratorTrait};
 use sea_orm::{
-    ActiveModelTrait, ActiveValue::Set, ColumnTrait, Database, DatabaseConnection, EntityTrait,
-    ModelTrait, QueryFilter, sqlx::types::chrono,
+    ActiveModelTrait,
+    ActiveValue::{Set, Unchanged},
+    ColumnTrait, Database, DatabaseConnection, EntityTrait, ModelTrait, QueryFilter,
+    sea_query::OnConflict,
+    sqlx::types::chrono,
 };
 use shared::{
-    AccountCredentials, AccountInfo, ChunkManager, ServerControlStreamMessage, accounts, characters,
+    AccountCredentials, AccountInfo, BUILD_ID, Chunk, ChunkManager, ChunkPos, ChunkView,
+    ClientControlStreamMessage, ClientHello, CloseCode, Compression, PROTOCOL_VERSION, PlayerPos,
+    ServerControlStreamMessage, ServerHello, accounts, bans, characters,
 };
 use std::{
     fs,
+    net::SocketAddr,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
+const CHUNKS_DIR_NAME: &str = "chunks";
+/// Optional protocol features this server can agree to during the handshake.
+const SUPPORTED_FEATURES: &[&str] = &[Compression::Zstd.feature()];
+
 use crate::{
     GameStartOption,
-    state::{AuthState, ServerSession, SessionManager},
+    config::{CONFIG_FILE_NAME, ConfigOverrides, ServerConfig},
+    identity::ServerIdentity,
+    login_throttle::LoginThrottle,
+    password::PasswordHasher,
+    recording::Recorder,
+    state::{ConnectionRole, PlayerManager, PlayerState, ServerSession, SessionManager},
+    tick::{TickStats, tick_duration},
 };
 
 pub struct GameManager {
     pub db: DatabaseConnection,
     pub session_manager: Arc<SessionManager>,
     pub game_dir: PathBuf,
-    pub chunk_manager: ChunkManager,
+    pub config: ServerConfig,
+    pub identity: ServerIdentity,
+    pub chunk_manager: tokio::sync::RwLock<ChunkManager>,
+    pub tick_stats: Mutex<TickStats>,
+    pub players: Mutex<PlayerManager>,
+    /// Set while sessions are being recorded
+    pub recorder: Option<Recorder>,
+    single_player: bool,
+    /// The last tick to start and when, server time is measured from it so it runs with the ticks
+    tick_clock: Mutex<(u64, Instant)>,
+    password_hasher: PasswordHasher,
+    login_throttle: LoginThrottle,
 }
 
 impl GameManager {
-    pub async fn new(option: GameStartOption) -> anyhow::Result<Arc<Self>> {
-        let data_path = Path::new("src/data");
-        if !data_path.exists() {
-            fs::create_dir(data_path)?;
+    pub async fn new(
+        option: GameStartOption,
+        data_dir: &Path,
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<Arc<Self>> {
+        if !data_dir.exists() {
+            fs::create_dir_all(data_dir)?;
         }
 
-        let game_dir = match option {
+        let new_world = matches!(option, GameStartOption::NewGame(_));
+        let (game_dir, mut config) = match option {
             GameStartOption::LoadGame(name) => {
-                let path = data_path.join(name);
+                let path = data_dir.join(name);
                 if !path.exists() {
                     return Err(anyhow!(
                         "Error loading game, path '{:?}' does not exist",
                         path
                     ));
                 }
-                path
+                if overrides.seed.is_some() {
+                    return Err(anyhow!(
+                        "Error loading game, the seed of an existing world can't be changed"
+                    ));
+                }
+                let config = ServerConfig::load_or_create(&path)?;
+                (path, config)
             }
             GameStartOption::NewGame(name) => {
-                let path = data_path.join(name);
+                let path = data_dir.join(name);
                 if path.exists() {
                     return Err(anyhow!(
                         "Error creating game, path '{:?}' already exists",
@@ -53,30 +90,245 @@ impl GameManager {
                     ));
                 }
                 fs::create_dir(&path)?;
-                path
+                // The seed is part of the world, the other overrides are only for this run
+                let mut config = ServerConfig::default();
+                if let Some(seed) = overrides.seed {
+                    config.world.seed = seed;
+                }
+                config.save(&path.join(CONFIG_FILE_NAME))?;
+                (path, config)
             }
         };
+        overrides.apply(&mut config);
+
+        let identity = ServerIdentity::load_or_create(&game_dir)?;
 
         let db = Database::connect(format!(
             "sqlite://{}/db.sqlite?mode=rwc",
-            game_dir.to_string_lossy().to_string()
+            game_dir.to_string_lossy()
         ))
         .await?;
         Migrator::up(&db, None).await?;
 
+        let mut chunk_manager = ChunkManager::new(
+            config.world.seed,
+            config.world.chunk_size,
+            config.world.spawn_radius,
+        )?;
+        chunk_manager.load_saved(&game_dir.join(CHUNKS_DIR_NAME))?;
+
+        let recorder = if config.recording.enabled {
+            let recorder = Recorder::start(&game_dir, &config, new_world)?;
+            println!("Recording sessions to {:?}", recorder.path());
+            Some(recorder)
+        } else {
+            None
+        };
+
         Ok(Arc::new(Self {
-            db: db,
+            db,
             session_manager: SessionManager::new(),
             game_dir,
-            chunk_manager: ChunkManager::new()?,
+            identity,
+            chunk_manager: tokio::sync::RwLock::new(chunk_manager),
+            tick_stats: Mutex::new(TickStats::default()),
+            players: Mutex::new(PlayerManager::default()),
+            recorder,
+            single_player: overrides.single_player,
+            tick_clock: Mutex::new((0, Instant::now())),
+            password_hasher: PasswordHasher::new(
+                config.security.bcrypt_cost,
+                config.security.hashing_threads,
+            ),
+            login_throttle: LoginThrottle::new(&config.security),
+            config,
         }))
     }
 
+    /// The clock clients synchronise to, in microseconds. Tick `n` starts at `n` tick durations,
+    /// so clients can tell from it which snapshot tick is current.
+    pub fn server_time_us(&self) -> u64 {
+        let (tick, started) = match self.tick_clock.lock() {
+            Ok(clock) => *clock,
+            Err(poisoned) => *poisoned.into_inner(),
+        };
+        tick * tick_duration().as_micros() as u64 + started.elapsed().as_micros() as u64
+    }
+
+    /// Called as each tick starts. Server time keeps pace with the ticks, so any the loop skips
+    /// leave it that far behind the wall clock.
+    pub fn start_tick(&self, tick: u64, now: Instant) {
+        if let Ok(mut clock) = self.tick_clock.lock() {
+            *clock = (tick, now);
+        }
+    }
+
+    pub async fn chunk(&self, pos: ChunkPos) -> anyhow::Result<Chunk> {
+        if let Some(chunk) = self.chunk_manager.read().await.chunks.get(&pos) {
+            return Ok(chunk.clone());
+        }
+        Ok(self
+            .chunk_manager
+            .write()
+            .await
+            .get_or_generate(pos)?
+            .clone())
+    }
+
+    /// Places the session's character at the world origin, standing on the ground, and returns
+    /// its entity id.
+    pub async fn spawn_player(
+        &self,
+        session: &Arc<tokio::sync::Mutex<ServerSession>>,
+    ) -> anyhow::Result<u32> {
+        let (addr, connection, character_id) = {
+            let session = session.lock().await;
+            let character_id = session
+                .character_id()
+                .ok_or_else(|| anyhow!("No character selected"))?;
+            (session.addr, session.connection.clone(), character_id)
+        };
+
+        // Characters pick up where they left the world, new ones start at the origin
+        let saved = characters::Entity::find_by_id(character_id)
+            .one(&self.db)
+            .await?
+            .ok_or_else(|| anyhow!("Character {character_id} does not exist"))?;
+        let position = match (saved.position_x, saved.position_y, saved.position_z) {
+            (Some(x), Some(y), Some(z)) => PlayerPos::new(x, y, z),
+            _ => {
+                let height = self.chunk_manager.read().await.height_at(0, 0).unwrap_or(0);
+                PlayerPos::new(0.0, 0.0, height as f32)
+            }
+        };
+        self.players
+            .lock()
+            .map(|mut players| {
+                // Recorded as the player spawns rather than when asked, so a replay spawns it
+                // before the same tick
+                if let Some(recorder) = &self.recorder {
+                    recorder.control(addr, &ClientControlStreamMessage::JoinWorldRequest);
+                }
+                players.spawn(addr, connection, character_id, position)
+            })
+            .map_err(|_| anyhow!("Player list is poisoned"))
+    }
+
+    /// The part of a requested view a client may have: no wider than the configured radius, and
+    /// centered no further than that from where its player really is. `None` outside the world.
+    pub fn allowed_view(&self, addr: SocketAddr, requested: ChunkView) -> Option<ChunkView> {
+        let radius = self.config.world.view_radius;
+        let (x, y) = self.players.lock().ok()?.get(&addr)?.position.tile();
+        let player_chunk = ChunkPos::from_tile(x, y, self.config.world.chunk_size);
+        let reach = radius as i64;
+        let center = ChunkPos::new(
+            requested
+                .center
+                .x
+                .clamp(player_chunk.x - reach, player_chunk.x + reach),
+            requested
+                .center
+                .y
+                .clamp(player_chunk.y - reach, player_chunk.y + reach),
+        );
+        Some(ChunkView::new(center, requested.radius.min(radius)))
+    }
+
+    /// Takes the player at `addr` out of the world, saving where its character was.
+    pub async fn leave_world(&self, addr: SocketAddr) -> Option<PlayerState> {
+        let player = match self.players.lock() {
+            Ok(mut players) => {
+                if let Some(recorder) = &self.recorder {
+                    recorder.disconnected(addr);
+                }
+                players.remove(&addr)?
+            }
+            Err(_) => return None,
+        };
+        if let Err(e) = self
+            .save_position(player.character_id, player.position)
+            .await
+        {
+            eprintln!("Could not save character {}: {e}", player.character_id);
+        }
+        Some(player)
+    }
+
+    async fn save_position(&self, character_id: i64, position: PlayerPos) -> anyhow::Result<()> {
+        characters::ActiveModel {
+            character_id: Unchanged(character_id),
+            position_x: Set(Some(position.x)),
+            position_y: Set(Some(position.y)),
+            position_z: Set(Some(position.z)),
+            ..Default::default()
+        }
+        .update(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Saves where every character in the world is and the chunks changed since the last save.
+    /// Returns the number of chunks written.
+    pub async fn save_world(&self) -> anyhow::Result<usize> {
+        let positions: Vec<(i64, PlayerPos)> = self
+            .players
+            .lock()
+            .map_err(|_| anyhow!("Player list is poisoned"))?
+            .iter()
+            .map(|(_, player)| (player.character_id, player.position))
+            .collect();
+        for (character_id, position) in positions {
+            self.save_position(character_id, position).await?;
+        }
+
+        self.chunk_manager
+            .write()
+            .await
+            .save_dirty(&self.game_dir.join(CHUNKS_DIR_NAME))
+    }
+
+    pub async fn kick(&self, username: &str, reason: &str) -> usize {
+        let sessions = self.session_manager.find_by_username(username).await;
+        for session in &sessions {
+            session.lock().await.disconnect(reason, CloseCode::Kicked);
+        }
+        sessions.len()
+    }
+
+    pub async fn ban(&self, username: &str) -> anyhow::Result<usize> {
+        let ban = bans::ActiveModel {
+            username: Set(username.to_string()),
+            created_at: Set(chrono::Utc::now().into()),
+        };
+        bans::Entity::insert(ban)
+            .on_conflict(
+                OnConflict::column(bans::Column::Username)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .do_nothing()
+            .exec(&self.db)
+            .await?;
+
+        Ok(self.kick(username, "Banned from this server").await)
+    }
+
+    async fn is_banned(&self, username: &str) -> anyhow::Result<bool> {
+        Ok(bans::Entity::find_by_id(username.to_string())
+            .one(&self.db)
+            .await?
+            .is_some())
+    }
+
     pub async fn create_character(
         &self,
-        username: String,
         character_name: String,
+        session: Arc<tokio::sync::Mutex<ServerSession>>,
     ) -> ServerControlStreamMessage {
+        let Some(username) = session.lock().await.username().map(str::to_string) else {
+            return ServerControlStreamMessage::CharacterDenied("Not logged in".into());
+        };
+
         match accounts::Entity::find_by_id(username.clone())
             .one(&self.db)
             .await
@@ -102,7 +354,10 @@ impl GameManager {
         };
 
         match character.insert(&self.db).await {
-            Ok(model) => ServerControlStreamMessage::CharacterSelected,
+            Ok(model) => {
+                session.lock().await.select_character(model.character_id);
+                ServerControlStreamMessage::CharacterSelected
+            }
             Err(e) => ServerControlStreamMessage::CharacterDenied(format!(
                 "Failed to create character: {e}"
             )),
@@ -111,9 +366,13 @@ impl GameManager {
 
     pub async fn select_character(
         &self,
-        username: String,
         character_id: i64,
+        session: Arc<tokio::sync::Mutex<ServerSession>>,
     ) -> ServerControlStreamMessage {
+        let Some(username) = session.lock().await.username().map(str::to_string) else {
+            return ServerControlStreamMessage::CharacterDenied("Not logged in".into());
+        };
+
         match characters::Entity::find()
             .filter(characters::Column::AccountUsername.eq(username.clone()))
             .filter(characters::Column::CharacterId.eq(character_id.clone()))
@@ -121,6 +380,7 @@ impl GameManager {
             .await
         {
             Ok(Some(model)) => {
+                session.lock().await.select_character(model.character_id);
                 return ServerControlStreamMessage::CharacterSelected;
             }
             Ok(None) => {
@@ -136,6 +396,110 @@ impl GameManager {
         ServerControlStreamMessage::CharacterDenied("An unexpected error occured".into())
     }
 
+    pub async fn handshake(
+        &self,
+        hello: ClientHello,
+        session: Arc<tokio::sync::Mutex<ServerSession>>,
+    ) -> ServerControlStreamMessage {
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return ServerControlStreamMessage::IncompatibleVersion {
+                server_version: PROTOCOL_VERSION,
+                server_build: BUILD_ID.into(),
+                client_version: hello.protocol_version,
+                client_build: hello.build_id,
+            };
+        }
+
+        let features: Vec<String> = hello
+            .features
+            .into_iter()
+            .filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str()))
+            .collect();
+        session.lock().await.complete_handshake(features.clone());
+
+        ServerControlStreamMessage::Connected(ServerHello {
+            protocol_version: PROTOCOL_VERSION,
+            build_id: BUILD_ID.into(),
+            features,
+        })
+    }
+
+    /// Whether a client connecting from `addr` is the server's master or an ordinary player.
+    pub fn connection_role(&self, addr: SocketAddr) -> ConnectionRole {
+        if self.single_player && addr.ip().is_loopback() {
+            ConnectionRole::LocalMaster
+        } else {
+            ConnectionRole::RemoteClient
+        }
+    }
+
+    async fn authenticate(
+        &self,
+        session: &Arc<tokio::sync::Mutex<ServerSession>>,
+        username: &str,
+    ) -> Result<String, String> {
+        // Checked again while claiming the login, logins racing past `check_capacity` are common
+        let max_players = self.player_cap(session).await;
+        let resume_token = self
+            .session_manager
+            .authenticate(
+                session,
+                username,
+                self.config.players.duplicate_login,
+                max_players,
+            )
+            .await?;
+        if let Some(recorder) = &self.recorder {
+            recorder.token_issued(session.lock().await.addr, &resume_token);
+        }
+        Ok(resume_token)
+    }
+
+    /// The player cap `session` counts against, the master is exempt.
+    async fn player_cap(&self, session: &Arc<tokio::sync::Mutex<ServerSession>>) -> Option<usize> {
+        match session.lock().await.role {
+            ConnectionRole::LocalMaster => None,
+            ConnectionRole::RemoteClient => Some(self.config.players.max_players),
+        }
+    }
+
+    async fn check_admission(
+        &self,
+        server_password: Option<&str>,
+        session: &Arc<tokio::sync::Mutex<ServerSession>>,
+    ) -> Result<(), String> {
+        let (role, addr) = {
+            let session = session.lock().await;
+            (session.role, session.addr)
+        };
+        if role == ConnectionRole::LocalMaster {
+            return Ok(());
+        }
+
+        if let Some(expected) = &self.config.players.server_password {
+            match server_password {
+                None => return Err("Server password required".into()),
+                Some(password) if password != expected.as_str() => {
+                    return Err("Incorrect server password".into());
+                }
+                Some(_) => {}
+            }
+        }
+
+        self.check_capacity(addr).await
+    }
+
+    async fn check_capacity(&self, addr: SocketAddr) -> Result<(), String> {
+        let max_players = self.config.players.max_players;
+        if self.session_manager.authenticated_count(addr).await >= max_players {
+            return Err(format!(
+                "Server is full ({max_players}/{max_players} players)"
+            ));
+        }
+
+        Ok(())
+    }
+
     pub async fn create(
         &self,
         credentials: AccountCredentials,
@@ -144,32 +508,54 @@ impl GameManager {
         let AccountCredentials {
             username,
             user_password,
-            ..
+            server_password,
         } = credentials;
 
+        if let Err(msg) = self
+            .check_admission(server_password.as_deref(), &session)
+            .await
+        {
+            return ServerControlStreamMessage::AccountCreateDenied(msg);
+        }
+
+        // A name can be banned before anyone has registered it
+        match self.is_banned(&username).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return ServerControlStreamMessage::AccountCreateDenied(
+                    "Account is banned from this server".to_string(),
+                );
+            }
+            Err(e) => {
+                return ServerControlStreamMessage::AccountCreateDenied(format!(
+                    "Database error: {e}"
+                ));
+            }
+        }
+
         match accounts::Entity::find_by_id(username.clone())
             .one(&self.db)
             .await
         {
             Ok(Some(_)) => {
-                let msg = "Account already exists".to_string();
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                return ServerControlStreamMessage::AccountCreateDenied(msg);
+                return ServerControlStreamMessage::AccountCreateDenied(
+                    "Account already exists".to_string(),
+                );
             }
             Ok(None) => {}
             Err(e) => {
-                let msg = format!("Database error: {e}");
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                return ServerControlStreamMessage::AccountCreateDenied(msg);
+                return ServerControlStreamMessage::AccountCreateDenied(format!(
+                    "Database error: {e}"
+                ));
             }
         }
 
-        let password_hash = match hash(&user_password, bcrypt::DEFAULT_COST) {
+        let password_hash = match self.password_hasher.hash(user_password).await {
             Ok(h) => h,
             Err(e) => {
-                let msg = format!("Password hash failed: {e}");
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                return ServerControlStreamMessage::AccountCreateDenied(msg);
+                return ServerControlStreamMessage::AccountCreateDenied(format!(
+                    "Password hash failed: {e}"
+                ));
             }
         };
 
@@ -180,20 +566,29 @@ impl GameManager {
         };
 
         match account.insert(&self.db).await {
-            Ok(_) => {
-                session.lock().await.auth = AuthState::Authenticated {
-                    username: username.clone(),
-                };
-                ServerControlStreamMessage::Authenticated(AccountInfo {
-                    username,
-                    characters: vec![],
-                })
-            }
-            Err(e) => {
-                let msg = format!("Failed to create account: {e}");
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                ServerControlStreamMessage::AccountCreateDenied(msg)
-            }
+            Ok(_) => match self.authenticate(&session, &username).await {
+                Ok(resume_token) => ServerControlStreamMessage::Authenticated {
+                    account: AccountInfo {
+                        username,
+                        characters: vec![],
+                    },
+                    resume_token,
+                },
+                Err(msg) => {
+                    // The server filled up or the name got taken while the row went in, drop it
+                    // again so the name is free to create once there is room
+                    if let Err(e) = accounts::Entity::delete_by_id(username.clone())
+                        .exec(&self.db)
+                        .await
+                    {
+                        eprintln!("Could not remove refused account {username}: {e}");
+                    }
+                    ServerControlStreamMessage::AccountCreateDenied(msg)
+                }
+            },
+            Err(e) => ServerControlStreamMessage::AccountCreateDenied(format!(
+                "Failed to create account: {e}"
+            )),
         }
     }
 
@@ -205,52 +600,161 @@ impl GameManager {
         let AccountCredentials {
             username,
             user_password,
-            ..
+            server_password,
         } = credentials;
+
+        if let Err(msg) = self
+            .check_admission(server_password.as_deref(), &session)
+            .await
+        {
+            return ServerControlStreamMessage::LoginDenied(msg);
+        }
+
+        let ip = session.lock().await.addr.ip();
+        let attempt = match self.login_throttle.begin(ip, &username) {
+            Ok(attempt) => attempt,
+            Err(remaining) => {
+                return ServerControlStreamMessage::LoginDenied(format!(
+                    "Too many failed login attempts, try again in {} seconds",
+                    remaining.as_secs() + 1
+                ));
+            }
+        };
+
         let account = match accounts::Entity::find_by_id(username.clone())
             .one(&self.db)
             .await
         {
             Ok(Some(acc)) => acc,
             Ok(None) => {
-                let msg = "Account does not exist".to_string();
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                return ServerControlStreamMessage::LoginDenied(msg);
+                attempt.failed();
+                return ServerControlStreamMessage::LoginDenied(
+                    "Account does not exist".to_string(),
+                );
             }
             Err(e) => {
-                let msg = format!("Database error: {e}");
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                return ServerControlStreamMessage::LoginDenied(msg);
+                return ServerControlStreamMessage::LoginDenied(format!("Database error: {e}"));
             }
         };
 
-        match verify(&user_password, &account.password_hash) {
-            Ok(true) => match account.find_related(characters::Entity).all(&self.db).await {
-                Ok(characters) => {
-                    session.lock().await.auth = AuthState::Authenticated {
-                        username: username.clone(),
-                    };
-                    ServerControlStreamMessage::Authenticated(AccountInfo {
-                        username,
-                        characters: characters,
-                    })
-                }
-                Err(e) => {
-                    let msg = format!("Could not load account characters: {e}");
-                    session.lock().await.auth = AuthState::Rejected(msg.clone());
-                    ServerControlStreamMessage::LoginDenied(msg)
-                }
-            },
+        match self
+            .password_hasher
+            .verify(user_password, account.password_hash.clone())
+            .await
+        {
+            Ok(true) => {}
             Ok(false) => {
-                let msg = "Invalid Password".to_string();
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                ServerControlStreamMessage::LoginDenied(msg)
+                attempt.failed();
+                return ServerControlStreamMessage::LoginDenied("Invalid Password".to_string());
+            }
+            Err(e) => {
+                return ServerControlStreamMessage::LoginDenied(format!(
+                    "Password verification failed: {e}"
+                ));
+            }
+        }
+
+        // Only after the password, so a failed login doesn't give away that the account is banned
+        match self.is_banned(&username).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return ServerControlStreamMessage::LoginDenied(
+                    "Account is banned from this server".to_string(),
+                );
+            }
+            Err(e) => {
+                return ServerControlStreamMessage::LoginDenied(format!("Database error: {e}"));
+            }
+        }
+
+        match account.find_related(characters::Entity).all(&self.db).await {
+            Ok(characters) => {
+                attempt.succeeded();
+                match self.authenticate(&session, &username).await {
+                    Ok(resume_token) => ServerControlStreamMessage::Authenticated {
+                        account: AccountInfo {
+                            username,
+                            characters,
+                        },
+                        resume_token,
+                    },
+                    Err(msg) => ServerControlStreamMessage::LoginDenied(msg),
+                }
+            }
+            Err(e) => ServerControlStreamMessage::LoginDenied(format!(
+                "Could not load account characters: {e}"
+            )),
+        }
+    }
+
+    /// Restores a login that dropped within the resume grace period without asking for the
+    /// password again. The token is single use, a fresh one is handed back on success.
+    pub async fn resume(
+        &self,
+        token: String,
+        session: Arc<tokio::sync::Mutex<ServerSession>>,
+    ) -> ServerControlStreamMessage {
+        // Only looked up for now, the token is used up once the login is claimed
+        let Some(detached) = self.session_manager.resumable(&token).await else {
+            return ServerControlStreamMessage::ResumeDenied(
+                "Session expired, please log in again".into(),
+            );
+        };
+
+        match self.is_banned(&detached.username).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return ServerControlStreamMessage::ResumeDenied(
+                    "Account is banned from this server".into(),
+                );
             }
             Err(e) => {
-                let msg = format!("Password verification failed: {e}");
-                session.lock().await.auth = AuthState::Rejected(msg.clone());
-                ServerControlStreamMessage::LoginDenied(msg)
+                return ServerControlStreamMessage::ResumeDenied(format!("Database error: {e}"));
+            }
+        }
+
+        let characters = match characters::Entity::find()
+            .filter(characters::Column::AccountUsername.eq(detached.username.clone()))
+            .all(&self.db)
+            .await
+        {
+            Ok(characters) => characters,
+            Err(e) => {
+                return ServerControlStreamMessage::ResumeDenied(format!(
+                    "Could not load account characters: {e}"
+                ));
             }
+        };
+
+        // The player cap is checked while claiming, where the connection being resumed is left out
+        let max_players = self.player_cap(&session).await;
+        let (detached, resume_token) = match self
+            .session_manager
+            .resume(
+                &session,
+                &token,
+                self.config.players.duplicate_login,
+                max_players,
+            )
+            .await
+        {
+            Ok(resumed) => resumed,
+            Err(msg) => return ServerControlStreamMessage::ResumeDenied(msg),
+        };
+        if let Some(recorder) = &self.recorder {
+            recorder.token_issued(session.lock().await.addr, &resume_token);
+        }
+        if let Some(character_id) = detached.character_id {
+            session.lock().await.select_character(character_id);
+        }
+
+        ServerControlStreamMessage::Resumed {
+            account: AccountInfo {
+                username: detached.username,
+                characters,
+            },
+            character_id: detached.character_id,
+            resume_token,
         }
     }
 }
@@ -2,4 +2,6 @@ mod session;
 pub use session::*;
 
 mod game_state;
-pub use game_state::*;
\ No newline at end of file
+pub use game_state::*;
+mod players;
+pub use players::*;
@@ -0,0 +1,241 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use quinn::Connection;
+use shared::{
+    Animation, EntityState, PlayerInput, PlayerPos, SERVER_DATAGRAM_OVERHEAD, ServerDatagram,
+    SnapshotEncoder, WALK_SPEED, apply_input, encode_datagram, input_duration,
+};
+
+/// Unspent movement time a player may bank, so inputs arriving in a burst after a hiccup still
+/// apply in full while a client sending inputs faster than real time gets nowhere.
+const MAX_MOVEMENT_BUDGET: Duration = Duration::from_millis(250);
+/// Inputs beyond this many per tick are dropped instead of queued.
+const MAX_INBOX_INPUTS: usize = 64;
+
+pub struct PlayerState {
+    pub entity_id: u32,
+    pub character_id: i64,
+    pub position: PlayerPos,
+    /// Tiles per second over the last tick
+    pub velocity: [f32; 2],
+    pub facing: f32,
+    /// The newest input already applied to `position`
+    pub last_input_id: Option<u32>,
+    pub rejected_inputs: u64,
+    last_client_time_ms: Option<u64>,
+    budget: Duration,
+    inbox: Vec<PlayerInput>,
+    pending: VecDeque<PlayerInput>,
+    /// `None` for replayed players, who get no snapshots
+    connection: Option<Connection>,
+    snapshots: SnapshotEncoder,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl PlayerState {
+    fn new(
+        entity_id: u32,
+        character_id: i64,
+        position: PlayerPos,
+        connection: Option<Connection>,
+    ) -> Self {
+        Self {
+            entity_id,
+            character_id,
+            position,
+            velocity: [0.0; 2],
+            facing: 0.0,
+            last_input_id: None,
+            rejected_inputs: 0,
+            last_client_time_ms: None,
+            budget: Duration::ZERO,
+            inbox: vec![],
+            pending: VecDeque::new(),
+            connection,
+            snapshots: SnapshotEncoder::new(),
+            outgoing: vec![],
+        }
+    }
+
+    pub fn entity_state(&self) -> EntityState {
+        let [x, y] = self.velocity;
+        let speed = (x * x + y * y).sqrt();
+        let animation = if speed == 0.0 {
+            Animation::Idle
+        } else if speed <= WALK_SPEED * 1.05 {
+            Animation::Walking
+        } else {
+            Animation::Running
+        };
+
+        EntityState {
+            entity_id: self.entity_id,
+            position: self.position,
+            velocity: self.velocity,
+            facing: self.facing,
+            animation,
+        }
+    }
+
+    fn newest_queued_id(&self) -> Option<u32> {
+        self.pending
+            .back()
+            .map(|input| input.input_id)
+            .or(self.last_input_id)
+    }
+}
+
+/// Authoritative state of every session that has joined the world, keyed by connection.
+#[derive(Default)]
+pub struct PlayerManager {
+    players: HashMap<SocketAddr, PlayerState>,
+    next_entity_id: u32,
+}
+
+impl PlayerManager {
+    /// Adds the player to the world and returns the entity id snapshots will know it by.
+    pub fn spawn(
+        &mut self,
+        addr: SocketAddr,
+        connection: Option<Connection>,
+        character_id: i64,
+        position: PlayerPos,
+    ) -> u32 {
+        let entity_id = self.next_entity_id;
+        self.next_entity_id = self.next_entity_id.wrapping_add(1);
+        self.players.insert(
+            addr,
+            PlayerState::new(entity_id, character_id, position, connection),
+        );
+        entity_id
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<PlayerState> {
+        self.players.remove(addr)
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PlayerState> {
+        self.players.get(addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &PlayerState)> {
+        self.players.iter()
+    }
+
+    /// Holds an input until the next tick collects it. Inputs for sessions outside the world are
+    /// ignored.
+    pub fn receive_input(&mut self, addr: SocketAddr, input: PlayerInput) {
+        if let Some(player) = self.players.get_mut(&addr)
+            && player.inbox.len() < MAX_INBOX_INPUTS
+        {
+            player.inbox.push(input);
+        }
+    }
+
+    pub fn acknowledge_snapshot(&mut self, addr: SocketAddr, tick: u32) {
+        if let Some(player) = self.players.get_mut(&addr) {
+            player.snapshots.acknowledge(tick);
+        }
+    }
+
+    /// Queues the inputs received since the last tick in order, dropping invalid ones along with
+    /// duplicates and anything older than what has already been queued.
+    pub fn collect_inputs(&mut self) {
+        for player in self.players.values_mut() {
+            let mut inbox = std::mem::take(&mut player.inbox);
+            inbox.sort_by_key(|input| input.input_id);
+
+            for input in inbox {
+                if !input.is_valid() {
+                    player.rejected_inputs += 1;
+                    continue;
+                }
+                if player
+                    .newest_queued_id()
+                    .is_some_and(|newest| input.input_id <= newest)
+                {
+                    continue;
+                }
+                player.pending.push_back(input);
+            }
+        }
+    }
+
+    /// Applies queued inputs, each covering the client time since the one before it but never
+    /// more time than the player has actually had since joining.
+    pub fn process_inputs(&mut self, tick: Duration, height_at: impl Fn(i64, i64) -> Option<i64>) {
+        for player in self.players.values_mut() {
+            player.budget = (player.budget + tick).min(MAX_MOVEMENT_BUDGET);
+            let start = player.position;
+
+            while !player.budget.is_zero()
+                && let Some(input) = player.pending.pop_front()
+            {
+                let dt = input_duration(player.last_client_time_ms, &input).min(player.budget);
+
+                player.position = apply_input(player.position, &input, dt, &height_at);
+                player.budget -= dt;
+                player.last_input_id = Some(input.input_id);
+                player.last_client_time_ms = Some(input.client_time_ms);
+                if input.movement != [0.0; 2] {
+                    player.facing = input.movement[1].atan2(input.movement[0]);
+                }
+            }
+
+            let seconds = tick.as_secs_f32();
+            player.velocity = [
+                (player.position.x - start.x) / seconds,
+                (player.position.y - start.y) / seconds,
+            ];
+        }
+    }
+
+    /// Encodes this tick's snapshot for every player, sized to fit their connection's datagrams.
+    pub fn build_snapshots(&mut self, tick: u32) {
+        let entities: Vec<EntityState> = self
+            .players
+            .values()
+            .map(PlayerState::entity_state)
+            .collect();
+
+        for player in self.players.values_mut() {
+            // Peers that cannot take datagrams get no snapshots
+            let Some(max_size) = player
+                .connection
+                .as_ref()
+                .and_then(Connection::max_datagram_size)
+            else {
+                continue;
+            };
+            player.outgoing = player.snapshots.encode(
+                tick,
+                player.last_input_id,
+                &entities,
+                max_size.saturating_sub(SERVER_DATAGRAM_OVERHEAD),
+            );
+        }
+    }
+
+    pub fn send_snapshots(&mut self) {
+        for player in self.players.values_mut() {
+            let Some(connection) = &player.connection else {
+                continue;
+            };
+            for part in player.outgoing.drain(..) {
+                let sent = encode_datagram(&ServerDatagram::Snapshot(part))
+                    .and_then(|bytes| Ok(connection.send_datagram(bytes)?));
+                if let Err(e) = sent {
+                    eprintln!(
+                        "Could not send snapshot to entity {}: {e}",
+                        player.entity_id
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,25 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use dashmap::DashMap;
+use quinn::Connection;
+use shared::{
+    ChunkView, ClockSync, CloseCode, ServerChatMessage, ServerControlStreamMessage, SessionPhase,
+};
+use tokio::sync::{mpsc::UnboundedSender, watch};
+
+use crate::config::DuplicateLoginPolicy;
+
+const LOGGED_IN_ELSEWHERE: &str = "Logged in elsewhere";
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Message(ServerControlStreamMessage),
+    Disconnect { reason: String, code: CloseCode },
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConnectionRole {
@@ -8,49 +27,495 @@ pub enum ConnectionRole {
     RemoteClient,
 }
 
-#[derive(Debug, Clone)]
-pub enum AuthState {
-    WaitingForCredentials,
-    Authenticated { username: String },
-    Rejected(String),
-}
-
 #[derive(Debug, Clone)]
 pub struct ServerSession {
     pub role: ConnectionRole,
-    pub auth: AuthState,
+    phase: SessionPhase,
+    username: Option<String>,
+    character_id: Option<i64>,
+    pub features: Vec<String>,
+    pub chunk_view: Option<watch::Sender<ChunkView>>,
+    pub chat: Option<UnboundedSender<ServerChatMessage>>,
+    /// Round trip time and clock offset to the client, from the server's pings
+    pub clock: ClockSync,
+    pub resume_token: Option<String>,
     pub addr: SocketAddr,
-    pub control_stream_opened: bool,
+    /// `None` for sessions replayed from a recording
+    pub connection: Option<Connection>,
+    pub outbound: UnboundedSender<SessionEvent>,
 }
 
 impl ServerSession {
-    pub fn new(addr: SocketAddr) -> Self {
-        let role = if addr.ip().is_loopback() {
-            ConnectionRole::LocalMaster
-        } else {
-            ConnectionRole::RemoteClient
-        };
+    pub fn new(
+        connection: Connection,
+        role: ConnectionRole,
+        outbound: UnboundedSender<SessionEvent>,
+    ) -> Self {
+        Self {
+            role,
+            phase: SessionPhase::Handshaking,
+            username: None,
+            character_id: None,
+            features: vec![],
+            chunk_view: None,
+            chat: None,
+            clock: ClockSync::default(),
+            resume_token: None,
+            addr: connection.remote_address(),
+            connection: Some(connection),
+            outbound,
+        }
+    }
 
+    /// A session driven by a replay instead of a client, so it has no connection.
+    pub fn replayed(
+        addr: SocketAddr,
+        role: ConnectionRole,
+        outbound: UnboundedSender<SessionEvent>,
+    ) -> Self {
         Self {
             role,
-            auth: AuthState::WaitingForCredentials,
+            phase: SessionPhase::Handshaking,
+            username: None,
+            character_id: None,
+            features: vec![],
+            chunk_view: None,
+            chat: None,
+            clock: ClockSync::default(),
+            resume_token: None,
             addr,
-            control_stream_opened: false,
+            connection: None,
+            outbound,
         }
     }
+
+    pub fn phase(&self) -> SessionPhase {
+        self.phase
+    }
+
     pub fn is_authed(&self) -> bool {
-        matches!(self.auth, AuthState::Authenticated { .. })
+        !matches!(
+            self.phase,
+            SessionPhase::Handshaking | SessionPhase::Connected
+        )
+    }
+
+    pub fn complete_handshake(&mut self, features: Vec<String>) {
+        self.phase = SessionPhase::Connected;
+        self.features = features;
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn character_id(&self) -> Option<i64> {
+        self.character_id
+    }
+
+    pub fn authenticate(&mut self, username: String) {
+        self.phase = SessionPhase::Authenticated;
+        self.username = Some(username);
+        self.character_id = None;
+    }
+
+    pub fn select_character(&mut self, character_id: i64) {
+        self.phase = SessionPhase::CharacterSelected;
+        self.character_id = Some(character_id);
+    }
+
+    pub fn enter_world(&mut self) {
+        self.phase = SessionPhase::InWorld;
+    }
+
+    /// Forgets the login entirely, the connection has to authenticate again to do anything.
+    pub fn reset(&mut self) {
+        self.phase = SessionPhase::Connected;
+        self.username = None;
+        self.character_id = None;
+        self.chunk_view = None;
+        self.resume_token = None;
+    }
+
+    pub fn send(&self, message: ServerControlStreamMessage) {
+        if let Err(e) = self.outbound.send(SessionEvent::Message(message)) {
+            eprintln!("Could not queue message for {}: {e}", self.addr);
+        }
+    }
+
+    pub fn disconnect(&self, reason: &str, code: CloseCode) {
+        let event = SessionEvent::Disconnect {
+            reason: reason.into(),
+            code,
+        };
+        if self.outbound.send(event).is_err()
+            && let Some(connection) = &self.connection
+        {
+            connection.close(code.code(), reason.as_bytes());
+        }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct DetachedSession {
+    pub username: String,
+    pub character_id: Option<i64>,
+    pub expires_at: Instant,
+}
+
 pub struct SessionManager {
     pub sessions: DashMap<SocketAddr, Arc<tokio::sync::Mutex<ServerSession>>>,
+    pub detached: DashMap<String, DetachedSession>,
+    claim_lock: tokio::sync::Mutex<()>,
 }
 
 impl SessionManager {
     pub fn new() -> Arc<Self> {
         Arc::new(Self {
             sessions: DashMap::new(),
+            detached: DashMap::new(),
+            claim_lock: tokio::sync::Mutex::new(()),
         })
     }
+
+    /// Marks the session as logged in to `username`, making sure no other session holds the
+    /// account afterwards and, given `max_players`, that the server is not full. Returns the
+    /// session's new resume token.
+    pub async fn authenticate(
+        &self,
+        session: &Arc<tokio::sync::Mutex<ServerSession>>,
+        username: &str,
+        policy: DuplicateLoginPolicy,
+        max_players: Option<usize>,
+    ) -> Result<String, String> {
+        // Serialised so two logins racing for one account or the last slot cannot both get through
+        let _claim = self.claim_lock.lock().await;
+        self.claim(session, username, policy, max_players, None)
+            .await
+    }
+
+    /// Logs the session back in to the login behind a resume token, as `authenticate` does. The
+    /// token is only used up once that succeeds, and a live session still holding it is logged
+    /// out and disconnected there and then.
+    pub async fn resume(
+        &self,
+        session: &Arc<tokio::sync::Mutex<ServerSession>>,
+        token: &str,
+        policy: DuplicateLoginPolicy,
+        max_players: Option<usize>,
+    ) -> Result<(DetachedSession, String), String> {
+        let _claim = self.claim_lock.lock().await;
+        let (detached, replacing) = self
+            .find_resumable(token)
+            .await
+            .ok_or_else(|| "Session expired, please log in again".to_string())?;
+
+        // Claiming drops the account's detached logins, the token's among them
+        let resume_token = self
+            .claim(
+                session,
+                &detached.username,
+                policy,
+                max_players,
+                replacing.as_ref(),
+            )
+            .await?;
+        if let Some(replacing) = replacing {
+            let mut replacing = replacing.lock().await;
+            replacing.reset();
+            replacing.disconnect("Session resumed elsewhere", CloseCode::SessionResumed);
+        }
+        Ok((detached, resume_token))
+    }
+
+    /// Looks up the login behind a resume token without using the token up.
+    pub async fn resumable(&self, token: &str) -> Option<DetachedSession> {
+        self.find_resumable(token)
+            .await
+            .map(|(detached, _)| detached)
+    }
+
+    /// `authenticate` without the claim lock, which the caller holds. `replacing` is a session
+    /// being resumed, which neither counts as a duplicate login nor against the cap.
+    async fn claim(
+        &self,
+        session: &Arc<tokio::sync::Mutex<ServerSession>>,
+        username: &str,
+        policy: DuplicateLoginPolicy,
+        max_players: Option<usize>,
+        replacing: Option<&Arc<tokio::sync::Mutex<ServerSession>>>,
+    ) -> Result<String, String> {
+        if let Some(max_players) = max_players
+            && self.count_others(session, username, replacing).await >= max_players
+        {
+            return Err(format!(
+                "Server is full ({max_players}/{max_players} players)"
+            ));
+        }
+
+        for existing in self.find_by_username(username).await {
+            if Arc::ptr_eq(&existing, session)
+                || replacing.is_some_and(|replacing| Arc::ptr_eq(&existing, replacing))
+            {
+                continue;
+            }
+            match policy {
+                DuplicateLoginPolicy::Reject => {
+                    return Err("Account is already logged in".into());
+                }
+                DuplicateLoginPolicy::KickExisting => {
+                    let mut existing = existing.lock().await;
+                    existing.reset();
+                    existing.disconnect(LOGGED_IN_ELSEWHERE, CloseCode::LoggedInElsewhere);
+                }
+            }
+        }
+        self.detached
+            .retain(|_, detached| detached.username != username);
+
+        let resume_token = hex::encode(rand::random::<[u8; 32]>());
+        let mut session = session.lock().await;
+        session.authenticate(username.to_string());
+        session.resume_token = Some(resume_token.clone());
+        Ok(resume_token)
+    }
+
+    /// Drops the session from the live map, keeping its login around for `grace` if it can be resumed.
+    pub async fn remove(&self, session: &Arc<tokio::sync::Mutex<ServerSession>>, grace: Duration) {
+        let (addr, detached) = {
+            let mut session = session.lock().await;
+            let detached = match (session.resume_token.take(), session.username()) {
+                (Some(token), Some(username)) => Some((
+                    token,
+                    DetachedSession {
+                        username: username.to_string(),
+                        character_id: session.character_id(),
+                        expires_at: Instant::now() + grace,
+                    },
+                )),
+                _ => None,
+            };
+            (session.addr, detached)
+        };
+
+        self.sessions
+            .remove_if(&addr, |_, existing| Arc::ptr_eq(existing, session));
+
+        let now = Instant::now();
+        self.detached
+            .retain(|_, detached| detached.expires_at > now);
+        if let Some((token, detached)) = detached {
+            self.detached.insert(token, detached);
+        }
+    }
+
+    /// The login behind a resume token, along with the live session still holding it if the
+    /// server has not noticed that connection dropping yet.
+    async fn find_resumable(
+        &self,
+        token: &str,
+    ) -> Option<(
+        DetachedSession,
+        Option<Arc<tokio::sync::Mutex<ServerSession>>>,
+    )> {
+        if let Some(detached) = self.detached.get(token).map(|entry| entry.clone()) {
+            return (detached.expires_at > Instant::now()).then_some((detached, None));
+        }
+
+        for live in self.all() {
+            let session = live.lock().await;
+            if session.resume_token.as_deref() == Some(token) {
+                let detached = DetachedSession {
+                    username: session.username()?.to_string(),
+                    character_id: session.character_id(),
+                    expires_at: Instant::now(),
+                };
+                drop(session);
+                return Some((detached, Some(live)));
+            }
+        }
+
+        None
+    }
+
+    pub fn all(&self) -> Vec<Arc<tokio::sync::Mutex<ServerSession>>> {
+        self.sessions
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    pub async fn find_by_username(
+        &self,
+        username: &str,
+    ) -> Vec<Arc<tokio::sync::Mutex<ServerSession>>> {
+        let mut found = vec![];
+        for session in self.all() {
+            if session.lock().await.username() == Some(username) {
+                found.push(session);
+            }
+        }
+        found
+    }
+
+    pub async fn broadcast(&self, message: ServerControlStreamMessage) {
+        for session in self.all() {
+            session.lock().await.send(message.clone());
+        }
+    }
+
+    /// Sends to every logged in session that has a chat stream open.
+    pub async fn broadcast_chat(&self, message: ServerChatMessage) {
+        for session in self.all() {
+            let session = session.lock().await;
+            if let Some(chat) = &session.chat
+                && session.is_authed()
+            {
+                let _ = chat.send(message.clone());
+            }
+        }
+    }
+
+    /// Logged in sessions other than `session` and `replacing`, leaving out any `username` is about
+    /// to take over.
+    async fn count_others(
+        &self,
+        session: &Arc<tokio::sync::Mutex<ServerSession>>,
+        username: &str,
+        replacing: Option<&Arc<tokio::sync::Mutex<ServerSession>>>,
+    ) -> usize {
+        let mut count = 0;
+        for other in self.all() {
+            if Arc::ptr_eq(&other, session)
+                || replacing.is_some_and(|replacing| Arc::ptr_eq(&other, replacing))
+            {
+                continue;
+            }
+            let other = other.lock().await;
+            if other.is_authed() && other.username() != Some(username) {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub async fn authenticated_count(&self, exclude: SocketAddr) -> usize {
+        let sessions: Vec<Arc<tokio::sync::Mutex<ServerSession>>> = self
+            .sessions
+            .iter()
+            .filter(|entry| *entry.key() != exclude)
+            .map(|entry| entry.value().clone())
+            .collect();
+
+        let mut count = 0;
+        for session in sessions {
+            if session.lock().await.is_authed() {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use super::*;
+
+    /// A session registered with `manager` as if a client had connected on `port`.
+    fn connect(
+        manager: &SessionManager,
+        port: u16,
+    ) -> (
+        Arc<tokio::sync::Mutex<ServerSession>>,
+        UnboundedReceiver<SessionEvent>,
+    ) {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
+        let session = Arc::new(tokio::sync::Mutex::new(ServerSession::replayed(
+            addr,
+            ConnectionRole::RemoteClient,
+            outbound_tx,
+        )));
+        manager.sessions.insert(addr, session.clone());
+        (session, outbound_rx)
+    }
+
+    #[tokio::test]
+    async fn resume_takes_over_a_session_that_is_still_registered() {
+        let manager = SessionManager::new();
+        let (old, mut old_rx) = connect(&manager, 1);
+        let token = manager
+            .authenticate(&old, "alice", DuplicateLoginPolicy::Reject, Some(1))
+            .await
+            .unwrap();
+
+        // The old connection dropped but the server has not noticed yet
+        let (new, _new_rx) = connect(&manager, 2);
+        let (detached, _) = manager
+            .resume(&new, &token, DuplicateLoginPolicy::Reject, Some(1))
+            .await
+            .unwrap();
+        assert_eq!(detached.username, "alice");
+        assert_eq!(new.lock().await.username(), Some("alice"));
+
+        // Logged out straight away rather than once its disconnect goes through
+        assert!(!old.lock().await.is_authed());
+        assert!(matches!(
+            old_rx.try_recv(),
+            Ok(SessionEvent::Disconnect {
+                code: CloseCode::SessionResumed,
+                ..
+            })
+        ));
+        assert!(manager.resumable(&token).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn refused_resume_keeps_the_token() {
+        let manager = SessionManager::new();
+        let (old, _old_rx) = connect(&manager, 1);
+        let token = manager
+            .authenticate(&old, "alice", DuplicateLoginPolicy::Reject, None)
+            .await
+            .unwrap();
+        let (bob, _bob_rx) = connect(&manager, 2);
+        manager
+            .authenticate(&bob, "bob", DuplicateLoginPolicy::Reject, None)
+            .await
+            .unwrap();
+
+        let (new, _new_rx) = connect(&manager, 3);
+        let refused = manager
+            .resume(&new, &token, DuplicateLoginPolicy::Reject, Some(1))
+            .await;
+        assert_eq!(refused.unwrap_err(), "Server is full (1/1 players)");
+        assert!(manager.resumable(&token).await.is_some());
+        assert!(old.lock().await.is_authed());
+    }
+
+    #[tokio::test]
+    async fn reject_still_refuses_other_logins_to_a_resumed_account() {
+        let manager = SessionManager::new();
+        let (old, _old_rx) = connect(&manager, 1);
+        let token = manager
+            .authenticate(&old, "alice", DuplicateLoginPolicy::Reject, None)
+            .await
+            .unwrap();
+        let (new, _new_rx) = connect(&manager, 2);
+        manager
+            .resume(&new, &token, DuplicateLoginPolicy::Reject, None)
+            .await
+            .unwrap();
+
+        // Only the session being resumed is let off, not anyone else after the account
+        let (other, _other_rx) = connect(&manager, 3);
+        let refused = manager
+            .authenticate(&other, "alice", DuplicateLoginPolicy::Reject, None)
+            .await;
+        assert_eq!(refused, Err("Account is already logged in".into()));
+    }
 }
@@ -0,0 +1,226 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub use shared::TICK_RATE;
+
+use crate::{state::GameManager, thread_manager::ThreadManager};
+
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
+pub fn tick_duration() -> Duration {
+    Duration::from_secs_f64(1.0 / TICK_RATE as f64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickPhase {
+    CollectInputs,
+    ProcessInputs,
+    Physics,
+    Collision,
+    World,
+    Ai,
+    Combat,
+    BuildSnapshots,
+    SendSnapshots,
+}
+
+impl TickPhase {
+    pub const ALL: [TickPhase; 9] = [
+        TickPhase::CollectInputs,
+        TickPhase::ProcessInputs,
+        TickPhase::Physics,
+        TickPhase::Collision,
+        TickPhase::World,
+        TickPhase::Ai,
+        TickPhase::Combat,
+        TickPhase::BuildSnapshots,
+        TickPhase::SendSnapshots,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhaseTiming {
+    pub last: Duration,
+    pub average: Duration,
+    pub max: Duration,
+}
+
+impl PhaseTiming {
+    fn record(&mut self, elapsed: Duration) {
+        self.last = elapsed;
+        self.max = self.max.max(elapsed);
+        // Exponential moving average over roughly the last second of ticks
+        self.average = (self.average * (TICK_RATE - 1) + elapsed) / TICK_RATE;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TickStats {
+    pub tick: u64,
+    pub phases: [PhaseTiming; TickPhase::ALL.len()],
+    pub total: PhaseTiming,
+    pub overruns: u64,
+    pub skipped_ticks: u64,
+}
+
+impl fmt::Display for TickStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Tick {} ({} overruns, {} skipped ticks)",
+            self.tick, self.overruns, self.skipped_ticks
+        )?;
+        for (phase, timing) in TickPhase::ALL.iter().zip(self.phases.iter()) {
+            writeln!(
+                f,
+                "  {:<16} avg {:>9.3?} max {:>9.3?}",
+                format!("{phase:?}"),
+                timing.average,
+                timing.max
+            )?;
+        }
+        write!(
+            f,
+            "  {:<16} avg {:>9.3?} max {:>9.3?}",
+            "Total", self.total.average, self.total.max
+        )
+    }
+}
+
+pub struct TickLoop {
+    game_manager: Arc<GameManager>,
+    tick: u64,
+}
+
+impl TickLoop {
+    pub fn new(game_manager: Arc<GameManager>) -> Self {
+        Self {
+            game_manager,
+            tick: 0,
+        }
+    }
+
+    /// The number of ticks run so far.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Runs one tick straight away, replays step through ticks this way rather than on a timer.
+    pub fn run_tick(&mut self) {
+        let tick_start = Instant::now();
+        self.game_manager.start_tick(self.tick, tick_start);
+        let mut timings = [Duration::ZERO; TickPhase::ALL.len()];
+
+        for (index, phase) in TickPhase::ALL.into_iter().enumerate() {
+            let phase_start = Instant::now();
+            self.run_phase(phase);
+            timings[index] = phase_start.elapsed();
+        }
+
+        self.tick += 1;
+        let total = tick_start.elapsed();
+
+        if let Ok(mut stats) = self.game_manager.tick_stats.lock() {
+            stats.tick = self.tick;
+            for (timing, elapsed) in stats.phases.iter_mut().zip(timings) {
+                timing.record(elapsed);
+            }
+            stats.total.record(total);
+            if total > tick_duration() {
+                stats.overruns += 1;
+            }
+        }
+    }
+
+    fn run_phase(&mut self, phase: TickPhase) {
+        match phase {
+            TickPhase::CollectInputs => self.collect_inputs(),
+            TickPhase::ProcessInputs => self.process_inputs(),
+            TickPhase::Physics => self.physics_step(),
+            TickPhase::Collision => self.collision_step(),
+            TickPhase::World => self.world_step(),
+            TickPhase::Ai => self.npc_ai_step(),
+            TickPhase::Combat => self.combat_step(),
+            TickPhase::BuildSnapshots => self.build_snapshots(),
+            TickPhase::SendSnapshots => self.send_snapshots(),
+        }
+    }
+
+    fn collect_inputs(&mut self) {
+        if let Ok(mut players) = self.game_manager.players.lock() {
+            players.collect_inputs();
+            if let Some(recorder) = &self.game_manager.recorder {
+                recorder.begin_tick(self.tick + 1);
+            }
+        }
+    }
+
+    fn process_inputs(&mut self) {
+        // Chunk generation holds the write lock only briefly, inputs can wait a tick for it
+        let Ok(chunk_manager) = self.game_manager.chunk_manager.try_read() else {
+            if let Some(recorder) = &self.game_manager.recorder {
+                recorder.inputs_deferred(self.tick);
+            }
+            return;
+        };
+        if let Ok(mut players) = self.game_manager.players.lock() {
+            players.process_inputs(tick_duration(), |x, y| chunk_manager.height_at(x, y));
+        }
+    }
+
+    fn physics_step(&mut self) {}
+
+    fn collision_step(&mut self) {}
+
+    fn world_step(&mut self) {}
+
+    fn npc_ai_step(&mut self) {}
+
+    fn combat_step(&mut self) {}
+
+    fn build_snapshots(&mut self) {
+        if let Ok(mut players) = self.game_manager.players.lock() {
+            players.build_snapshots(self.tick as u32);
+        }
+    }
+
+    fn send_snapshots(&mut self) {
+        if let Ok(mut players) = self.game_manager.players.lock() {
+            players.send_snapshots();
+        }
+    }
+
+    async fn run(mut self) {
+        let tick_duration = tick_duration();
+        let mut next_tick = tokio::time::Instant::now();
+
+        loop {
+            tokio::time::sleep_until(next_tick).await;
+
+            let now = tokio::time::Instant::now();
+            let mut caught_up = 0;
+            while next_tick <= now && caught_up < MAX_CATCH_UP_TICKS {
+                self.run_tick();
+                next_tick += tick_duration;
+                caught_up += 1;
+            }
+
+            // Too far behind to catch up, drop the backlog instead of spiralling
+            if next_tick <= now {
+                let behind = (now - next_tick).as_nanos() / tick_duration.as_nanos() + 1;
+                next_tick += tick_duration * behind as u32;
+                if let Ok(mut stats) = self.game_manager.tick_stats.lock() {
+                    stats.skipped_ticks += behind as u64;
+                }
+            }
+        }
+    }
+}
+
+pub async fn run_tick_loop(thread_manager: Arc<ThreadManager>, game_manager: Arc<GameManager>) {
+    let tick_loop = TickLoop::new(game_manager);
+    thread_manager.spawn(move || tick_loop.run()).await;
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How often each side pings the other.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Pings still waiting for a pong, older ones are treated as lost.
+const OUTSTANDING_PINGS: usize = 8;
+/// Weight of each new sample in the smoothed figures, as for TCP's SRTT.
+const RTT_SMOOTHING: f64 = 1.0 / 8.0;
+/// Weight of each new sample in the jitter, as for TCP's RTTVAR.
+const JITTER_SMOOTHING: f64 = 1.0 / 4.0;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Ping {
+    pub sequence: u32,
+}
+
+impl Ping {
+    /// The answer to this ping, `time_us` being the responder's clock when it answered.
+    pub fn reply(&self, time_us: u64) -> Pong {
+        Pong {
+            sequence: self.sequence,
+            time_us,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Pong {
+    pub sequence: u32,
+    pub time_us: u64,
+}
+
+/// Round trip time, jitter and the peer's clock offset, each smoothed over many ping/pong
+/// exchanges. Send times are remembered locally rather than echoed back, so a peer can only
+/// make itself look slower, never faster.
+#[derive(Debug, Clone)]
+pub struct ClockSync {
+    epoch: Instant,
+    next_sequence: u32,
+    /// Sequence and local send time in microseconds of pings not yet answered
+    outstanding: VecDeque<(u32, u64)>,
+    samples: u32,
+    /// Smoothed round trip, microseconds
+    rtt: f64,
+    /// Smoothed variation of the round trip, microseconds
+    jitter: f64,
+    /// Smoothed peer clock minus local clock, microseconds
+    offset: f64,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            next_sequence: 0,
+            outstanding: VecDeque::with_capacity(OUTSTANDING_PINGS),
+            samples: 0,
+            rtt: 0.0,
+            jitter: 0.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl ClockSync {
+    /// A new ping to send, remembered so its pong can be timed.
+    pub fn next_ping(&mut self, now: Instant) -> Ping {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        if self.outstanding.len() == OUTSTANDING_PINGS {
+            self.outstanding.pop_front();
+        }
+        self.outstanding
+            .push_back((sequence, self.local_time_us(now)));
+        Ping { sequence }
+    }
+
+    /// Takes a sample from `pong`, ignoring it unless it answers a ping still outstanding.
+    pub fn receive_pong(&mut self, pong: Pong, now: Instant) {
+        let Some(index) = self
+            .outstanding
+            .iter()
+            .position(|(sequence, _)| *sequence == pong.sequence)
+        else {
+            return;
+        };
+        let (_, sent_us) = self.outstanding[index];
+        // Anything sent before this ping has been overtaken and would only give a stale sample
+        self.outstanding.drain(..=index);
+
+        let received_us = self.local_time_us(now);
+        let rtt = received_us.saturating_sub(sent_us) as f64;
+        // Assume the pong was sent halfway through the round trip
+        let offset = pong.time_us as f64 - (sent_us as f64 + rtt / 2.0);
+
+        if self.samples == 0 {
+            self.rtt = rtt;
+            self.jitter = rtt / 2.0;
+            self.offset = offset;
+        } else {
+            self.jitter += ((self.rtt - rtt).abs() - self.jitter) * JITTER_SMOOTHING;
+            self.rtt += (rtt - self.rtt) * RTT_SMOOTHING;
+            self.offset += (offset - self.offset) * RTT_SMOOTHING;
+        }
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    /// How many pongs have been measured.
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.measured(self.rtt)
+    }
+
+    pub fn jitter(&self) -> Option<Duration> {
+        self.measured(self.jitter)
+    }
+
+    /// The peer's clock at local time `now`, in the peer's microseconds.
+    pub fn peer_time_us(&self, now: Instant) -> Option<u64> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some((self.local_time_us(now) as f64 + self.offset).max(0.0) as u64)
+    }
+
+    /// This side's clock, what to put in a pong when answering the peer.
+    pub fn local_time_us(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_micros() as u64
+    }
+
+    fn measured(&self, micros: f64) -> Option<Duration> {
+        (self.samples > 0).then(|| Duration::from_secs_f64(micros / 1_000_000.0))
+    }
+}
+
+impl fmt::Display for ClockSync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.samples == 0 {
+            return write!(f, "rtt unknown");
+        }
+        write!(
+            f,
+            "rtt {:.1} ms ± {:.1} ms, clock offset {:+.1} ms",
+            self.rtt / 1000.0,
+            self.jitter / 1000.0,
+            self.offset / 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(clock: &ClockSync, millis: u64) -> Instant {
+        clock.epoch + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn measures_round_trip_and_offset() {
+        let mut clock = ClockSync::default();
+        // The peer's clock runs 5 s ahead, pongs take 20 ms each way
+        for round in 0..50 {
+            let sent = round * 1000;
+            let ping = clock.next_ping(at(&clock, sent));
+            let pong = ping.reply((5_000 + sent + 20) * 1000);
+            clock.receive_pong(pong, at(&clock, sent + 40));
+        }
+
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(40)));
+        assert!(clock.jitter().unwrap() < Duration::from_millis(1));
+        let peer = clock.peer_time_us(at(&clock, 60_000)).unwrap();
+        assert!(peer.abs_diff(65_000_000) < 1000, "{peer}");
+    }
+
+    #[test]
+    fn ignores_unknown_and_overtaken_pongs() {
+        let mut clock = ClockSync::default();
+        clock.receive_pong(
+            Pong {
+                sequence: 7,
+                time_us: 0,
+            },
+            at(&clock, 0),
+        );
+        assert_eq!(clock.samples(), 0);
+
+        let first = clock.next_ping(at(&clock, 0));
+        let second = clock.next_ping(at(&clock, 1000));
+        clock.receive_pong(second.reply(0), at(&clock, 1010));
+        // The first ping's pong arriving late would claim a 2 s round trip
+        clock.receive_pong(first.reply(0), at(&clock, 2000));
+
+        assert_eq!(clock.samples(), 1);
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,441 @@
+use quinn::{ReadExactError, RecvStream, SendStream, WriteError};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::CloseCode;
+
+const LENGTH_PREFIX_SIZE: usize = 4;
+/// Set on the length prefix when the payload is compressed, which leaves uncompressed frames
+/// byte for byte what older builds send.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+const SIZE_MASK: u32 = !COMPRESSED_FLAG;
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+/// How much memory decoding a frame may take, as a multiple of the frame cap. bincode counts every
+/// value at its size in memory, which for small varint encoded integers is up to 8 times their
+/// size on the wire.
+const DECODE_LIMIT_FACTOR: usize = 8;
+/// What decoding a datagram may take, well above anything that fits in one.
+const DATAGRAM_DECODE_LIMIT: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("frame of {size} bytes exceeds the {max} byte limit")]
+    Oversize { size: usize, max: usize },
+    #[error("stream ended {received} bytes into a {expected} byte frame")]
+    Truncated { expected: usize, received: usize },
+    #[error("could not decode frame: {0}")]
+    Undecodable(#[from] bincode::error::DecodeError),
+    #[error("could not encode message: {0}")]
+    Unencodable(#[from] bincode::error::EncodeError),
+    #[error("received a compressed frame without negotiating compression")]
+    UnexpectedCompression,
+    #[error("could not compress frame: {0}")]
+    Compress(std::io::Error),
+    #[error("could not decompress frame: {0}")]
+    Decompress(std::io::Error),
+    #[error("stream closed")]
+    Closed,
+    #[error("connection error: {0}")]
+    Read(#[from] quinn::ReadError),
+    #[error("connection error: {0}")]
+    Write(#[from] WriteError),
+}
+
+impl CodecError {
+    /// The code the connection should be closed with when the peer sent this frame, if the
+    /// error is the peer's fault rather than the connection going away.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            CodecError::Oversize { .. } => Some(CloseCode::FrameTooLarge),
+            CodecError::Truncated { .. }
+            | CodecError::Undecodable(_)
+            | CodecError::UnexpectedCompression
+            | CodecError::Decompress(_) => Some(CloseCode::MalformedFrame),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum frame size, in bytes, for each kind of stream.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameLimits {
+    pub control: usize,
+    pub chunks: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            control: 1024 * 1024,
+            chunks: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 1] = [Compression::Zstd];
+
+    /// The name offered in the handshake feature list.
+    pub const fn feature(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_features(features: &[String]) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|compression| features.iter().any(|f| f == compression.feature()))
+    }
+}
+
+/// Length prefixed bincode framing that refuses frames above `max_frame_size` in either direction.
+/// Once compression is negotiated, payloads of at least `compression_threshold` bytes are compressed.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    pub max_frame_size: usize,
+    pub compression: Option<Compression>,
+    pub compression_threshold: usize,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(FrameLimits::default().control)
+    }
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Option<Compression>, threshold: usize) -> Self {
+        self.compression = compression;
+        self.compression_threshold = threshold;
+        self
+    }
+
+    pub fn encode<T: Serialize>(&self, msg: T) -> Result<Vec<u8>, CodecError> {
+        let payload = bincode::serde::encode_to_vec(msg, bincode::config::standard())?;
+        self.check_size(payload.len())?;
+
+        let (payload, flag) = match self.compression {
+            Some(Compression::Zstd) if payload.len() >= self.compression_threshold => {
+                let compressed =
+                    zstd::bulk::compress(&payload, ZSTD_LEVEL).map_err(CodecError::Compress)?;
+                if compressed.len() < payload.len() {
+                    (compressed, COMPRESSED_FLAG)
+                } else {
+                    (payload, 0)
+                }
+            }
+            _ => (payload, 0),
+        };
+
+        let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32 | flag).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Decodes a whole frame, length prefix included, as produced by [`FrameCodec::encode`].
+    pub fn decode<T: DeserializeOwned>(&self, frame: &[u8]) -> Result<T, CodecError> {
+        let Some((prefix, payload)) = frame.split_first_chunk::<LENGTH_PREFIX_SIZE>() else {
+            return Err(CodecError::Truncated {
+                expected: LENGTH_PREFIX_SIZE,
+                received: frame.len(),
+            });
+        };
+        let (size, compressed) = self.parse_prefix(*prefix)?;
+        if payload.len() < size {
+            return Err(CodecError::Truncated {
+                expected: size,
+                received: payload.len(),
+            });
+        }
+        self.decode_body(&payload[..size], compressed)
+    }
+
+    pub async fn send<T: Serialize>(
+        &self,
+        send: &mut SendStream,
+        msg: T,
+    ) -> Result<(), CodecError> {
+        let frame = self.encode(msg)?;
+        send.write_all(&frame).await?;
+        Ok(())
+    }
+
+    pub async fn receive<T: DeserializeOwned>(
+        &self,
+        recv: &mut RecvStream,
+    ) -> Result<T, CodecError> {
+        let frame = self.receive_frame(recv).await?;
+        self.decode(&frame)
+    }
+
+    /// Reads one whole frame, length prefix included, leaving it to [`FrameCodec::decode`] so it
+    /// can be decoded with whatever compression has been negotiated by then.
+    pub async fn receive_frame(&self, recv: &mut RecvStream) -> Result<Vec<u8>, CodecError> {
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        match recv.read_exact(&mut prefix).await {
+            Ok(()) => {}
+            Err(ReadExactError::FinishedEarly(0)) => return Err(CodecError::Closed),
+            Err(ReadExactError::FinishedEarly(received)) => {
+                return Err(CodecError::Truncated {
+                    expected: LENGTH_PREFIX_SIZE,
+                    received,
+                });
+            }
+            Err(ReadExactError::ReadError(e)) => return Err(e.into()),
+        }
+
+        let (size, _) = self.parse_prefix(prefix)?;
+
+        let mut frame = vec![0u8; LENGTH_PREFIX_SIZE + size];
+        frame[..LENGTH_PREFIX_SIZE].copy_from_slice(&prefix);
+        match recv.read_exact(&mut frame[LENGTH_PREFIX_SIZE..]).await {
+            Ok(()) => {}
+            Err(ReadExactError::FinishedEarly(received)) => {
+                return Err(CodecError::Truncated {
+                    expected: size,
+                    received,
+                });
+            }
+            Err(ReadExactError::ReadError(e)) => return Err(e.into()),
+        }
+
+        Ok(frame)
+    }
+
+    fn parse_prefix(&self, prefix: [u8; LENGTH_PREFIX_SIZE]) -> Result<(usize, bool), CodecError> {
+        let prefix = u32::from_be_bytes(prefix);
+        let size = (prefix & SIZE_MASK) as usize;
+        self.check_size(size)?;
+        Ok((size, prefix & COMPRESSED_FLAG != 0))
+    }
+
+    fn decode_body<T: DeserializeOwned>(
+        &self,
+        payload: &[u8],
+        compressed: bool,
+    ) -> Result<T, CodecError> {
+        let limit = self.max_frame_size.saturating_mul(DECODE_LIMIT_FACTOR);
+        if !compressed {
+            return decode_with_limit(payload, limit);
+        }
+
+        match self.compression {
+            Some(Compression::Zstd) => {
+                // Check the size the frame claims to expand to before allocating room for it
+                let size = match zstd::zstd_safe::get_frame_content_size(payload) {
+                    Ok(Some(size)) => size as usize,
+                    Ok(None) => self.max_frame_size,
+                    Err(_) => {
+                        return Err(CodecError::Decompress(std::io::Error::other(
+                            "invalid zstd frame header",
+                        )));
+                    }
+                };
+                self.check_size(size)?;
+                let payload =
+                    zstd::bulk::decompress(payload, size).map_err(CodecError::Decompress)?;
+                decode_with_limit(&payload, limit)
+            }
+            None => Err(CodecError::UnexpectedCompression),
+        }
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), CodecError> {
+        if size > self.max_frame_size {
+            return Err(CodecError::Oversize {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a datagram, which has no length prefix.
+/// Reads frames off `recv` until it ends, passing each on undecoded and then the error that ended
+/// it. Meant to run on its own task: reading inside a `select!` loses half a frame whenever
+/// another branch wins, while waiting on the channel is cancel safe.
+pub async fn forward_frames(
+    mut recv: RecvStream,
+    codec: FrameCodec,
+    frame_tx: UnboundedSender<Result<Vec<u8>, CodecError>>,
+) {
+    loop {
+        let frame = codec.receive_frame(&mut recv).await;
+        let ended = frame.is_err();
+        if frame_tx.send(frame).is_err() || ended {
+            return;
+        }
+    }
+}
+
+pub fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CodecError> {
+    decode_with_limit(payload, DATAGRAM_DECODE_LIMIT)
+}
+
+/// Decodes `payload`, failing as soon as it claims more than `limit` bytes of memory so a small
+/// frame cannot make the decoder allocate a huge collection.
+fn decode_with_limit<T: DeserializeOwned>(payload: &[u8], limit: usize) -> Result<T, CodecError> {
+    // bincode only takes the limit as a constant, so it is rounded up to a power of two
+    let bits = limit
+        .checked_next_power_of_two()
+        .map_or(usize::BITS, usize::trailing_zeros);
+    match bits {
+        ..=16 => decode_limited::<T, { 1 << 16 }>(payload),
+        17 => decode_limited::<T, { 1 << 17 }>(payload),
+        18 => decode_limited::<T, { 1 << 18 }>(payload),
+        19 => decode_limited::<T, { 1 << 19 }>(payload),
+        20 => decode_limited::<T, { 1 << 20 }>(payload),
+        21 => decode_limited::<T, { 1 << 21 }>(payload),
+        22 => decode_limited::<T, { 1 << 22 }>(payload),
+        23 => decode_limited::<T, { 1 << 23 }>(payload),
+        24 => decode_limited::<T, { 1 << 24 }>(payload),
+        25 => decode_limited::<T, { 1 << 25 }>(payload),
+        26 => decode_limited::<T, { 1 << 26 }>(payload),
+        27 => decode_limited::<T, { 1 << 27 }>(payload),
+        28 => decode_limited::<T, { 1 << 28 }>(payload),
+        29 => decode_limited::<T, { 1 << 29 }>(payload),
+        _ => decode_limited::<T, { 1 << 30 }>(payload),
+    }
+}
+
+fn decode_limited<T: DeserializeOwned, const LIMIT: usize>(
+    payload: &[u8],
+) -> Result<T, CodecError> {
+    let config = bincode::config::standard().with_limit::<LIMIT>();
+    let (msg, _): (T, usize) = bincode::serde::decode_from_slice(payload, config)?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{ChunkManager, ChunkStreamMessage, ServerControlStreamMessage, TilePos};
+
+    fn chunk_loads() -> Vec<ChunkStreamMessage> {
+        let chunk_manager = ChunkManager::new(7, 16, 2).unwrap();
+        chunk_manager
+            .chunks
+            .into_values()
+            .map(ChunkStreamMessage::Load)
+            .collect()
+    }
+
+    fn zstd_codec() -> FrameCodec {
+        FrameCodec::new(16 * 1024 * 1024)
+            .with_compression(Some(Compression::Zstd), DEFAULT_COMPRESSION_THRESHOLD)
+    }
+
+    /// Chunk tiles live in a `HashMap`, so compare contents rather than encoded bytes.
+    fn tiles(msg: &ChunkStreamMessage) -> HashSet<TilePos> {
+        let ChunkStreamMessage::Load(chunk) = msg else {
+            panic!("expected a chunk load");
+        };
+        chunk.tiles.keys().copied().collect()
+    }
+
+    #[test]
+    fn compressed_chunks_round_trip_smaller() {
+        let mut plain_total = 0;
+        let mut compressed_total = 0;
+
+        for msg in chunk_loads() {
+            let plain = FrameCodec::new(16 * 1024 * 1024).encode(&msg).unwrap();
+            let compressed = zstd_codec().encode(&msg).unwrap();
+            plain_total += plain.len();
+            compressed_total += compressed.len();
+
+            let decoded: ChunkStreamMessage = zstd_codec().decode(&compressed).unwrap();
+            assert_eq!(tiles(&decoded), tiles(&msg));
+        }
+
+        assert!(
+            compressed_total * 10 < plain_total * 9,
+            "{compressed_total} bytes zstd is not much smaller than {plain_total} bytes plain"
+        );
+    }
+
+    #[test]
+    fn small_frames_are_not_compressed() {
+        let msg = ServerControlStreamMessage::Announcement("hello".into());
+        let plain = FrameCodec::default().encode(&msg).unwrap();
+        let frame = zstd_codec().encode(&msg).unwrap();
+
+        assert_eq!(frame, plain);
+    }
+
+    #[test]
+    fn compressed_frame_requires_negotiation() {
+        let frame = zstd_codec().encode(&chunk_loads()[0]).unwrap();
+        let result = FrameCodec::new(16 * 1024 * 1024).decode::<ChunkStreamMessage>(&frame);
+
+        assert!(matches!(result, Err(CodecError::UnexpectedCompression)));
+    }
+
+    #[test]
+    fn decompressed_size_is_limited() {
+        let frame = zstd_codec().encode(&chunk_loads()[0]).unwrap();
+        let codec = FrameCodec::new(frame.len())
+            .with_compression(Some(Compression::Zstd), DEFAULT_COMPRESSION_THRESHOLD);
+        let result = codec.decode::<ChunkStreamMessage>(&frame);
+
+        assert!(matches!(result, Err(CodecError::Oversize { .. })));
+    }
+
+    #[test]
+    fn claimed_lengths_are_limited_by_the_frame_cap() {
+        // A 5 byte frame claiming a 32 MiB string
+        let claimed: u32 = 32 * 1024 * 1024;
+        let mut frame = 5u32.to_be_bytes().to_vec();
+        frame.push(0xFC);
+        frame.extend_from_slice(&claimed.to_le_bytes());
+        let result = FrameCodec::new(1024 * 1024).decode::<String>(&frame);
+
+        assert!(matches!(
+            result,
+            Err(CodecError::Undecodable(
+                bincode::error::DecodeError::LimitExceeded
+            ))
+        ));
+    }
+
+    #[test]
+    fn oversize_and_truncated_frames_are_rejected() {
+        let codec = FrameCodec::new(64);
+        let oversize = [0x00, 0x01, 0x00, 0x00];
+        let truncated = [0x00, 0x00, 0x00, 0x10, 0x01];
+
+        assert!(matches!(
+            codec.decode::<ServerControlStreamMessage>(&oversize),
+            Err(CodecError::Oversize { size: 65536, .. })
+        ));
+        assert!(matches!(
+            codec.decode::<ServerControlStreamMessage>(&truncated),
+            Err(CodecError::Truncated {
+                expected: 16,
+                received: 1
+            })
+        ));
+    }
+}
@@ -1,4 +1,5 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 2.0.0-rc.20
 
 pub mod accounts;
+pub mod bans;
 pub mod characters;
@@ -4,6 +4,12 @@ pub use map::*;
 mod pos;
 pub use pos::*;
 
+mod clock;
+pub use clock::*;
+
+mod codec;
+pub use codec::*;
+
 mod shared_networking;
 pub use shared_networking::*;
 
@@ -11,4 +17,13 @@ mod entities;
 pub use entities::*;
 
 mod messages;
-pub use messages::*;
\ No newline at end of file
+pub use messages::*;
+
+mod movement;
+pub use movement::*;
+
+mod snapshot;
+pub use snapshot::*;
+
+mod tls;
+pub use tls::*;
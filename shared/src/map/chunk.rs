@@ -12,11 +12,10 @@ pub struct Chunk {
 }
 
 impl Chunk {
-    pub fn new(pos: ChunkPos) -> anyhow::Result<Self> {
-        let size: usize = 4;
+    pub fn new(pos: ChunkPos, size: usize, seed: u32) -> anyhow::Result<Self> {
         let mut tiles: HashMap<TilePos, Tile> = HashMap::new();
 
-        let height_map = generate_heightmap(&pos.to_tile_pos(size), size)?;
+        let height_map = generate_heightmap(&pos.to_tile_pos(size), size, seed)?;
         height_map.iter().for_each(|((x, y), z)| {
             let position: TilePos = TilePos::new(*x, *y, *z);
             let tile: Tile = Tile {
@@ -27,4 +26,13 @@ impl Chunk {
         });
         Ok(Self { pos, size, tiles })
     }
+
+    /// Height of the highest tile in the column at `x`, `y`, if the column has any.
+    pub fn height_at(&self, x: i64, y: i64) -> Option<i64> {
+        self.tiles
+            .keys()
+            .filter(|pos| pos.x == x && pos.y == y)
+            .map(|pos| pos.z)
+            .max()
+    }
 }
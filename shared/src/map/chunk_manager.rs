@@ -1,32 +1,103 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    path::Path,
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{Chunk, ChunkPos, map::chunk, pos};
+use crate::{Chunk, ChunkPos};
+
+const CHUNK_EXTENSION: &str = "chunk";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ChunkManager {
+    pub seed: u32,
+    pub chunk_size: usize,
     pub chunks: HashMap<ChunkPos, Chunk>,
+    #[serde(skip)]
+    dirty: HashSet<ChunkPos>,
 }
 
 impl ChunkManager {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(seed: u32, chunk_size: usize, radius: i64) -> anyhow::Result<Self> {
         let mut chunks: HashMap<ChunkPos, Chunk> = HashMap::new();
 
-        let pos = (0, 0);
-        let size: i64 = 2;
-
-        let chunk_pos = ChunkPos::new(0, 0);
-
-        for x in -(pos.0 + size)..=(pos.0 + size) {
-            for y in -(pos.1 + size)..=(pos.1 + size) {
+        for x in -radius..=radius {
+            for y in -radius..=radius {
                 let pos = ChunkPos::new(x, y);
-                let chunk = Chunk::new(pos)?;
+                let chunk = Chunk::new(pos, chunk_size, seed)?;
                 chunks.insert(pos, chunk);
             }
         }
 
-        Ok(Self { chunks })
+        let dirty: HashSet<ChunkPos> = chunks.keys().copied().collect();
+
+        Ok(Self {
+            seed,
+            chunk_size,
+            chunks,
+            dirty,
+        })
+    }
+
+    pub fn load_saved(&mut self, dir: &Path) -> anyhow::Result<()> {
+        if !dir.exists() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            // Anything else is a write that never finished
+            if path.extension() != Some(OsStr::new(CHUNK_EXTENSION)) {
+                continue;
+            }
+            let bytes = fs::read(path)?;
+            let (chunk, _): (Chunk, usize) =
+                bincode::serde::decode_from_slice(&bytes, bincode::config::standard())?;
+            self.dirty.remove(&chunk.pos);
+            self.chunks.insert(chunk.pos, chunk);
+        }
+
+        Ok(())
+    }
+
+    pub fn save_dirty(&mut self, dir: &Path) -> anyhow::Result<usize> {
+        fs::create_dir_all(dir)?;
+
+        let dirty: Vec<ChunkPos> = self.dirty.iter().copied().collect();
+        for pos in &dirty {
+            if let Some(chunk) = self.chunks.get(pos) {
+                let bytes: Vec<u8> =
+                    bincode::serde::encode_to_vec(chunk, bincode::config::standard())?;
+                // Written aside and renamed over the old file, so a crash never leaves half a chunk
+                let path = dir.join(format!("{}_{}.{CHUNK_EXTENSION}", pos.x, pos.y));
+                let temp_path = path.with_extension("tmp");
+                fs::write(&temp_path, bytes)?;
+                fs::rename(&temp_path, &path)?;
+            }
+            self.dirty.remove(pos);
+        }
+
+        Ok(dirty.len())
+    }
+
+    /// Returns the chunk at `pos`, generating it from the world seed the first time it is needed.
+    pub fn get_or_generate(&mut self, pos: ChunkPos) -> anyhow::Result<&Chunk> {
+        if !self.chunks.contains_key(&pos) {
+            let chunk = Chunk::new(pos, self.chunk_size, self.seed)?;
+            self.chunks.insert(pos, chunk);
+            self.dirty.insert(pos);
+        }
+        Ok(&self.chunks[&pos])
+    }
+
+    /// Height of the tile column at `x`, `y`, or `None` if its chunk is not loaded.
+    pub fn height_at(&self, x: i64, y: i64) -> Option<i64> {
+        self.chunks
+            .get(&ChunkPos::from_tile(x, y, self.chunk_size))?
+            .height_at(x, y)
     }
 
     pub fn get_chunks_radius(&self, pos: ChunkPos, size: usize) -> Vec<(ChunkPos, Chunk)> {
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ChunkPos;
+
+/// The square of chunks around `center` that a client wants loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkView {
+    pub center: ChunkPos,
+    pub radius: usize,
+}
+
+impl ChunkView {
+    pub fn new(center: ChunkPos, radius: usize) -> Self {
+        Self { center, radius }
+    }
+
+    pub fn contains(&self, pos: ChunkPos) -> bool {
+        let radius = self.radius as i64;
+        (pos.x - self.center.x).abs() <= radius && (pos.y - self.center.y).abs() <= radius
+    }
+
+    /// Every chunk in view, closest to the center first.
+    pub fn positions(&self) -> Vec<ChunkPos> {
+        let radius = self.radius as i64;
+        let mut positions: Vec<ChunkPos> = (-radius..=radius)
+            .flat_map(|x| {
+                (-radius..=radius).map(move |y| ChunkPos::new(self.center.x + x, self.center.y + y))
+            })
+            .collect();
+        positions.sort_by_key(|pos| pos.distance_squared(&self.center));
+        positions
+    }
+}
@@ -1,26 +1,23 @@
-use std::{
-    collections::HashMap,
-    sync::{LazyLock, RwLock},
-};
+use std::collections::HashMap;
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use noise::{NoiseFn, Perlin};
 
-use crate::{TilePos};
+use crate::TilePos;
 
-static PERLIN: LazyLock<RwLock<Perlin>> = LazyLock::new(|| RwLock::new(Perlin::new(0)));
-
-pub fn generate_heightmap(postion: &TilePos, size: usize) -> Result<HashMap<(i64, i64), i64>> {
+pub fn generate_heightmap(
+    postion: &TilePos,
+    size: usize,
+    seed: u32,
+) -> Result<HashMap<(i64, i64), i64>> {
     let scale = 0.025;
+    let perlin = Perlin::new(seed);
 
     let mut height_map: HashMap<(i64, i64), i64> = HashMap::new();
     for y in -(size as i64)..=size as i64 {
         for x in -(size as i64)..=size as i64 {
             let pos = [(x + postion.x), (y + postion.y)];
-            let noise = PERLIN
-                .read()
-                .map_err(|e| anyhow!("Could not get PERLIN to read: {e}"))?
-                .get([pos[0] as f64 * scale, pos[1] as f64 * scale]);
+            let noise = perlin.get([pos[0] as f64 * scale, pos[1] as f64 * scale]);
             height_map.insert((pos[0], pos[1]), (((noise + 1.0) * 0.5) * 5.0) as i64);
         }
     }
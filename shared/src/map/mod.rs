@@ -8,4 +8,6 @@ mod chunk;
 pub use chunk::*;
 
 mod chunk_manager;
-pub use chunk_manager::*;
\ No newline at end of file
+pub use chunk_manager::*;
+mod chunk_view;
+pub use chunk_view::*;
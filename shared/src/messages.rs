@@ -1,6 +1,8 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
-use crate::{Chunk, ChunkPos, characters};
+use crate::{Chunk, ChunkPos, ChunkView, Ping, PlayerInput, Pong, characters};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AccountCredentials {
@@ -25,26 +27,193 @@ pub struct AccountInfo {
     pub characters: Vec<characters::Model>,
 }
 
+/// Bumped whenever a message or datagram changes shape, peers on different versions refuse each
+/// other cleanly.
+pub const PROTOCOL_VERSION: u32 = 7;
+pub const BUILD_ID: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientHello {
+    pub protocol_version: u32,
+    pub build_id: String,
+    pub features: Vec<String>,
+}
+
+impl ClientHello {
+    pub fn new(features: Vec<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build_id: BUILD_ID.into(),
+            features,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerHello {
+    pub protocol_version: u32,
+    pub build_id: String,
+    /// The client's requested features that the server agreed to use
+    pub features: Vec<String>,
+}
+
+/// First frame on every stream, telling the peer which handler the stream belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamKind {
+    Control,
+    Chunks,
+    Chat,
+    Bulk,
+}
+
+// `StreamKind::Control`, `ConnectionRequest` and the first two server replies must keep their
+// positions and leading fields so that any two versions can still read each other's handshake.
 #[derive(Serialize, Deserialize, Clone)]
 pub enum ClientControlStreamMessage {
-    ConnectionRequest,
+    ConnectionRequest(ClientHello),
     CreateAccount(AccountCredentials),
     Login(AccountCredentials),
     CreateCharacter(String),
     SelectCharacter(i64),
     JoinWorldRequest,
+    Resume(String),
+    UpdateView(ChunkView),
+}
+
+impl ClientControlStreamMessage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ConnectionRequest(_) => "ConnectionRequest",
+            Self::CreateAccount(_) => "CreateAccount",
+            Self::Login(_) => "Login",
+            Self::CreateCharacter(_) => "CreateCharacter",
+            Self::SelectCharacter(_) => "SelectCharacter",
+            Self::JoinWorldRequest => "JoinWorldRequest",
+            Self::Resume(_) => "Resume",
+            Self::UpdateView(_) => "UpdateView",
+        }
+    }
+}
+
+/// Where a connection is in the login flow, each phase only accepts the messages that move it forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionPhase {
+    Handshaking,
+    Connected,
+    Authenticated,
+    CharacterSelected,
+    InWorld,
+}
+
+impl SessionPhase {
+    pub fn allows(self, message: &ClientControlStreamMessage) -> bool {
+        use ClientControlStreamMessage::*;
+        match message {
+            ConnectionRequest(_) => self == SessionPhase::Handshaking,
+            CreateAccount(_) | Login(_) | Resume(_) => self == SessionPhase::Connected,
+            CreateCharacter(_) | SelectCharacter(_) => matches!(
+                self,
+                SessionPhase::Authenticated | SessionPhase::CharacterSelected
+            ),
+            JoinWorldRequest => self == SessionPhase::CharacterSelected,
+            UpdateView(_) => self == SessionPhase::InWorld,
+        }
+    }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProtocolError {
+    UnexpectedMessage {
+        message: String,
+        phase: SessionPhase,
+    },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedMessage { message, phase } => {
+                write!(f, "{message} is not allowed while {phase:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ServerControlStreamMessage {
-    Connected,
+    Connected(ServerHello),
+    IncompatibleVersion {
+        server_version: u32,
+        server_build: String,
+        client_version: u32,
+        client_build: String,
+    },
     Disconnected(String),
-    Authenticated(AccountInfo),
+    Authenticated {
+        account: AccountInfo,
+        resume_token: String,
+    },
+    Resumed {
+        account: AccountInfo,
+        character_id: Option<i64>,
+        resume_token: String,
+    },
+    ResumeDenied(String),
     LoginDenied(String),
     AccountCreateDenied(String),
     CharacterSelected,
     CharacterDenied(String),
-    InitialWorld {
-        chunks: Vec<(ChunkPos, Chunk)>,
-    }
+    /// Chunks follow on the chunk stream once the client reports its view, snapshots follow as
+    /// datagrams. `entity_id` is the player's own entity in those snapshots.
+    WorldJoined {
+        max_view_radius: usize,
+        chunk_size: usize,
+        entity_id: u32,
+    },
+    Announcement(String),
+    ProtocolError(ProtocolError),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ClientDatagram {
+    Input(PlayerInput),
+    /// Every part of this snapshot tick arrived, so the server may delta encode against it
+    SnapshotAck(u32),
+    Ping(Ping),
+    /// Answers a server ping with the client's clock
+    Pong(Pong),
+}
+
+/// Bytes a `ServerDatagram` adds around its payload, reserved when splitting snapshots.
+pub const SERVER_DATAGRAM_OVERHEAD: usize = 4;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ServerDatagram {
+    /// One part of a bit-packed snapshot, see `SnapshotDecoder`
+    Snapshot(Vec<u8>),
+    Ping(Ping),
+    /// Answers a client ping with the server's clock
+    Pong(Pong),
+}
+
+/// Sent on the server's unidirectional chunk stream.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ChunkStreamMessage {
+    Load(Chunk),
+    Unload(ChunkPos),
+}
+
+pub const MAX_CHAT_LENGTH: usize = 256;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ClientChatMessage {
+    Say(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ServerChatMessage {
+    Message { from: String, text: String },
+    Rejected(String),
 }
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::PlayerPos;
+
+/// Tiles per second at full stick.
+pub const WALK_SPEED: f32 = 4.0;
+pub const SPRINT_MULTIPLIER: f32 = 1.5;
+/// Players can climb onto a tile at most this much higher than the one they stand on.
+pub const MAX_STEP_HEIGHT: i64 = 1;
+/// The most time a single input may cover, so a stalled or lying client cannot cover a large
+/// distance with one input.
+pub const MAX_INPUT_DURATION: Duration = Duration::from_millis(100);
+/// Time covered by the first input after joining, when there is no earlier one to measure from.
+pub const FIRST_INPUT_DURATION: Duration = Duration::from_micros(16_667);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ActionFlags(pub u8);
+
+impl ActionFlags {
+    pub const SPRINT: ActionFlags = ActionFlags(1);
+    pub const JUMP: ActionFlags = ActionFlags(1 << 1);
+    pub const INTERACT: ActionFlags = ActionFlags(1 << 2);
+
+    pub fn contains(self, other: ActionFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: ActionFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// One frame of player input, sent unreliably at the client's frame rate.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PlayerInput {
+    pub input_id: u32,
+    /// Milliseconds on the client's own clock, only ever compared with its other inputs
+    pub client_time_ms: u64,
+    /// Desired direction in tile space, at most unit length
+    pub movement: [f32; 2],
+    pub actions: ActionFlags,
+}
+
+impl PlayerInput {
+    /// False for inputs no honest client produces, such as a movement vector longer than one.
+    pub fn is_valid(&self) -> bool {
+        let [x, y] = self.movement;
+        x.is_finite() && y.is_finite() && x * x + y * y <= 1.0 + 1e-4
+    }
+
+    pub fn speed(&self) -> f32 {
+        if self.actions.contains(ActionFlags::SPRINT) {
+            WALK_SPEED * SPRINT_MULTIPLIER
+        } else {
+            WALK_SPEED
+        }
+    }
+}
+
+/// How much time `input` covers: the client time since the input applied before it, capped at
+/// `MAX_INPUT_DURATION`.
+pub fn input_duration(previous_client_time_ms: Option<u64>, input: &PlayerInput) -> Duration {
+    match previous_client_time_ms {
+        Some(previous) => Duration::from_millis(input.client_time_ms.saturating_sub(previous)),
+        None => FIRST_INPUT_DURATION,
+    }
+    .min(MAX_INPUT_DURATION)
+}
+
+/// Moves `pos` as `input` asks for over `dt`, sliding along whichever axis is still open when
+/// the terrain blocks the full step. `height_at` gives the tile height of a column, `None`
+/// where there is no ground to stand on.
+pub fn apply_input(
+    pos: PlayerPos,
+    input: &PlayerInput,
+    dt: Duration,
+    height_at: impl Fn(i64, i64) -> Option<i64>,
+) -> PlayerPos {
+    let distance = input.speed() * dt.min(MAX_INPUT_DURATION).as_secs_f32();
+    let [dx, dy] = input.movement.map(|axis| axis * distance);
+
+    let candidates = [
+        PlayerPos::new(pos.x + dx, pos.y + dy, pos.z),
+        PlayerPos::new(pos.x + dx, pos.y, pos.z),
+        PlayerPos::new(pos.x, pos.y + dy, pos.z),
+    ];
+    for candidate in candidates {
+        let (x, y) = candidate.tile();
+        if let Some(height) = height_at(x, y)
+            && height - pos.z.round() as i64 <= MAX_STEP_HEIGHT
+        {
+            return PlayerPos::new(candidate.x, candidate.y, height as f32);
+        }
+    }
+
+    pos
+}
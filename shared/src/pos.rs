@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
 pub struct PlayerPos {
     pub x: f32,
     pub y: f32,
@@ -11,6 +11,11 @@ impl PlayerPos {
     pub fn new(x: f32, y: f32, z: f32) -> Self {
         Self { x, y, z }
     }
+
+    /// The column of tiles the player is standing in.
+    pub fn tile(&self) -> (i64, i64) {
+        (self.x.round() as i64, self.y.round() as i64)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Copy)]
@@ -41,6 +46,20 @@ impl ChunkPos {
         Self { x, y }
     }
 
+    /// The chunk holding the tile column at `x`, `y`, the inverse of `to_tile_pos`.
+    pub fn from_tile(x: i64, y: i64, size: usize) -> Self {
+        let span = (size * 2 + 1) as i64;
+        let size = size as i64;
+        Self {
+            x: (x + size).div_euclid(span),
+            y: (y + size).div_euclid(span),
+        }
+    }
+
+    pub fn distance_squared(&self, other: &ChunkPos) -> i64 {
+        (self.x - other.x).pow(2) + (self.y - other.y).pow(2)
+    }
+
     pub fn to_tile_pos(&self, size: usize) -> TilePos {
         TilePos {
             x: self.x * (size * 2 + 1) as i64,
@@ -1,35 +1,57 @@
 use bytes::Bytes;
-use quinn::{Connection, RecvStream, SendStream};
+use quinn::{Connection, RecvStream, SendStream, VarInt};
 use serde::Serialize;
 
+use crate::{CodecError, FrameCodec, StreamKind, decode_payload};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Kicked = 1,
+    ServerShutdown = 2,
+    SessionResumed = 3,
+    LoggedInElsewhere = 4,
+    IncompatibleVersion = 5,
+    FrameTooLarge = 6,
+    MalformedFrame = 7,
+    UnsupportedStream = 8,
+}
 
+impl CloseCode {
+    pub fn code(self) -> VarInt {
+        VarInt::from_u32(self as u32)
+    }
+}
 
-pub async fn send_message<T: Serialize>(send: &mut SendStream, msg: T) -> anyhow::Result<()> {
-    let bytes: Vec<u8> = bincode::serde::encode_to_vec(msg, bincode::config::standard())?;
-    let len: [u8; _] = (bytes.len() as u32).to_be_bytes();
-
-    send.write_all(&len).await?;
-    send.write_all(&bytes).await?;
-    
-    Ok(())
+pub async fn send_message<T: Serialize>(send: &mut SendStream, msg: T) -> Result<(), CodecError> {
+    FrameCodec::default().send(send, msg).await
 }
 
-pub async fn receive_message<T: serde::de::DeserializeOwned>(recv: &mut RecvStream) -> anyhow::Result<T> {
-    let mut len_buf: [u8; 4] = [0u8;4];
-    recv.read_exact(&mut len_buf).await?;
-    let size: usize = u32::from_be_bytes(len_buf) as usize;
+pub async fn receive_message<T: serde::de::DeserializeOwned>(recv: &mut RecvStream) -> Result<T, CodecError> {
+    FrameCodec::default().receive(recv).await
+}
 
-    let mut data:Vec<u8> = vec![0u8;size];
-    recv.read_exact(&mut data).await?;
-    let (msg, _): (T, usize) = bincode::serde::decode_from_slice(&data, bincode::config::standard())?;
+/// Opens a bidirectional stream and declares what it carries.
+pub async fn open_stream(conn: &Connection, kind: StreamKind) -> anyhow::Result<(SendStream, RecvStream)> {
+    let (mut send, recv) = conn.open_bi().await?;
+    send_message(&mut send, kind).await?;
+    Ok((send, recv))
+}
 
-    Ok(msg)
+/// Opens a unidirectional stream and declares what it carries.
+pub async fn open_uni_stream(conn: &Connection, kind: StreamKind) -> anyhow::Result<SendStream> {
+    let mut send = conn.open_uni().await?;
+    send_message(&mut send, kind).await?;
+    Ok(send)
 }
 
-pub async fn send_datagram<T: Serialize>(conn: &Connection, msg: &T) -> anyhow::Result<()> {
+pub fn encode_datagram<T: Serialize>(msg: &T) -> anyhow::Result<Bytes> {
     let bytes: Vec<u8> = bincode::serde::encode_to_vec(msg, bincode::config::standard())?;
 
-    conn.send_datagram(bytes.into())?;
+    Ok(bytes.into())
+}
+
+pub async fn send_datagram<T: Serialize>(conn: &Connection, msg: &T) -> anyhow::Result<()> {
+    conn.send_datagram(encode_datagram(msg)?)?;
 
     Ok(())
 }
@@ -37,7 +59,5 @@ pub async fn send_datagram<T: Serialize>(conn: &Connection, msg: &T) -> anyhow::
 pub async fn receive_datagram<T: serde::de::DeserializeOwned>(conn: &Connection) -> anyhow::Result<T> {
     let bytes: Bytes = conn.read_datagram().await?;
 
-    let (msg, _): (T, usize) = bincode::serde::decode_from_slice(&bytes, bincode::config::standard())?;
-
-    Ok(msg)
-}
\ No newline at end of file
+    Ok(decode_payload(&bytes)?)
+}
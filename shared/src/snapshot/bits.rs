@@ -0,0 +1,101 @@
+use crate::SnapshotError;
+
+/// Packs values MSB first into a byte buffer without padding between fields.
+#[derive(Debug, Clone, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bit_len(&self) -> usize {
+        self.bits
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write(value as u64, 1);
+    }
+
+    /// Writes the low `bits` bits of `value`.
+    pub fn write(&mut self, value: u64, bits: u32) {
+        for shift in (0..bits).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> shift) & 1) as u8;
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= bit << (7 - self.bits % 8);
+            self.bits += 1;
+        }
+    }
+
+    /// Writes `value` as a `bits` wide two's complement number, it must fit.
+    pub fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & mask(bits), bits);
+    }
+
+    /// Copies every bit written to `other` onto the end of this writer.
+    pub fn append(&mut self, other: &BitWriter) {
+        let mut reader = BitReader::new(&other.bytes);
+        let mut remaining = other.bits;
+        while remaining > 0 {
+            let bits = remaining.min(32) as u32;
+            // Cannot fail, `other` holds at least `remaining` bits
+            let value = reader.read(bits).unwrap_or_default();
+            self.write(value, bits);
+            remaining -= bits as usize;
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, bit: 0 }
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.read(1)? == 1)
+    }
+
+    pub fn read(&mut self, bits: u32) -> Result<u64, SnapshotError> {
+        if self.bit + bits as usize > self.bytes.len() * 8 {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.bytes[self.bit / 8];
+            value = (value << 1) | ((byte >> (7 - self.bit % 8)) & 1) as u64;
+            self.bit += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn read_signed(&mut self, bits: u32) -> Result<i64, SnapshotError> {
+        let value = self.read(bits)?;
+        // Sign extend from the top bit of the field
+        let shift = 64 - bits;
+        Ok(((value << shift) as i64) >> shift)
+    }
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
@@ -0,0 +1,88 @@
+use std::f32::consts::TAU;
+
+use serde::{Deserialize, Serialize};
+
+use crate::PlayerPos;
+
+/// Position steps per tile, giving x and y a range of about 131k tiles in 24 bits.
+pub const POSITION_SCALE: f32 = 64.0;
+/// Velocity steps per tile per second, about 128 tiles per second either way in 16 bits.
+pub const VELOCITY_SCALE: f32 = 256.0;
+
+pub const POSITION_XY_BITS: u32 = 24;
+pub const POSITION_Z_BITS: u32 = 16;
+pub const VELOCITY_BITS: u32 = 16;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Animation {
+    #[default]
+    Idle,
+    Walking,
+    Running,
+}
+
+impl Animation {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Animation::Walking,
+            2 => Animation::Running,
+            _ => Animation::Idle,
+        }
+    }
+}
+
+/// What every client is told about an entity each tick.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntityState {
+    pub entity_id: u32,
+    pub position: PlayerPos,
+    /// Tiles per second along x and y
+    pub velocity: [f32; 2],
+    /// Radians counter-clockwise from +x
+    pub facing: f32,
+    pub animation: Animation,
+}
+
+/// An `EntityState` reduced to the precision it is sent with. Baselines are kept in this form so
+/// the server and client compare exactly the same values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizedEntity {
+    pub x: i32,
+    pub y: i32,
+    pub z: i16,
+    pub velocity: [i16; 2],
+    pub facing: u8,
+    pub animation: u8,
+}
+
+impl QuantizedEntity {
+    pub fn from_state(state: &EntityState) -> Self {
+        let xy_limit = (1 << (POSITION_XY_BITS - 1)) - 1;
+        let position =
+            |value: f32| ((value * POSITION_SCALE).round() as i32).clamp(-xy_limit, xy_limit);
+        let velocity = |value: f32| (value * VELOCITY_SCALE).round() as i16;
+
+        Self {
+            x: position(state.position.x),
+            y: position(state.position.y),
+            z: (state.position.z * POSITION_SCALE).round() as i16,
+            velocity: state.velocity.map(velocity),
+            facing: ((state.facing.rem_euclid(TAU) / TAU * 256.0).round() as u32 % 256) as u8,
+            animation: state.animation as u8,
+        }
+    }
+
+    pub fn to_state(self, entity_id: u32) -> EntityState {
+        EntityState {
+            entity_id,
+            position: PlayerPos::new(
+                self.x as f32 / POSITION_SCALE,
+                self.y as f32 / POSITION_SCALE,
+                self.z as f32 / POSITION_SCALE,
+            ),
+            velocity: self.velocity.map(|value| value as f32 / VELOCITY_SCALE),
+            facing: self.facing as f32 / 256.0 * TAU,
+            animation: Animation::from_u8(self.animation),
+        }
+    }
+}
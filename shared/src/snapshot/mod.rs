@@ -0,0 +1,8 @@
+mod bits;
+pub use bits::*;
+
+mod entity;
+pub use entity::*;
+
+mod snapshot_codec;
+pub use snapshot_codec::*;
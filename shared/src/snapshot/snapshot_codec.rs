@@ -0,0 +1,515 @@
+use std::collections::{BTreeMap, HashMap};
+
+use thiserror::Error;
+
+use crate::{
+    BitReader, BitWriter, EntityState, POSITION_XY_BITS, POSITION_Z_BITS, QuantizedEntity,
+    VELOCITY_BITS,
+};
+
+/// How many ticks back a snapshot can still serve as a baseline. Older acks fall back to a full
+/// snapshot, which is also how a client that lost its baseline recovers.
+pub const SNAPSHOT_HISTORY: u32 = 64;
+/// Bytes every part spends on tick, part index, part count and record count.
+const PART_HEADER_BYTES: usize = 8;
+/// Incomplete snapshots the decoder waits on before giving up on the oldest.
+const MAX_PENDING_SNAPSHOTS: usize = 8;
+/// Changes this small are sent as an 8 bit difference instead of the whole coordinate.
+const SMALL_DELTA_BITS: u32 = 8;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("snapshot packet ended early")]
+    Truncated,
+    #[error("malformed snapshot packet: {0}")]
+    Malformed(&'static str),
+    #[error("snapshot {tick} is a delta from {baseline}, which is no longer held")]
+    MissingBaseline { tick: u32, baseline: u32 },
+}
+
+/// How many ticks the server runs per second, `Snapshot::tick` counts in these.
+pub const TICK_RATE: u32 = 60;
+
+/// A complete world state for one tick as a client sees it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub tick: u32,
+    /// The newest of this client's inputs the server had applied when the snapshot was taken
+    pub ack_input_id: Option<u32>,
+    /// Sorted by entity id
+    pub entities: Vec<EntityState>,
+}
+
+type EntityMap = HashMap<u32, QuantizedEntity>;
+
+/// Server side of one client's snapshot stream, delta encoding against the newest snapshot the
+/// client has acknowledged.
+#[derive(Default)]
+pub struct SnapshotEncoder {
+    history: BTreeMap<u32, EntityMap>,
+    acked: Option<u32>,
+}
+
+impl SnapshotEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn acknowledge(&mut self, tick: u32) {
+        if self.history.contains_key(&tick) && self.acked.is_none_or(|acked| tick > acked) {
+            self.acked = Some(tick);
+        }
+    }
+
+    /// Encodes `entities` as the snapshot for `tick`, split into parts of at most `max_size`
+    /// bytes that can each be sent as one datagram.
+    pub fn encode(
+        &mut self,
+        tick: u32,
+        ack_input_id: Option<u32>,
+        entities: &[EntityState],
+        max_size: usize,
+    ) -> Vec<Vec<u8>> {
+        self.history
+            .retain(|&held, _| held <= tick && tick - held < SNAPSHOT_HISTORY);
+        let baseline_tick = self.acked.filter(|acked| self.history.contains_key(acked));
+        let empty = EntityMap::new();
+        let baseline = baseline_tick.map_or(&empty, |acked| &self.history[&acked]);
+
+        let current: EntityMap = entities
+            .iter()
+            .map(|state| (state.entity_id, QuantizedEntity::from_state(state)))
+            .collect();
+
+        // Each record with the entity it leaves the client holding, `None` once removed
+        let mut records: Vec<(u32, Option<QuantizedEntity>, BitWriter)> = vec![];
+        for &entity_id in baseline.keys() {
+            if !current.contains_key(&entity_id) {
+                let mut record = BitWriter::new();
+                record.write(entity_id as u64, 32);
+                record.write_bool(true);
+                records.push((entity_id, None, record));
+            }
+        }
+        let mut ids: Vec<u32> = current.keys().copied().collect();
+        ids.sort_unstable();
+        for entity_id in ids {
+            let entity = &current[&entity_id];
+            let previous = baseline.get(&entity_id);
+            if previous == Some(entity) {
+                continue;
+            }
+            let mut record = BitWriter::new();
+            record.write(entity_id as u64, 32);
+            record.write_bool(false);
+            match previous {
+                Some(previous) => write_delta(&mut record, previous, entity),
+                None => write_full(&mut record, entity),
+            }
+            records.push((entity_id, Some(*entity), record));
+        }
+
+        let mut prelude = BitWriter::new();
+        prelude.write_bool(baseline_tick.is_some());
+        if let Some(acked) = baseline_tick {
+            prelude.write((tick - acked) as u64, 8);
+        }
+        prelude.write_bool(ack_input_id.is_some());
+        if let Some(input_id) = ack_input_id {
+            prelude.write(input_id as u64, 32);
+        }
+
+        // Records never straddle parts, so every part decodes on its own
+        let budget =
+            (max_size.saturating_sub(PART_HEADER_BYTES) * 8).saturating_sub(prelude.bit_len());
+        let mut parts: Vec<(BitWriter, u16)> = vec![(BitWriter::new(), 0)];
+        // What the client holds once this snapshot arrives. Records that don't fit are left out
+        // of it too, so they go out again against the next baseline.
+        let mut sent = baseline.clone();
+        for (entity_id, entity, record) in records {
+            let (body, count) = parts.last_mut().expect("always one part");
+            if *count > 0 && body.bit_len() + record.bit_len() > budget {
+                if parts.len() == u8::MAX as usize {
+                    break;
+                }
+                parts.push((BitWriter::new(), 0));
+            }
+            let (body, count) = parts.last_mut().expect("always one part");
+            body.append(&record);
+            *count += 1;
+            match entity {
+                Some(entity) => sent.insert(entity_id, entity),
+                None => sent.remove(&entity_id),
+            };
+        }
+
+        self.history.insert(tick, sent);
+
+        let part_count = parts.len();
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, (body, count))| {
+                let mut part = BitWriter::new();
+                part.write(tick as u64, 32);
+                part.write(index as u64, 8);
+                part.write(part_count as u64, 8);
+                part.write(count as u64, 16);
+                part.append(&prelude);
+                part.append(&body);
+                part.into_bytes()
+            })
+            .collect()
+    }
+}
+
+/// Client side of the snapshot stream, reassembling parts and applying deltas.
+#[derive(Default)]
+pub struct SnapshotDecoder {
+    history: BTreeMap<u32, EntityMap>,
+    partial: BTreeMap<u32, Vec<Option<Vec<u8>>>>,
+    newest: Option<u32>,
+}
+
+impl SnapshotDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes one datagram, returning the snapshot once every part of it has arrived. Parts of
+    /// snapshots older than the newest complete one are dropped.
+    pub fn receive(&mut self, part: &[u8]) -> Result<Option<Snapshot>, SnapshotError> {
+        let mut reader = BitReader::new(part);
+        let tick = reader.read(32)? as u32;
+        let index = reader.read(8)? as usize;
+        let count = reader.read(8)? as usize;
+        if index >= count {
+            return Err(SnapshotError::Malformed("part index out of range"));
+        }
+        if self.newest.is_some_and(|newest| tick <= newest) {
+            return Ok(None);
+        }
+
+        let parts = self
+            .partial
+            .entry(tick)
+            .or_insert_with(|| vec![None; count]);
+        if parts.len() != count {
+            return Err(SnapshotError::Malformed("part count changed"));
+        }
+        parts[index] = Some(part.to_vec());
+        if parts.iter().any(Option::is_none) {
+            while self.partial.len() > MAX_PENDING_SNAPSHOTS {
+                self.partial.pop_first();
+            }
+            return Ok(None);
+        }
+
+        let parts = self.partial.remove(&tick).unwrap_or_default();
+        let snapshot = self.decode(tick, parts.into_iter().flatten());
+        self.partial.retain(|&pending, _| pending > tick);
+        snapshot.map(Some)
+    }
+
+    fn decode(
+        &mut self,
+        tick: u32,
+        parts: impl Iterator<Item = Vec<u8>>,
+    ) -> Result<Snapshot, SnapshotError> {
+        let mut entities: Option<EntityMap> = None;
+        let mut ack_input_id = None;
+
+        for part in parts {
+            let mut reader = BitReader::new(&part);
+            reader.read(48)?;
+            let records = reader.read(16)?;
+
+            let baseline = if reader.read_bool()? {
+                let age = reader.read(8)? as u32;
+                Some(tick.wrapping_sub(age))
+            } else {
+                None
+            };
+            ack_input_id = if reader.read_bool()? {
+                Some(reader.read(32)? as u32)
+            } else {
+                None
+            };
+
+            let entities = match &mut entities {
+                Some(entities) => entities,
+                None => entities.insert(match baseline {
+                    Some(baseline) => self
+                        .history
+                        .get(&baseline)
+                        .cloned()
+                        .ok_or(SnapshotError::MissingBaseline { tick, baseline })?,
+                    None => EntityMap::new(),
+                }),
+            };
+
+            for _ in 0..records {
+                let entity_id = reader.read(32)? as u32;
+                if reader.read_bool()? {
+                    entities.remove(&entity_id);
+                    continue;
+                }
+                let entity = match entities.get(&entity_id) {
+                    Some(previous) => read_delta(&mut reader, previous)?,
+                    None => read_full(&mut reader)?,
+                };
+                entities.insert(entity_id, entity);
+            }
+        }
+
+        let entities = entities.unwrap_or_default();
+        let mut states: Vec<EntityState> = entities
+            .iter()
+            .map(|(&entity_id, entity)| entity.to_state(entity_id))
+            .collect();
+        states.sort_by_key(|state| state.entity_id);
+
+        self.history.insert(tick, entities);
+        self.history
+            .retain(|&held, _| tick - held < SNAPSHOT_HISTORY);
+        self.newest = Some(tick);
+
+        Ok(Snapshot {
+            tick,
+            ack_input_id,
+            entities: states,
+        })
+    }
+}
+
+fn write_full(record: &mut BitWriter, entity: &QuantizedEntity) {
+    record.write_signed(entity.x as i64, POSITION_XY_BITS);
+    record.write_signed(entity.y as i64, POSITION_XY_BITS);
+    record.write_signed(entity.z as i64, POSITION_Z_BITS);
+    for velocity in entity.velocity {
+        record.write_signed(velocity as i64, VELOCITY_BITS);
+    }
+    record.write(entity.facing as u64, 8);
+    record.write(entity.animation as u64, 8);
+}
+
+fn read_full(reader: &mut BitReader) -> Result<QuantizedEntity, SnapshotError> {
+    Ok(QuantizedEntity {
+        x: reader.read_signed(POSITION_XY_BITS)? as i32,
+        y: reader.read_signed(POSITION_XY_BITS)? as i32,
+        z: reader.read_signed(POSITION_Z_BITS)? as i16,
+        velocity: [
+            reader.read_signed(VELOCITY_BITS)? as i16,
+            reader.read_signed(VELOCITY_BITS)? as i16,
+        ],
+        facing: reader.read(8)? as u8,
+        animation: reader.read(8)? as u8,
+    })
+}
+
+/// Writes a coordinate as a small difference from `previous` when it fits.
+fn write_coordinate(record: &mut BitWriter, previous: i64, current: i64, bits: u32) {
+    let delta = current - previous;
+    let small = delta.abs() < 1 << (SMALL_DELTA_BITS - 1);
+    record.write_bool(small);
+    if small {
+        record.write_signed(delta, SMALL_DELTA_BITS);
+    } else {
+        record.write_signed(current, bits);
+    }
+}
+
+fn read_coordinate(reader: &mut BitReader, previous: i64, bits: u32) -> Result<i64, SnapshotError> {
+    if reader.read_bool()? {
+        Ok(previous + reader.read_signed(SMALL_DELTA_BITS)?)
+    } else {
+        reader.read_signed(bits)
+    }
+}
+
+fn write_delta(record: &mut BitWriter, previous: &QuantizedEntity, entity: &QuantizedEntity) {
+    let moved = (previous.x, previous.y, previous.z) != (entity.x, entity.y, entity.z);
+    record.write_bool(moved);
+    if moved {
+        write_coordinate(record, previous.x as i64, entity.x as i64, POSITION_XY_BITS);
+        write_coordinate(record, previous.y as i64, entity.y as i64, POSITION_XY_BITS);
+        write_coordinate(record, previous.z as i64, entity.z as i64, POSITION_Z_BITS);
+    }
+
+    record.write_bool(previous.velocity != entity.velocity);
+    if previous.velocity != entity.velocity {
+        for velocity in entity.velocity {
+            record.write_signed(velocity as i64, VELOCITY_BITS);
+        }
+    }
+
+    record.write_bool(previous.facing != entity.facing);
+    if previous.facing != entity.facing {
+        record.write(entity.facing as u64, 8);
+    }
+
+    record.write_bool(previous.animation != entity.animation);
+    if previous.animation != entity.animation {
+        record.write(entity.animation as u64, 8);
+    }
+}
+
+fn read_delta(
+    reader: &mut BitReader,
+    previous: &QuantizedEntity,
+) -> Result<QuantizedEntity, SnapshotError> {
+    let mut entity = *previous;
+    if reader.read_bool()? {
+        entity.x = read_coordinate(reader, previous.x as i64, POSITION_XY_BITS)? as i32;
+        entity.y = read_coordinate(reader, previous.y as i64, POSITION_XY_BITS)? as i32;
+        entity.z = read_coordinate(reader, previous.z as i64, POSITION_Z_BITS)? as i16;
+    }
+    if reader.read_bool()? {
+        entity.velocity = [
+            reader.read_signed(VELOCITY_BITS)? as i16,
+            reader.read_signed(VELOCITY_BITS)? as i16,
+        ];
+    }
+    if reader.read_bool()? {
+        entity.facing = reader.read(8)? as u8;
+    }
+    if reader.read_bool()? {
+        entity.animation = reader.read(8)? as u8;
+    }
+    Ok(entity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Animation, PlayerPos};
+
+    const MTU: usize = 1200;
+
+    fn entity(entity_id: u32, x: f32) -> EntityState {
+        EntityState {
+            entity_id,
+            position: PlayerPos::new(x, -x / 2.0, 3.0),
+            velocity: [4.0, -1.5],
+            facing: 1.0,
+            animation: Animation::Walking,
+        }
+    }
+
+    fn world(count: u32, x: f32) -> Vec<EntityState> {
+        (0..count).map(|id| entity(id, x + id as f32)).collect()
+    }
+
+    fn receive_all(decoder: &mut SnapshotDecoder, parts: &[Vec<u8>]) -> Snapshot {
+        let mut snapshot = None;
+        for part in parts {
+            snapshot = decoder.receive(part).unwrap();
+        }
+        snapshot.expect("every part was delivered")
+    }
+
+    fn quantized(states: &[EntityState]) -> Vec<QuantizedEntity> {
+        states.iter().map(QuantizedEntity::from_state).collect()
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        let mut writer = BitWriter::new();
+        writer.write_bool(true);
+        writer.write_signed(-5, 7);
+        writer.write(0xABCDEF, 24);
+        writer.write_signed(-(1 << 23), 24);
+
+        let bytes = writer.into_bytes();
+        let mut reader = BitReader::new(&bytes);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_signed(7).unwrap(), -5);
+        assert_eq!(reader.read(24).unwrap(), 0xABCDEF);
+        assert_eq!(reader.read_signed(24).unwrap(), -(1 << 23));
+        assert!(matches!(reader.read(8), Err(SnapshotError::Truncated)));
+    }
+
+    #[test]
+    fn deltas_apply_on_top_of_the_acked_baseline() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+
+        let first = world(10, 0.0);
+        let full = encoder.encode(1, Some(7), &first, MTU);
+        let snapshot = receive_all(&mut decoder, &full);
+        assert_eq!(snapshot.ack_input_id, Some(7));
+        assert_eq!(quantized(&snapshot.entities), quantized(&first));
+        encoder.acknowledge(1);
+
+        // One entity moves, one leaves and one joins
+        let mut second = first.clone();
+        second[3].position.x += 0.5;
+        second.remove(5);
+        second.push(entity(42, 100.0));
+        let delta = encoder.encode(2, Some(8), &second, MTU);
+        assert!(delta[0].len() * 4 < full[0].len());
+
+        let snapshot = receive_all(&mut decoder, &delta);
+        second.sort_by_key(|state| state.entity_id);
+        assert_eq!(quantized(&snapshot.entities), quantized(&second));
+    }
+
+    #[test]
+    fn large_snapshots_are_split_to_fit_the_mtu() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+
+        let entities = world(500, 0.0);
+        let parts = encoder.encode(1, None, &entities, MTU);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.len() <= MTU));
+
+        // Parts may arrive in any order
+        let mut reversed = parts.clone();
+        reversed.reverse();
+        let snapshot = receive_all(&mut decoder, &reversed);
+        assert_eq!(quantized(&snapshot.entities), quantized(&entities));
+    }
+
+    #[test]
+    fn records_beyond_the_last_part_follow_in_later_snapshots() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+        let entities = world(1000, 0.0);
+
+        let parts = encoder.encode(1, None, &entities, 40);
+        assert_eq!(parts.len(), u8::MAX as usize);
+        let mut snapshot = receive_all(&mut decoder, &parts);
+        assert!(snapshot.entities.len() < entities.len());
+
+        for tick in 2..10 {
+            encoder.acknowledge(tick - 1);
+            snapshot = receive_all(&mut decoder, &encoder.encode(tick, None, &entities, 40));
+        }
+        assert_eq!(quantized(&snapshot.entities), quantized(&entities));
+    }
+
+    #[test]
+    fn stale_acks_fall_back_to_a_full_snapshot() {
+        let mut encoder = SnapshotEncoder::new();
+        let entities = world(3, 0.0);
+
+        let full = encoder.encode(1, None, &entities, MTU);
+        encoder.acknowledge(1);
+        assert!(encoder.encode(2, None, &entities, MTU)[0].len() < full[0].len());
+
+        // The baseline ages out of the history without a newer ack
+        let late = encoder.encode(1 + SNAPSHOT_HISTORY, None, &entities, MTU);
+        assert_eq!(late[0].len(), full[0].len());
+
+        // A fresh decoder can read the full snapshot but not a delta
+        let mut decoder = SnapshotDecoder::new();
+        encoder.acknowledge(1 + SNAPSHOT_HISTORY);
+        let delta = encoder.encode(2 + SNAPSHOT_HISTORY, None, &entities, MTU);
+        assert!(matches!(
+            decoder.receive(&delta[0]),
+            Err(SnapshotError::MissingBaseline { .. })
+        ));
+        assert!(decoder.receive(&late[0]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use quinn::{
+    Connection,
+    rustls::{
+        self, DigitallySignedStruct, Error as TlsError, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    },
+};
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of the DER encoded certificate, formatted as colon separated hex pairs.
+pub fn certificate_fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Fingerprints of every server this client has connected to, stored one `address fingerprint` pair per line.
+#[derive(Debug)]
+pub struct KnownServers {
+    path: PathBuf,
+    fingerprints: Mutex<HashMap<String, String>>,
+}
+
+impl KnownServers {
+    pub fn load(path: &Path) -> anyhow::Result<Arc<Self>> {
+        let mut fingerprints: HashMap<String, String> = HashMap::new();
+
+        if path.exists() {
+            for line in fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                match line.split_once(' ') {
+                    Some((server, fingerprint)) => {
+                        fingerprints.insert(server.into(), fingerprint.trim().into());
+                    }
+                    None => return Err(anyhow!("Malformed known servers entry: '{line}'")),
+                }
+            }
+        }
+
+        Ok(Arc::new(Self {
+            path: path.to_path_buf(),
+            fingerprints: Mutex::new(fingerprints),
+        }))
+    }
+
+    pub fn fingerprint(&self, server: &str) -> Option<String> {
+        self.fingerprints.lock().ok()?.get(server).cloned()
+    }
+
+    /// Pins the certificate `connection` was established with, unless the server already has one.
+    /// Called once the handshake has completed, so a certificate is only remembered after its
+    /// owner proved it holds the key.
+    pub fn pin_connected(&self, server: &str, connection: &Connection) -> anyhow::Result<()> {
+        if self.fingerprint(server).is_some() {
+            return Ok(());
+        }
+        let cert = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|chain| chain.first().cloned())
+            .ok_or_else(|| anyhow!("Server {server} presented no certificate"))?;
+        let fingerprint = certificate_fingerprint(&cert);
+        self.pin(server, &fingerprint)?;
+        println!("Pinned certificate for {server}: {fingerprint}");
+        Ok(())
+    }
+
+    fn pin(&self, server: &str, fingerprint: &str) -> anyhow::Result<()> {
+        let mut fingerprints = self
+            .fingerprints
+            .lock()
+            .map_err(|e| anyhow!("Could not lock known servers: {e}"))?;
+        fingerprints.insert(server.into(), fingerprint.into());
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents: String = fingerprints
+            .iter()
+            .map(|(server, fingerprint)| format!("{server} {fingerprint}\n"))
+            .collect();
+        fs::write(&self.path, contents)?;
+
+        Ok(())
+    }
+}
+
+/// Trust on first use: the first certificate seen for a server address is accepted and, once
+/// the connection succeeds, pinned with [`KnownServers::pin_connected`]. Any later certificate
+/// with a different fingerprint is refused.
+#[derive(Debug)]
+pub struct PinnedCertVerifier {
+    server: String,
+    known_servers: Arc<KnownServers>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    pub fn new(server: String, known_servers: Arc<KnownServers>) -> anyhow::Result<Self> {
+        let provider = CryptoProvider::get_default()
+            .ok_or_else(|| anyhow!("No default crypto provider installed"))?
+            .clone();
+
+        Ok(Self {
+            server,
+            known_servers,
+            provider,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let fingerprint = certificate_fingerprint(end_entity);
+
+        match self.known_servers.fingerprint(&self.server) {
+            Some(pinned) if pinned == fingerprint => Ok(ServerCertVerified::assertion()),
+            Some(pinned) => Err(TlsError::General(format!(
+                "Certificate for {} does not match the pinned fingerprint (pinned {pinned}, got {fingerprint}). \
+                 If the server identity was intentionally replaced, remove its entry from {:?}",
+                self.server, self.known_servers.path
+            ))),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+pub fn pinned_client_config(
+    server: String,
+    known_servers: Arc<KnownServers>,
+) -> anyhow::Result<rustls::ClientConfig> {
+    Ok(rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(server, known_servers)?))
+        .with_no_client_auth())
+}